@@ -0,0 +1,12 @@
+-- API keys for X-Api-Key authentication. Only a SHA-256 hash of each
+-- key is stored; `prefix` is the public part used to look it up.
+CREATE TABLE api_keys (
+    prefix TEXT PRIMARY KEY,
+    key_hash TEXT NOT NULL,
+    owner TEXT NOT NULL,
+    scopes TEXT NOT NULL DEFAULT '',
+    created_at BIGINT NOT NULL,
+    expires_at BIGINT,
+    last_used_at BIGINT,
+    revoked_at BIGINT
+);
@@ -0,0 +1,7 @@
+-- Rate limiter state shared by replicas (RATE_LIMIT_STORE=database):
+-- the theoretical arrival time, in Unix milliseconds, of each
+-- client's next request under the GCRA.
+CREATE TABLE rate_limits (
+    key TEXT PRIMARY KEY,
+    tat BIGINT NOT NULL
+);
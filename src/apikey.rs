@@ -0,0 +1,405 @@
+// ==================================================
+// API keys
+// ==================================================
+// Long-lived credentials for clients that cannot obtain JWTs, sent
+// as `X-Api-Key: hak_<prefix>_<secret>` and accepted on the API
+// routes when AUTH_API_KEYS=true.
+//
+// - Keys live in the `api_keys` table of the DATABASE_URL database,
+//   each with an owner, scopes, optional expiry and last-used time
+// - Only a SHA-256 hash of the key is stored; the public prefix
+//   finds the row. Keys are 256 random bits, so a fast hash is as
+//   safe as a password hash here and keeps lookups cheap
+// - Managed with `hello-api apikey create|list|revoke`; the secret
+//   is printed once, at creation, and cannot be recovered
+//
+// Revoked and expired keys are rejected immediately, since every
+// request reads the key's row.
+
+use crate::{
+    cli::ApiKeyCommand,
+    clock::{format_timestamp, unix_now},
+    crypto,
+    db::{Database, DbError},
+    error::ApiError,
+};
+use axum::{async_trait, extract::FromRequestParts, http::request::Parts};
+use sqlx::{any::AnyRow, Row};
+use std::fmt;
+use tracing::warn;
+
+/// Marks a string as one of our keys (and makes leaked keys easy to
+/// find with secret scanners).
+const KEY_PREFIX: &str = "hak_";
+/// Random bytes in the public prefix and the secret part.
+const PREFIX_BYTES: usize = 6;
+const SECRET_BYTES: usize = 32;
+/// `last_used_at` is only written when it is older than this, so
+/// busy keys do not cost a database write per request.
+const LAST_USED_RESOLUTION_SECS: i64 = 60;
+
+// --------------------------------------------------
+// Extractor
+// --------------------------------------------------
+
+/// The API key a request authenticated with.
+///
+/// Rejects with 401 when the request did not use an API key; take
+/// `Option<ApiKey>` where other credentials are fine too.
+#[derive(Clone, Debug)]
+pub struct ApiKey {
+    /// Public identifier, e.g. `hak_3f9a0c12d4e5`.
+    pub prefix: String,
+    pub owner: String,
+    pub scopes: Vec<String>,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ApiKey
+where
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<ApiKey>()
+            .cloned()
+            .ok_or_else(|| ApiError::Unauthorized("An API key is required".to_string()))
+    }
+}
+
+// --------------------------------------------------
+// Verification
+// --------------------------------------------------
+
+#[derive(Debug)]
+pub enum KeyError {
+    /// Client-facing reason the key was refused.
+    Rejected(&'static str),
+    Db(DbError),
+}
+
+impl From<DbError> for KeyError {
+    fn from(err: DbError) -> Self {
+        Self::Db(err)
+    }
+}
+
+/// Look up and check a presented key.
+pub async fn authenticate(db: &Database, presented: &str) -> Result<ApiKey, KeyError> {
+    let Some((prefix, _)) = split_key(presented) else {
+        return Err(KeyError::Rejected("Invalid API key"));
+    };
+
+    let row = db
+        .fetch_optional(
+            sqlx::query(
+                "SELECT prefix, owner, scopes, key_hash, created_at, expires_at, last_used_at, \
+                 revoked_at FROM api_keys WHERE prefix = $1",
+            )
+            .bind(prefix),
+        )
+        .await?;
+    let Some(row) = row else {
+        return Err(KeyError::Rejected("Invalid API key"));
+    };
+    let key = KeyRow::decode(&row)?;
+
+    if !crypto::constant_time_eq(hash_key(presented).as_bytes(), key.key_hash.as_bytes()) {
+        return Err(KeyError::Rejected("Invalid API key"));
+    }
+
+    // Only reveal why a key is refused to whoever holds it.
+    let now = unix_now();
+    if key.revoked_at.is_some() {
+        return Err(KeyError::Rejected("API key revoked"));
+    }
+    if key.expires_at.is_some_and(|expires_at| expires_at <= now) {
+        return Err(KeyError::Rejected("API key expired"));
+    }
+
+    if key
+        .last_used_at
+        .is_none_or(|last_used| now - last_used >= LAST_USED_RESOLUTION_SECS)
+    {
+        // Off the request path: a slow write must not delay the call.
+        let (db, prefix) = (db.clone(), prefix.to_string());
+        tokio::spawn(async move {
+            let result = db
+                .execute(
+                    sqlx::query("UPDATE api_keys SET last_used_at = $1 WHERE prefix = $2")
+                        .bind(now)
+                        .bind(prefix),
+                )
+                .await;
+            if let Err(err) = result {
+                warn!("Failed to record API key use: {}", err);
+            }
+        });
+    }
+
+    Ok(ApiKey {
+        prefix: prefix.to_string(),
+        scopes: split_scopes(&key.scopes),
+        owner: key.owner,
+    })
+}
+
+/// One `api_keys` row.
+struct KeyRow {
+    prefix: String,
+    owner: String,
+    scopes: String,
+    key_hash: String,
+    created_at: i64,
+    expires_at: Option<i64>,
+    last_used_at: Option<i64>,
+    revoked_at: Option<i64>,
+}
+
+impl KeyRow {
+    fn decode(row: &AnyRow) -> Result<Self, DbError> {
+        Ok(Self {
+            prefix: row.try_get("prefix")?,
+            owner: row.try_get("owner")?,
+            scopes: row.try_get("scopes")?,
+            key_hash: row.try_get("key_hash")?,
+            created_at: row.try_get("created_at")?,
+            expires_at: row.try_get("expires_at")?,
+            last_used_at: row.try_get("last_used_at")?,
+            revoked_at: row.try_get("revoked_at")?,
+        })
+    }
+}
+
+/// `hak_<prefix>_<secret>` into the public prefix (with `hak_`) and
+/// the secret.
+fn split_key(key: &str) -> Option<(&str, &str)> {
+    let (prefix, secret) = key.rsplit_once('_')?;
+    let id = prefix.strip_prefix(KEY_PREFIX)?;
+    let is_hex = |s: &str| s.bytes().all(|b| b.is_ascii_hexdigit());
+    (id.len() == PREFIX_BYTES * 2
+        && secret.len() == SECRET_BYTES * 2
+        && is_hex(id)
+        && is_hex(secret))
+    .then_some((prefix, secret))
+}
+
+fn hash_key(key: &str) -> String {
+    crypto::to_hex(&crypto::sha256(key.as_bytes()))
+}
+
+/// Scopes are stored space-separated, like the OAuth `scope` claim.
+fn split_scopes(scopes: &str) -> Vec<String> {
+    scopes.split_whitespace().map(str::to_string).collect()
+}
+
+// --------------------------------------------------
+// Commands
+// --------------------------------------------------
+
+/// Entry point for `hello-api apikey ...`.
+pub async fn run_command(db: &Database, command: ApiKeyCommand) -> Result<(), KeyError> {
+    match command {
+        ApiKeyCommand::Create {
+            owner,
+            scopes,
+            expires_in_days,
+        } => {
+            let (key, expires_at) = create(db, &owner, &scopes, expires_in_days).await?;
+            let (prefix, _) = split_key(&key).expect("generated keys are well-formed");
+            eprintln!(
+                "Created API key {} for {:?} (scopes: {}, expires: {})",
+                prefix,
+                owner,
+                display_scopes(&scopes),
+                expires_at.map_or("never".to_string(), format_timestamp)
+            );
+            eprintln!("Store it now, it cannot be shown again:");
+            println!("{key}");
+        }
+        ApiKeyCommand::List => {
+            let rows = db
+                .fetch_all(sqlx::query(
+                    "SELECT prefix, owner, scopes, key_hash, created_at, expires_at, last_used_at, \
+                     revoked_at FROM api_keys ORDER BY created_at, prefix",
+                ))
+                .await?;
+
+            println!(
+                "{:<16} {:<16} {:<8} {:<16} {:<16} {:<16} SCOPES",
+                "PREFIX", "OWNER", "STATE", "CREATED", "EXPIRES", "LAST USED"
+            );
+            let now = unix_now();
+            for row in &rows {
+                let key = KeyRow::decode(row)?;
+                let timestamp = |at: Option<i64>| at.map_or("-".to_string(), format_timestamp);
+                println!(
+                    "{:<16} {:<16} {:<8} {:<16} {:<16} {:<16} {}",
+                    key.prefix,
+                    key.owner,
+                    key_state(&key, now),
+                    format_timestamp(key.created_at),
+                    timestamp(key.expires_at),
+                    timestamp(key.last_used_at),
+                    display_scopes(&split_scopes(&key.scopes)),
+                );
+            }
+        }
+        ApiKeyCommand::Revoke { prefix } => {
+            revoke(db, &prefix).await?;
+            println!("Revoked API key {prefix}");
+        }
+    }
+    Ok(())
+}
+
+/// Store a new key; returns it with its expiry.
+async fn create(
+    db: &Database,
+    owner: &str,
+    scopes: &[String],
+    expires_in_days: Option<u32>,
+) -> Result<(String, Option<i64>), KeyError> {
+    let mut random = [0u8; PREFIX_BYTES + SECRET_BYTES];
+    crypto::fill_random(&mut random);
+    let prefix = format!("{KEY_PREFIX}{}", crypto::to_hex(&random[..PREFIX_BYTES]));
+    let key = format!("{prefix}_{}", crypto::to_hex(&random[PREFIX_BYTES..]));
+
+    let now = unix_now();
+    let expires_at = expires_in_days.map(|days| now + i64::from(days) * 86_400);
+    db.execute(
+        sqlx::query(
+            "INSERT INTO api_keys (prefix, key_hash, owner, scopes, created_at, expires_at) \
+             VALUES ($1, $2, $3, $4, $5, $6)",
+        )
+        .bind(&prefix)
+        .bind(hash_key(&key))
+        .bind(owner)
+        .bind(scopes.join(" "))
+        .bind(now)
+        .bind(expires_at),
+    )
+    .await?;
+    Ok((key, expires_at))
+}
+
+async fn revoke(db: &Database, prefix: &str) -> Result<(), KeyError> {
+    let revoked = db
+        .execute(
+            sqlx::query(
+                "UPDATE api_keys SET revoked_at = $1 WHERE prefix = $2 AND revoked_at IS NULL",
+            )
+            .bind(unix_now())
+            .bind(prefix),
+        )
+        .await?;
+    if revoked == 0 {
+        let exists = db
+            .fetch_optional(sqlx::query("SELECT 1 FROM api_keys WHERE prefix = $1").bind(prefix))
+            .await?
+            .is_some();
+        return Err(KeyError::Rejected(if exists {
+            "API key is already revoked"
+        } else {
+            "No API key with that prefix"
+        }));
+    }
+    Ok(())
+}
+
+fn key_state(key: &KeyRow, now: i64) -> &'static str {
+    if key.revoked_at.is_some() {
+        "revoked"
+    } else if key.expires_at.is_some_and(|expires_at| expires_at <= now) {
+        "expired"
+    } else {
+        "active"
+    }
+}
+
+fn display_scopes(scopes: &[String]) -> String {
+    if scopes.is_empty() {
+        "-".to_string()
+    } else {
+        scopes.join(",")
+    }
+}
+
+// --------------------------------------------------
+// Errors
+// --------------------------------------------------
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Rejected(reason) => write!(f, "{reason}"),
+            Self::Db(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db, migrate};
+
+    async fn migrated() -> Database {
+        let db = db::memory().await;
+        migrate::up(&db).await.unwrap();
+        db
+    }
+
+    fn rejection(result: Result<impl fmt::Debug, KeyError>) -> &'static str {
+        match result {
+            Err(KeyError::Rejected(reason)) => reason,
+            other => panic!("expected a rejection, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn create_authenticate_revoke() {
+        let db = migrated().await;
+        let scopes = vec!["orders:read".to_string(), "orders:write".to_string()];
+        let (key, expires_at) = create(&db, "billing", &scopes, Some(30)).await.unwrap();
+        assert!(expires_at.unwrap() > unix_now() + 29 * 86_400);
+
+        let api_key = authenticate(&db, &key).await.unwrap();
+        assert_eq!(api_key.owner, "billing");
+        assert_eq!(api_key.scopes, scopes);
+        assert!(key.starts_with(&format!("{}_", api_key.prefix)));
+
+        // Right prefix, wrong secret.
+        let forged = format!("{}_{}", api_key.prefix, "0".repeat(SECRET_BYTES * 2));
+        assert_eq!(
+            rejection(authenticate(&db, &forged).await),
+            "Invalid API key"
+        );
+        assert_eq!(
+            rejection(authenticate(&db, "hak_nope").await),
+            "Invalid API key"
+        );
+
+        revoke(&db, &api_key.prefix).await.unwrap();
+        assert_eq!(rejection(authenticate(&db, &key).await), "API key revoked");
+        assert_eq!(
+            rejection(revoke(&db, &api_key.prefix).await),
+            "API key is already revoked"
+        );
+        assert_eq!(
+            rejection(revoke(&db, "hak_000000000000").await),
+            "No API key with that prefix"
+        );
+    }
+
+    #[tokio::test]
+    async fn expired_keys_are_rejected() {
+        let db = migrated().await;
+        let (key, _) = create(&db, "billing", &[], Some(0)).await.unwrap();
+        assert_eq!(rejection(authenticate(&db, &key).await), "API key expired");
+    }
+}
@@ -0,0 +1,243 @@
+// ==================================================
+// Command line
+// ==================================================
+// With no arguments the binary runs the HTTP server. Operational
+// tasks are subcommands sharing the same environment config:
+//
+//   hello-api migrate up       apply every pending migration
+//   hello-api migrate down     roll back the latest migration
+//   hello-api migrate status   list applied / pending migrations
+//   hello-api apikey create    issue an API key (secret printed once)
+//   hello-api apikey list      list API keys, without secrets
+//   hello-api apikey revoke    revoke an API key by its prefix
+
+pub const USAGE: &str = "\
+Usage:
+  hello-api                  run the HTTP server
+  hello-api migrate up       apply every pending migration
+  hello-api migrate down     roll back the latest migration
+  hello-api migrate status   list applied and pending migrations
+  hello-api apikey create --owner NAME [--scopes a,b] [--expires-in-days N]
+                             issue an API key; the secret is printed once
+  hello-api apikey list      list API keys (prefix, owner, scopes, state)
+  hello-api apikey revoke PREFIX
+                             revoke an API key";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Serve,
+    Migrate(MigrateCommand),
+    ApiKey(ApiKeyCommand),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MigrateCommand {
+    Up,
+    Down,
+    Status,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ApiKeyCommand {
+    Create {
+        owner: String,
+        scopes: Vec<String>,
+        expires_in_days: Option<u32>,
+    },
+    List,
+    Revoke {
+        prefix: String,
+    },
+}
+
+impl Command {
+    /// Parse the arguments after the program name.
+    pub fn parse<I, S>(args: I) -> Result<Self, String>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        let args: Vec<S> = args.into_iter().collect();
+        let args: Vec<&str> = args.iter().map(AsRef::as_ref).collect();
+
+        match args.as_slice() {
+            [] => Ok(Self::Serve),
+            ["migrate", "up"] => Ok(Self::Migrate(MigrateCommand::Up)),
+            ["migrate", "down"] => Ok(Self::Migrate(MigrateCommand::Down)),
+            ["migrate", "status"] => Ok(Self::Migrate(MigrateCommand::Status)),
+            ["migrate", ..] => Err("migrate expects one of: up, down, status".to_string()),
+            ["apikey", "create", flags @ ..] => parse_apikey_create(flags).map(Self::ApiKey),
+            ["apikey", "list"] => Ok(Self::ApiKey(ApiKeyCommand::List)),
+            ["apikey", "revoke", prefix] => Ok(Self::ApiKey(ApiKeyCommand::Revoke {
+                prefix: prefix.to_string(),
+            })),
+            ["apikey", ..] => Err("apikey expects one of: create, list, revoke PREFIX".to_string()),
+            [other, ..] => Err(format!("unknown command {other:?}")),
+        }
+    }
+}
+
+/// `--owner NAME [--scopes a,b] [--expires-in-days N]`, in any order.
+fn parse_apikey_create(flags: &[&str]) -> Result<ApiKeyCommand, String> {
+    let mut owner = None;
+    let mut scopes = Vec::new();
+    let mut expires_in_days = None;
+
+    let mut flags = flags.iter();
+    while let Some(flag) = flags.next() {
+        let mut value = || {
+            flags
+                .next()
+                .map(|v| v.trim())
+                .filter(|v| !v.is_empty())
+                .ok_or_else(|| format!("{flag} expects a value"))
+        };
+        match *flag {
+            "--owner" => owner = Some(value()?.to_string()),
+            "--scopes" => {
+                scopes = value()?
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(str::to_string)
+                    .collect();
+                if scopes.iter().any(|s| s.contains(char::is_whitespace)) {
+                    return Err("--scopes must not contain spaces".to_string());
+                }
+            }
+            "--expires-in-days" => {
+                let raw = value()?;
+                let days = raw.parse().ok().filter(|days| *days > 0).ok_or_else(|| {
+                    format!("--expires-in-days expects a positive number, got {raw:?}")
+                })?;
+                expires_in_days = Some(days);
+            }
+            other => return Err(format!("unknown apikey create option {other:?}")),
+        }
+    }
+
+    Ok(ApiKeyCommand::Create {
+        owner: owner.ok_or("apikey create requires --owner")?,
+        scopes,
+        expires_in_days,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &str) -> Result<Command, String> {
+        Command::parse(args.split_whitespace())
+    }
+
+    fn create(owner: &str, scopes: &[&str], expires_in_days: Option<u32>) -> Command {
+        Command::ApiKey(ApiKeyCommand::Create {
+            owner: owner.to_string(),
+            scopes: scopes.iter().map(|s| s.to_string()).collect(),
+            expires_in_days,
+        })
+    }
+
+    #[test]
+    fn commands_are_parsed() {
+        for (args, command) in [
+            ("", Command::Serve),
+            ("migrate up", Command::Migrate(MigrateCommand::Up)),
+            ("migrate down", Command::Migrate(MigrateCommand::Down)),
+            ("migrate status", Command::Migrate(MigrateCommand::Status)),
+            ("apikey list", Command::ApiKey(ApiKeyCommand::List)),
+            (
+                "apikey revoke hk_1a2b",
+                Command::ApiKey(ApiKeyCommand::Revoke {
+                    prefix: "hk_1a2b".to_string(),
+                }),
+            ),
+            ("apikey create --owner ci", create("ci", &[], None)),
+            (
+                "apikey create --expires-in-days 30 --scopes a,,b, --owner ci",
+                create("ci", &["a", "b"], Some(30)),
+            ),
+        ] {
+            assert_eq!(parse(args), Ok(command), "{args:?}");
+        }
+    }
+
+    #[test]
+    fn invalid_commands_are_rejected() {
+        for (args, error) in [
+            ("serve", "unknown command \"serve\""),
+            ("migrate", "migrate expects one of: up, down, status"),
+            (
+                "migrate sideways",
+                "migrate expects one of: up, down, status",
+            ),
+            ("migrate up now", "migrate expects one of: up, down, status"),
+            (
+                "apikey",
+                "apikey expects one of: create, list, revoke PREFIX",
+            ),
+            (
+                "apikey delete x",
+                "apikey expects one of: create, list, revoke PREFIX",
+            ),
+            (
+                "apikey revoke",
+                "apikey expects one of: create, list, revoke PREFIX",
+            ),
+            ("apikey create", "apikey create requires --owner"),
+            ("apikey create --scopes a", "apikey create requires --owner"),
+            ("apikey create --owner", "--owner expects a value"),
+            (
+                "apikey create --owner ci --expires-in-days 0",
+                "--expires-in-days expects a positive number, got \"0\"",
+            ),
+            (
+                "apikey create --owner ci --expires-in-days soon",
+                "--expires-in-days expects a positive number, got \"soon\"",
+            ),
+            (
+                "apikey create --owner ci --name x",
+                "unknown apikey create option \"--name\"",
+            ),
+        ] {
+            assert_eq!(parse(args), Err(error.to_string()), "{args:?}");
+        }
+    }
+
+    #[test]
+    fn scopes_must_not_contain_spaces() {
+        let args = [
+            "apikey",
+            "create",
+            "--owner",
+            "ci",
+            "--scopes",
+            "read, write all",
+        ];
+        assert_eq!(
+            Command::parse(args),
+            Err("--scopes must not contain spaces".to_string())
+        );
+
+        // Spaces around the commas are fine.
+        let args = [
+            "apikey",
+            "create",
+            "--owner",
+            "ci",
+            "--scopes",
+            " read , write ",
+        ];
+        assert_eq!(
+            Command::parse(args),
+            Ok(create("ci", &["read", "write"], None))
+        );
+
+        let args = ["apikey", "create", "--owner", " ", "--scopes", "a"];
+        assert_eq!(
+            Command::parse(args),
+            Err("--owner expects a value".to_string())
+        );
+    }
+}
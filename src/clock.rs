@@ -0,0 +1,66 @@
+// ==================================================
+// Wall-clock time
+// ==================================================
+// Unix timestamps for everything stored or compared across processes
+// (database rows, token claims, rate limit state, spans), and the
+// human-readable form the CLI prints. A clock set before 1970 reads
+// as the epoch.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+use time::{macros::format_description, OffsetDateTime};
+
+/// Seconds since the Unix epoch.
+pub fn unix_now() -> i64 {
+    since_epoch().as_secs() as i64
+}
+
+/// Milliseconds since the Unix epoch.
+pub fn unix_millis() -> i64 {
+    since_epoch().as_millis() as i64
+}
+
+/// Nanoseconds since the Unix epoch.
+pub fn unix_nanos() -> u64 {
+    since_epoch().as_nanos() as u64
+}
+
+fn since_epoch() -> std::time::Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+/// `YYYY-MM-DD HH:MM` (UTC) for a Unix timestamp; the number itself
+/// if it is outside the years 1 to 9999.
+pub fn format_timestamp(secs: i64) -> String {
+    OffsetDateTime::from_unix_timestamp(secs)
+        .ok()
+        .and_then(|at| {
+            at.format(format_description!("[year]-[month]-[day] [hour]:[minute]"))
+                .ok()
+        })
+        .unwrap_or_else(|| secs.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_timestamp_on_known_epochs() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00");
+        assert_eq!(format_timestamp(951_782_400), "2000-02-29 00:00");
+        assert_eq!(format_timestamp(1_700_000_000), "2023-11-14 22:13");
+        assert_eq!(format_timestamp(4_102_444_799), "2099-12-31 23:59");
+        assert_eq!(format_timestamp(-1), "1969-12-31 23:59");
+        assert_eq!(format_timestamp(i64::MAX), i64::MAX.to_string());
+    }
+
+    #[test]
+    fn clocks_agree() {
+        let (secs, millis) = (unix_now(), unix_millis());
+        assert!(secs > 1_700_000_000);
+        assert!((millis / 1000 - secs).abs() <= 1);
+        assert!((unix_nanos() / 1_000_000_000) as i64 - secs <= 1);
+    }
+}
@@ -0,0 +1,1203 @@
+// ==================================================
+// Application configuration
+// ==================================================
+// All runtime configuration is read from environment variables
+// exactly once, at boot, into a typed `Config`.
+//
+// Loading never stops at the first problem: every variable is
+// checked and all errors are reported together, so a broken
+// deployment can be fixed in a single pass.
+
+use crate::{
+    auth::{AuthSettings, JwksSource},
+    cors::{self, CorsSettings},
+    db::Backend,
+    error::ErrorFormat,
+    http_client::Endpoint,
+    listener::{self, IpNet, ListenAddr},
+    logging::{self, LogFormat},
+    overload::{ConcurrencySettings, LimitMode},
+    ratelimit::{self, RateLimitSettings},
+    telemetry::{OtlpProtocol, OtlpSettings},
+};
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use std::{
+    collections::HashMap,
+    env, fmt,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+use tracing_subscriber::filter::Targets;
+
+const DEFAULT_APP_PORT: u16 = 8080;
+const DEFAULT_SOCKET_MODE: u32 = 0o660;
+const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_UPGRADE_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_DB_POOL_MAX_SIZE: u32 = 10;
+const DEFAULT_DB_POOL_TIMEOUT_SECS: u64 = 5;
+const DEFAULT_DB_CONNECT_TIMEOUT_SECS: u64 = 5;
+const DEFAULT_HEALTH_CHECK_TIMEOUT_MS: u64 = 1000;
+const DEFAULT_HEALTH_CHECK_CACHE_MS: u64 = 2000;
+const DEFAULT_HEALTH_DISK_PATH: &str = "/";
+const DEFAULT_HEALTH_DISK_MIN_FREE_MB: u64 = 100;
+const DEFAULT_OTLP_TIMEOUT_MS: u64 = 10_000;
+const DEFAULT_REQUEST_TIMEOUT_MS: u64 = 30_000;
+const DEFAULT_JWKS_CACHE_SECS: u64 = 300;
+const DEFAULT_JWT_LEEWAY_SECS: u64 = 60;
+/// RFC 7518 §3.2: an HS256 key must be at least as long as the hash.
+const MIN_JWT_SECRET_BYTES: usize = 32;
+const DEFAULT_CONCURRENCY_QUEUE_SIZE: usize = 100;
+const DEFAULT_CONCURRENCY_QUEUE_TIMEOUT_MS: u64 = 500;
+const DEFAULT_CONCURRENCY_MIN_LIMIT: usize = 1;
+const DEFAULT_CONCURRENCY_TARGET_LATENCY_MS: u64 = 500;
+const DEFAULT_CORS_ALLOWED_METHODS: &str = "GET,HEAD,POST,PUT,PATCH,DELETE";
+const DEFAULT_CORS_ALLOWED_HEADERS: &str =
+    "authorization,content-type,x-api-key,x-request-id,x-request-deadline,traceparent,tracestate";
+const DEFAULT_CORS_EXPOSE_HEADERS: &str =
+    "x-request-id,retry-after,ratelimit-limit,ratelimit-remaining,ratelimit-reset,ratelimit-policy";
+const DEFAULT_CORS_MAX_AGE_SECS: u64 = 600;
+
+// --------------------------------------------------
+// Config
+// --------------------------------------------------
+
+#[derive(Clone)]
+pub struct Config {
+    pub database_url: String,
+    pub db_pool_max_size: u32,
+    pub db_pool_timeout: Duration,
+    pub db_connect_timeout: Duration,
+    pub db_auto_migrate: bool,
+    /// APP_LISTEN, or APP_BIND:APP_PORT.
+    pub listen: ListenAddr,
+    /// Separate listener for operational endpoints; `None` serves
+    /// them on APP_PORT alongside the API.
+    pub admin_port: Option<u16>,
+    pub admin_bind_addr: IpAddr,
+    /// TLS on the main listener; `None` serves plain HTTP.
+    pub tls: Option<TlsSettings>,
+    pub shutdown_timeout: Duration,
+    /// Default time budget of application requests.
+    pub request_timeout: Duration,
+    /// How long a SIGUSR2-spawned successor has to become ready.
+    pub upgrade_timeout: Duration,
+    pub log_level: Targets,
+    pub log_format: LogFormat,
+    pub error_format: ErrorFormat,
+    pub health_check_timeout: Duration,
+    pub health_check_cache_ttl: Duration,
+    pub health_disk_path: PathBuf,
+    /// HEALTH_DISK_MIN_FREE_MB, in bytes.
+    pub health_disk_min_free_bytes: u64,
+    /// OTLP trace export; `None` when disabled.
+    pub otlp: Option<OtlpSettings>,
+    /// Bearer token authentication for the API routes; off when unset.
+    pub auth: Option<AuthSettings>,
+    /// Reverse proxies whose X-Forwarded-For entries are believed.
+    pub trusted_proxies: Vec<IpNet>,
+    /// Per-client quotas on the API routes; off when unset.
+    pub rate_limit: Option<RateLimitSettings>,
+    /// Concurrency limit and load shedding; off when unset.
+    pub concurrency: Option<ConcurrencySettings>,
+    /// Cross-origin access for browsers; off when unset.
+    pub cors: Option<CorsSettings>,
+}
+
+impl Config {
+    /// Load configuration from the process environment.
+    pub fn from_env() -> Result<Self, ConfigError> {
+        Self::from_lookup(|key| env::var(key).ok())
+    }
+
+    /// Load configuration from an arbitrary key/value source.
+    ///
+    /// `from_env` is a thin wrapper around this; tests can pass a
+    /// closure over a map instead of mutating the real environment.
+    pub fn from_lookup<F>(lookup: F) -> Result<Self, ConfigError>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        let mut errors = Vec::new();
+
+        let database_url = match lookup("DATABASE_URL") {
+            Some(v) if !v.trim().is_empty() => v,
+            Some(_) => {
+                errors.push("DATABASE_URL is set but empty".to_string());
+                String::new()
+            }
+            None => {
+                errors.push("DATABASE_URL is not set".to_string());
+                String::new()
+            }
+        };
+
+        if !database_url.is_empty()
+            && let Err(err) = Backend::from_url(&database_url)
+        {
+            errors.push(format!("DATABASE_URL: {err}"));
+        }
+
+        let db_pool_max_size = parse_var(
+            &lookup,
+            "DB_POOL_MAX_SIZE",
+            DEFAULT_DB_POOL_MAX_SIZE,
+            &mut errors,
+        );
+        if db_pool_max_size == 0 {
+            errors.push("DB_POOL_MAX_SIZE must be greater than 0".to_string());
+        }
+
+        let db_pool_timeout = Duration::from_secs(parse_var(
+            &lookup,
+            "DB_POOL_TIMEOUT",
+            DEFAULT_DB_POOL_TIMEOUT_SECS,
+            &mut errors,
+        ));
+
+        let db_connect_timeout = Duration::from_secs(parse_var(
+            &lookup,
+            "DB_CONNECT_TIMEOUT",
+            DEFAULT_DB_CONNECT_TIMEOUT_SECS,
+            &mut errors,
+        ));
+
+        let db_auto_migrate = parse_var(&lookup, "DB_AUTO_MIGRATE", true, &mut errors);
+
+        let app_port = parse_var(&lookup, "APP_PORT", DEFAULT_APP_PORT, &mut errors);
+        if app_port == 0 {
+            errors.push("APP_PORT must be between 1 and 65535".to_string());
+        }
+
+        let app_bind = parse_ip_var(&lookup, "APP_BIND", &mut errors);
+
+        let listen = match lookup("APP_LISTEN") {
+            None => ListenAddr::Tcp(SocketAddr::new(app_bind, app_port)),
+            Some(raw) => unix_listen_addr(&lookup, &raw, &mut errors)
+                .unwrap_or(ListenAddr::Tcp(SocketAddr::new(app_bind, app_port))),
+        };
+
+        let admin_port = lookup("ADMIN_PORT").and_then(|raw| match raw.trim().parse::<u16>() {
+            Ok(port) if port != 0 => Some(port),
+            _ => {
+                errors.push(format!(
+                    "ADMIN_PORT must be between 1 and 65535, got {raw:?}"
+                ));
+                None
+            }
+        });
+        if let ListenAddr::Tcp(addr) = &listen
+            && admin_port == Some(addr.port())
+        {
+            errors.push("ADMIN_PORT must differ from APP_PORT".to_string());
+        }
+
+        let admin_bind_addr = parse_ip_var(&lookup, "ADMIN_BIND_ADDR", &mut errors);
+
+        let tls = tls_settings(&lookup, &listen, &mut errors);
+
+        let shutdown_timeout = Duration::from_secs(parse_var(
+            &lookup,
+            "GRACEFUL_SHUTDOWN_TIMEOUT",
+            DEFAULT_SHUTDOWN_TIMEOUT_SECS,
+            &mut errors,
+        ));
+
+        let request_timeout = Duration::from_millis(parse_var(
+            &lookup,
+            "REQUEST_TIMEOUT_MS",
+            DEFAULT_REQUEST_TIMEOUT_MS,
+            &mut errors,
+        ));
+        if request_timeout.is_zero() {
+            errors.push("REQUEST_TIMEOUT_MS must be greater than 0".to_string());
+        }
+
+        let upgrade_timeout = Duration::from_secs(parse_var(
+            &lookup,
+            "UPGRADE_TIMEOUT",
+            DEFAULT_UPGRADE_TIMEOUT_SECS,
+            &mut errors,
+        ));
+
+        let log_level = parse_var(&lookup, "LOG_LEVEL", logging::default_level(), &mut errors);
+        let log_format = parse_var(&lookup, "LOG_FORMAT", LogFormat::default(), &mut errors);
+
+        let error_format = parse_var(&lookup, "ERROR_FORMAT", ErrorFormat::default(), &mut errors);
+
+        let health_check_timeout = Duration::from_millis(parse_var(
+            &lookup,
+            "HEALTH_CHECK_TIMEOUT_MS",
+            DEFAULT_HEALTH_CHECK_TIMEOUT_MS,
+            &mut errors,
+        ));
+        if health_check_timeout.is_zero() {
+            errors.push("HEALTH_CHECK_TIMEOUT_MS must be greater than 0".to_string());
+        }
+
+        let health_check_cache_ttl = Duration::from_millis(parse_var(
+            &lookup,
+            "HEALTH_CHECK_CACHE_MS",
+            DEFAULT_HEALTH_CHECK_CACHE_MS,
+            &mut errors,
+        ));
+
+        let health_disk_path = lookup("HEALTH_DISK_PATH")
+            .map(PathBuf::from)
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_HEALTH_DISK_PATH));
+
+        let health_disk_min_free_mb: u64 = parse_var(
+            &lookup,
+            "HEALTH_DISK_MIN_FREE_MB",
+            DEFAULT_HEALTH_DISK_MIN_FREE_MB,
+            &mut errors,
+        );
+        let health_disk_min_free_bytes = health_disk_min_free_mb
+            .checked_mul(1024 * 1024)
+            .unwrap_or_else(|| {
+                errors.push(format!(
+                    "HEALTH_DISK_MIN_FREE_MB is too large: {health_disk_min_free_mb}"
+                ));
+                0
+            });
+
+        let otlp = otlp_settings(&lookup, &mut errors);
+        let auth = auth_settings(&lookup, &mut errors);
+
+        let trusted_proxies = match lookup("TRUSTED_PROXIES") {
+            None => Vec::new(),
+            Some(raw) => raw
+                .split(',')
+                .filter(|entry| !entry.trim().is_empty())
+                .filter_map(|entry| {
+                    IpNet::parse(entry)
+                        .map_err(|err| errors.push(format!("TRUSTED_PROXIES: {err}")))
+                        .ok()
+                })
+                .collect(),
+        };
+
+        let rate_limit = rate_limit_settings(&lookup, &mut errors);
+        let concurrency = concurrency_settings(&lookup, &mut errors);
+        let cors = cors_settings(&lookup, &mut errors);
+
+        if !errors.is_empty() {
+            return Err(ConfigError { errors });
+        }
+
+        Ok(Self {
+            database_url,
+            db_pool_max_size,
+            db_pool_timeout,
+            db_connect_timeout,
+            db_auto_migrate,
+            listen,
+            admin_port,
+            admin_bind_addr,
+            tls,
+            shutdown_timeout,
+            request_timeout,
+            upgrade_timeout,
+            log_level,
+            log_format,
+            error_format,
+            health_check_timeout,
+            health_check_cache_ttl,
+            health_disk_path,
+            health_disk_min_free_bytes,
+            otlp,
+            auth,
+            trusted_proxies,
+            rate_limit,
+            concurrency,
+            cors,
+        })
+    }
+}
+
+/// Secrets (the database URL may embed credentials) are never printed;
+/// only the URL scheme is shown.
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = self
+            .database_url
+            .split_once("://")
+            .map_or("<unknown>", |(scheme, _)| scheme);
+
+        f.debug_struct("Config")
+            .field("database_url", &format_args!("{scheme}://<hidden>"))
+            .field("db_pool_max_size", &self.db_pool_max_size)
+            .field("db_pool_timeout", &self.db_pool_timeout)
+            .field("db_connect_timeout", &self.db_connect_timeout)
+            .field("db_auto_migrate", &self.db_auto_migrate)
+            .field("listen", &format_args!("{}", self.listen))
+            .field("admin_port", &self.admin_port)
+            .field("admin_bind_addr", &self.admin_bind_addr)
+            .field("tls", &self.tls)
+            .field("shutdown_timeout", &self.shutdown_timeout)
+            .field("request_timeout", &self.request_timeout)
+            .field("upgrade_timeout", &self.upgrade_timeout)
+            .field("log_level", &format_args!("{}", self.log_level))
+            .field("log_format", &self.log_format)
+            .field("error_format", &self.error_format)
+            .field("health_check_timeout", &self.health_check_timeout)
+            .field("health_check_cache_ttl", &self.health_check_cache_ttl)
+            .field("health_disk_path", &self.health_disk_path)
+            .field(
+                "health_disk_min_free_bytes",
+                &self.health_disk_min_free_bytes,
+            )
+            .field("otlp", &self.otlp)
+            .field("auth", &self.auth)
+            .field("trusted_proxies", &self.trusted_proxies)
+            .field("rate_limit", &self.rate_limit)
+            .field("concurrency", &self.concurrency)
+            .field("cors", &self.cors)
+            .finish()
+    }
+}
+
+/// Certificate and private key for TLS on the main listener.
+#[derive(Clone, Debug)]
+pub struct TlsSettings {
+    /// PEM certificate chain, leaf first.
+    pub cert_file: PathBuf,
+    /// PEM private key matching the certificate.
+    pub key_file: PathBuf,
+    /// Client certificate verification (mutual TLS).
+    pub client_auth: ClientAuth,
+    /// PEM bundle of CAs trusted to sign client certificates; set
+    /// whenever `client_auth` is not `Off`.
+    pub client_ca_file: Option<PathBuf>,
+}
+
+/// Whether clients must present a certificate (TLS_CLIENT_AUTH).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ClientAuth {
+    /// No client certificate is requested.
+    #[default]
+    Off,
+    /// Requested; clients without one are still accepted, but one
+    /// that is presented must verify.
+    Optional,
+    /// Handshakes without a verified client certificate fail.
+    Required,
+}
+
+impl FromStr for ClientAuth {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "off" => Ok(Self::Off),
+            "optional" => Ok(Self::Optional),
+            "required" => Ok(Self::Required),
+            _ => Err(()),
+        }
+    }
+}
+
+/// TLS_CERT_FILE and TLS_KEY_FILE, which must be set together, and
+/// the TLS_CLIENT_AUTH / TLS_CLIENT_CA_FILE pair for mutual TLS.
+fn tls_settings<F>(lookup: &F, listen: &ListenAddr, errors: &mut Vec<String>) -> Option<TlsSettings>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let cert_file = lookup("TLS_CERT_FILE").filter(|v| !v.trim().is_empty());
+    let key_file = lookup("TLS_KEY_FILE").filter(|v| !v.trim().is_empty());
+    let client_auth = parse_var(lookup, "TLS_CLIENT_AUTH", ClientAuth::Off, errors);
+    let client_ca_file = lookup("TLS_CLIENT_CA_FILE")
+        .filter(|v| !v.trim().is_empty())
+        .map(|v| PathBuf::from(v.trim()));
+
+    let settings = match (cert_file, key_file) {
+        (None, None) => {
+            if client_auth != ClientAuth::Off {
+                errors.push("TLS_CLIENT_AUTH requires TLS_CERT_FILE and TLS_KEY_FILE".to_string());
+            }
+            return None;
+        }
+        (Some(cert_file), Some(key_file)) => TlsSettings {
+            cert_file: PathBuf::from(cert_file.trim()),
+            key_file: PathBuf::from(key_file.trim()),
+            client_auth,
+            client_ca_file,
+        },
+        _ => {
+            errors.push("TLS_CERT_FILE and TLS_KEY_FILE must be set together".to_string());
+            return None;
+        }
+    };
+
+    if settings.client_auth != ClientAuth::Off && settings.client_ca_file.is_none() {
+        errors.push("TLS_CLIENT_AUTH requires TLS_CLIENT_CA_FILE".to_string());
+        return None;
+    }
+
+    if cfg!(not(unix)) {
+        errors.push("TLS is only supported on Unix".to_string());
+        return None;
+    }
+    if !matches!(listen, ListenAddr::Tcp(_)) {
+        errors.push("TLS_CERT_FILE needs a TCP listener, not APP_LISTEN=unix:".to_string());
+        return None;
+    }
+    Some(settings)
+}
+
+/// Parse an optional variable, falling back to `default` when unset.
+///
+/// A value that is set but malformed is an error, never a silent fallback.
+fn parse_var<F, T>(lookup: &F, key: &str, default: T, errors: &mut Vec<String>) -> T
+where
+    F: Fn(&str) -> Option<String>,
+    T: FromStr,
+{
+    match lookup(key) {
+        None => default,
+        Some(raw) => match raw.trim().parse() {
+            Ok(v) => v,
+            Err(_) => {
+                errors.push(format!("{key} has an invalid value: {raw:?}"));
+                default
+            }
+        },
+    }
+}
+
+/// An IP address variable, defaulting to the IPv4 wildcard.
+fn parse_ip_var<F>(lookup: &F, key: &str, errors: &mut Vec<String>) -> IpAddr
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let default = IpAddr::V4(Ipv4Addr::UNSPECIFIED);
+    match lookup(key) {
+        None => default,
+        Some(raw) => listener::parse_ip(&raw).unwrap_or_else(|err| {
+            errors.push(format!("{key}: {err}"));
+            default
+        }),
+    }
+}
+
+/// APP_LISTEN=unix:/path, with APP_SOCKET_MODE (octal) permissions.
+#[cfg(unix)]
+fn unix_listen_addr<F>(lookup: &F, raw: &str, errors: &mut Vec<String>) -> Option<ListenAddr>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mode = match lookup("APP_SOCKET_MODE") {
+        None => DEFAULT_SOCKET_MODE,
+        Some(mode) => match u32::from_str_radix(mode.trim(), 8) {
+            Ok(mode) if mode <= 0o777 => mode,
+            _ => {
+                errors.push(format!(
+                    "APP_SOCKET_MODE must be octal permissions such as 660, got {mode:?}"
+                ));
+                DEFAULT_SOCKET_MODE
+            }
+        },
+    };
+
+    match listener::parse_unix_path(raw) {
+        Some(path) => Some(ListenAddr::Unix { path, mode }),
+        None => {
+            errors.push(format!(
+                "APP_LISTEN must be unix:/path/to.sock, got {raw:?}"
+            ));
+            None
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn unix_listen_addr<F>(_lookup: &F, _raw: &str, errors: &mut Vec<String>) -> Option<ListenAddr>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    errors.push("APP_LISTEN (Unix sockets) is only supported on Unix".to_string());
+    None
+}
+
+/// OTLP trace export from the standard `OTEL_*` variables.
+///
+/// Export is off unless an endpoint is set. Signal-specific
+/// `OTEL_EXPORTER_OTLP_TRACES_*` variables take precedence over the
+/// generic ones, headers included (the two lists are not merged). A
+/// generic endpoint gets `/v1/traces` appended for `http/protobuf`;
+/// gRPC uses it as is.
+fn otlp_settings<F>(lookup: &F, errors: &mut Vec<String>) -> Option<OtlpSettings>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    // The variable in effect for `suffix`, and its value.
+    let traces_or = |suffix: &str| {
+        let traces = format!("OTEL_EXPORTER_OTLP_TRACES_{suffix}");
+        match lookup(&traces) {
+            Some(value) => Some((traces, value)),
+            None => {
+                let generic = format!("OTEL_EXPORTER_OTLP_{suffix}");
+                lookup(&generic).map(|value| (generic, value))
+            }
+        }
+    };
+
+    match lookup("OTEL_TRACES_EXPORTER").as_deref().map(str::trim) {
+        None | Some("otlp") => {}
+        Some("none") => return None,
+        Some(other) => {
+            errors.push(format!(
+                "OTEL_TRACES_EXPORTER has an invalid value: {other:?} (expected otlp or none)"
+            ));
+            return None;
+        }
+    }
+
+    let protocol = match traces_or("PROTOCOL") {
+        None => OtlpProtocol::default(),
+        Some((key, raw)) => raw.trim().parse().unwrap_or_else(|_| {
+            errors.push(format!(
+                "{key} {raw:?} is not supported (expected http/protobuf or grpc)"
+            ));
+            OtlpProtocol::default()
+        }),
+    };
+
+    let (key, url) = match (
+        lookup("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT"),
+        lookup("OTEL_EXPORTER_OTLP_ENDPOINT"),
+    ) {
+        (Some(url), _) => ("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT", url),
+        (None, Some(base)) if protocol == OtlpProtocol::Grpc => {
+            ("OTEL_EXPORTER_OTLP_ENDPOINT", base)
+        }
+        (None, Some(base)) => (
+            "OTEL_EXPORTER_OTLP_ENDPOINT",
+            format!("{}/v1/traces", base.trim().trim_end_matches('/')),
+        ),
+        (None, None) => return None,
+    };
+
+    let endpoint = match Endpoint::parse(url.trim()) {
+        Ok(endpoint) => Some(endpoint),
+        Err(err) => {
+            errors.push(format!("{key}: {err}"));
+            None
+        }
+    };
+
+    let headers = match traces_or("HEADERS") {
+        None => HeaderMap::new(),
+        Some((key, raw)) => match parse_pairs(&raw) {
+            Some(pairs) => otlp_headers(&key, pairs, errors),
+            None => {
+                errors.push(format!("{key} must be a list of key=value pairs"));
+                HeaderMap::new()
+            }
+        },
+    };
+
+    let timeout_ms = match traces_or("TIMEOUT") {
+        None => DEFAULT_OTLP_TIMEOUT_MS,
+        Some((key, raw)) => raw.trim().parse().unwrap_or_else(|_| {
+            errors.push(format!("{key} has an invalid value: {raw:?}"));
+            DEFAULT_OTLP_TIMEOUT_MS
+        }),
+    };
+
+    let mut resource = match lookup("OTEL_RESOURCE_ATTRIBUTES") {
+        None => Vec::new(),
+        Some(raw) => parse_pairs(&raw).unwrap_or_else(|| {
+            errors.push("OTEL_RESOURCE_ATTRIBUTES must be a list of key=value pairs".to_string());
+            Vec::new()
+        }),
+    };
+    // OTEL_SERVICE_NAME wins over a service.name resource attribute.
+    let service_name = lookup("OTEL_SERVICE_NAME")
+        .or_else(|| {
+            resource
+                .iter()
+                .find(|(k, _)| k == "service.name")
+                .map(|(_, v)| v.clone())
+        })
+        .unwrap_or_else(|| env!("CARGO_PKG_NAME").to_string());
+    resource.retain(|(k, _)| k != "service.name");
+    resource.insert(0, ("service.name".to_string(), service_name));
+
+    Some(OtlpSettings {
+        endpoint: endpoint?,
+        protocol,
+        headers,
+        timeout: Duration::from_millis(timeout_ms),
+        resource,
+    })
+}
+
+/// API authentication: an HS256 secret (AUTH_JWT_SECRET) and/or a
+/// JWKS (AUTH_JWKS_FILE or AUTH_JWKS_URL) for RS256 / ES256 bearer
+/// tokens, and database API keys (AUTH_API_KEYS).
+///
+/// Authentication is off unless one of these is set; issuer and
+/// audience checks without one are a configuration error, not a
+/// silently open API.
+fn auth_settings<F>(lookup: &F, errors: &mut Vec<String>) -> Option<AuthSettings>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let non_empty = |key: &str| lookup(key).filter(|v| !v.trim().is_empty());
+
+    let secret = non_empty("AUTH_JWT_SECRET").map(String::into_bytes);
+    if let Some(secret) = &secret
+        && secret.len() < MIN_JWT_SECRET_BYTES
+    {
+        errors.push(format!(
+            "AUTH_JWT_SECRET must be at least {MIN_JWT_SECRET_BYTES} bytes"
+        ));
+    }
+
+    let jwks = match (non_empty("AUTH_JWKS_FILE"), non_empty("AUTH_JWKS_URL")) {
+        (None, None) => None,
+        (Some(path), None) => Some(JwksSource::File(PathBuf::from(path.trim()))),
+        (None, Some(url)) => match Endpoint::parse(url.trim()) {
+            Ok(endpoint) => Some(JwksSource::Url(endpoint)),
+            Err(err) => {
+                errors.push(format!("AUTH_JWKS_URL: {err}"));
+                None
+            }
+        },
+        (Some(_), Some(_)) => {
+            errors.push("AUTH_JWKS_FILE and AUTH_JWKS_URL are mutually exclusive".to_string());
+            None
+        }
+    };
+
+    let issuer = non_empty("AUTH_JWT_ISSUER").map(|v| v.trim().to_string());
+    let audience = non_empty("AUTH_JWT_AUDIENCE").map(|v| v.trim().to_string());
+    let jwks_cache_secs = parse_var(
+        lookup,
+        "AUTH_JWKS_CACHE_SECS",
+        DEFAULT_JWKS_CACHE_SECS,
+        errors,
+    );
+    let leeway_secs = parse_var(
+        lookup,
+        "AUTH_JWT_LEEWAY_SECS",
+        DEFAULT_JWT_LEEWAY_SECS,
+        errors,
+    );
+    let api_keys = parse_var(lookup, "AUTH_API_KEYS", false, errors);
+    let policy_file = non_empty("AUTH_POLICY_FILE").map(|v| PathBuf::from(v.trim()));
+
+    if secret.is_none() && jwks.is_none() && (issuer.is_some() || audience.is_some()) {
+        errors.push(
+            "AUTH_JWT_ISSUER / AUTH_JWT_AUDIENCE require AUTH_JWT_SECRET, AUTH_JWKS_FILE or AUTH_JWKS_URL"
+                .to_string(),
+        );
+    }
+    if secret.is_none() && jwks.is_none() && !api_keys {
+        if policy_file.is_some() {
+            errors.push(
+                "AUTH_POLICY_FILE requires AUTH_JWT_SECRET, AUTH_JWKS_FILE, AUTH_JWKS_URL or AUTH_API_KEYS"
+                    .to_string(),
+            );
+        }
+        return None;
+    }
+
+    Some(AuthSettings {
+        secret,
+        jwks,
+        jwks_cache_ttl: Duration::from_secs(jwks_cache_secs),
+        issuer,
+        audience,
+        leeway: Duration::from_secs(leeway_secs),
+        api_keys,
+        policy_file,
+    })
+}
+
+/// RATE_LIMIT (the default quota) and RATE_LIMIT_ROUTES (per-route
+/// quotas); rate limiting is off when neither is set.
+fn rate_limit_settings<F>(lookup: &F, errors: &mut Vec<String>) -> Option<RateLimitSettings>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let default = lookup("RATE_LIMIT")
+        .filter(|v| !v.trim().is_empty())
+        .and_then(|raw| {
+            raw.parse()
+                .map_err(|_| {
+                    errors.push(format!(
+                        "RATE_LIMIT must look like 100/m (requests per s, m, h or d; at most 1000/s), got {raw:?}"
+                    ))
+                })
+                .ok()
+        });
+    let routes = match lookup("RATE_LIMIT_ROUTES") {
+        None => HashMap::new(),
+        Some(raw) => ratelimit::parse_routes(&raw).unwrap_or_else(|err| {
+            errors.push(format!("RATE_LIMIT_ROUTES: {err}"));
+            HashMap::new()
+        }),
+    };
+    let store = parse_var(lookup, "RATE_LIMIT_STORE", Default::default(), errors);
+
+    if default.is_none() && routes.is_empty() {
+        return None;
+    }
+    Some(RateLimitSettings {
+        default,
+        routes,
+        store,
+    })
+}
+
+/// CONCURRENCY_LIMIT turns on the limiter; the other CONCURRENCY_*
+/// variables tune it and are an error without it.
+fn concurrency_settings<F>(lookup: &F, errors: &mut Vec<String>) -> Option<ConcurrencySettings>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let max: Option<usize> = lookup("CONCURRENCY_LIMIT").and_then(|raw| {
+        raw.trim().parse().ok().filter(|max| *max > 0).or_else(|| {
+            errors.push(format!(
+                "CONCURRENCY_LIMIT must be a number greater than 0, got {raw:?}"
+            ));
+            None
+        })
+    });
+    let queue_size = parse_var(
+        lookup,
+        "CONCURRENCY_QUEUE_SIZE",
+        DEFAULT_CONCURRENCY_QUEUE_SIZE,
+        errors,
+    );
+    let queue_timeout = Duration::from_millis(parse_var(
+        lookup,
+        "CONCURRENCY_QUEUE_TIMEOUT_MS",
+        DEFAULT_CONCURRENCY_QUEUE_TIMEOUT_MS,
+        errors,
+    ));
+    let mode = parse_var(lookup, "CONCURRENCY_MODE", LimitMode::default(), errors);
+    let min = parse_var(
+        lookup,
+        "CONCURRENCY_MIN_LIMIT",
+        DEFAULT_CONCURRENCY_MIN_LIMIT,
+        errors,
+    );
+    let target_latency = Duration::from_millis(parse_var(
+        lookup,
+        "CONCURRENCY_TARGET_LATENCY_MS",
+        DEFAULT_CONCURRENCY_TARGET_LATENCY_MS,
+        errors,
+    ));
+
+    let Some(max) = max else {
+        let tuned = [
+            "CONCURRENCY_QUEUE_SIZE",
+            "CONCURRENCY_QUEUE_TIMEOUT_MS",
+            "CONCURRENCY_MODE",
+            "CONCURRENCY_MIN_LIMIT",
+            "CONCURRENCY_TARGET_LATENCY_MS",
+        ];
+        if lookup("CONCURRENCY_LIMIT").is_none() && tuned.iter().any(|key| lookup(key).is_some()) {
+            errors.push("CONCURRENCY_* settings require CONCURRENCY_LIMIT".to_string());
+        }
+        return None;
+    };
+    if min == 0 || min > max {
+        errors.push(format!(
+            "CONCURRENCY_MIN_LIMIT must be between 1 and CONCURRENCY_LIMIT ({max})"
+        ));
+    }
+    if target_latency.is_zero() {
+        errors.push("CONCURRENCY_TARGET_LATENCY_MS must be greater than 0".to_string());
+    }
+
+    Some(ConcurrencySettings {
+        max,
+        min,
+        queue_size,
+        queue_timeout,
+        mode,
+        target_latency,
+    })
+}
+
+/// CORS_ALLOWED_ORIGINS turns on CORS; the other CORS_* variables
+/// tune it and are an error without it.
+fn cors_settings<F>(lookup: &F, errors: &mut Vec<String>) -> Option<CorsSettings>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    fn list<T>(
+        lookup: &impl Fn(&str) -> Option<String>,
+        key: &str,
+        default: &str,
+        parse: fn(&str) -> Result<T, String>,
+        errors: &mut Vec<String>,
+    ) -> Option<T> {
+        let raw = lookup(key).unwrap_or_else(|| default.to_string());
+        parse(&raw)
+            .map_err(|err| errors.push(format!("{key}: {err}")))
+            .ok()
+    }
+
+    let origins = lookup("CORS_ALLOWED_ORIGINS")
+        .filter(|v| !v.trim().is_empty())
+        .and_then(|raw| {
+            cors::parse_origins(&raw)
+                .map_err(|err| errors.push(format!("CORS_ALLOWED_ORIGINS: {err}")))
+                .ok()
+        });
+    let methods = list(
+        lookup,
+        "CORS_ALLOWED_METHODS",
+        DEFAULT_CORS_ALLOWED_METHODS,
+        cors::parse_methods,
+        errors,
+    );
+    let headers = list(
+        lookup,
+        "CORS_ALLOWED_HEADERS",
+        DEFAULT_CORS_ALLOWED_HEADERS,
+        cors::parse_headers,
+        errors,
+    );
+    let expose_headers = list(
+        lookup,
+        "CORS_EXPOSE_HEADERS",
+        DEFAULT_CORS_EXPOSE_HEADERS,
+        cors::parse_headers,
+        errors,
+    );
+    let credentials = parse_var(lookup, "CORS_ALLOW_CREDENTIALS", false, errors);
+    let max_age = Duration::from_secs(parse_var(
+        lookup,
+        "CORS_MAX_AGE",
+        DEFAULT_CORS_MAX_AGE_SECS,
+        errors,
+    ));
+
+    let Some(origins) = origins else {
+        let tuned = [
+            "CORS_ALLOWED_METHODS",
+            "CORS_ALLOWED_HEADERS",
+            "CORS_EXPOSE_HEADERS",
+            "CORS_ALLOW_CREDENTIALS",
+            "CORS_MAX_AGE",
+        ];
+        if lookup("CORS_ALLOWED_ORIGINS").is_none() && tuned.iter().any(|key| lookup(key).is_some())
+        {
+            errors.push("CORS_* settings require CORS_ALLOWED_ORIGINS".to_string());
+        }
+        return None;
+    };
+    if credentials
+        && origins
+            .iter()
+            .any(|origin| matches!(origin, cors::AllowedOrigin::Any))
+    {
+        errors.push(
+            "CORS_ALLOW_CREDENTIALS=true cannot be combined with CORS_ALLOWED_ORIGINS=*; \
+             list the allowed origins instead"
+                .to_string(),
+        );
+    }
+    let expose_headers = match expose_headers {
+        Some(None) => {
+            errors.push("CORS_EXPOSE_HEADERS must list header names, not *".to_string());
+            None
+        }
+        other => other.flatten(),
+    };
+
+    Some(CorsSettings {
+        origins,
+        methods: methods?,
+        headers: headers?,
+        expose_headers: expose_headers?,
+        credentials,
+        max_age,
+    })
+}
+
+/// Request headers from the `key` pairs. Values are percent-decoded,
+/// so they can hold anything and are checked like any header value.
+fn otlp_headers(key: &str, pairs: Vec<(String, String)>, errors: &mut Vec<String>) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for (name, value) in pairs {
+        match (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(&value),
+        ) {
+            (Ok(name), Ok(value)) => {
+                headers.append(name, value);
+            }
+            (Err(_), _) => errors.push(format!("{key}: {name:?} is not a valid header name")),
+            // Values are usually credentials, so not repeated.
+            (Ok(name), Err(_)) => errors.push(format!(
+                "{key}: the value of {name} is not a valid header value"
+            )),
+        }
+    }
+    headers
+}
+
+/// `k1=v1,k2=v2` with percent-encoded values (the W3C Baggage format
+/// used by `OTEL_*` list variables).
+fn parse_pairs(raw: &str) -> Option<Vec<(String, String)>> {
+    raw.split(',')
+        .filter(|pair| !pair.trim().is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=')?;
+            let key = key.trim();
+            (!key.is_empty()).then(|| (key.to_string(), percent_decode(value.trim())))
+        })
+        .collect()
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let decoded = (bytes[i] == b'%')
+            .then(|| value.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match decoded {
+            Some(byte) => {
+                out.push(byte);
+                i += 3;
+            }
+            None => {
+                out.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// --------------------------------------------------
+// Errors
+// --------------------------------------------------
+
+#[derive(Debug)]
+pub struct ConfigError {
+    pub errors: Vec<String>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid configuration: {}", self.errors.join("; "))
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Configuration from `vars` plus a database URL.
+    fn load(vars: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let vars: HashMap<String, String> = [("DATABASE_URL", "sqlite::memory:")]
+            .iter()
+            .chain(vars)
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Config::from_lookup(|key| vars.get(key).cloned())
+    }
+
+    #[test]
+    fn defaults_apply_when_unset() {
+        let config = load(&[]).unwrap();
+        assert_eq!(config.listen.to_string(), "http://0.0.0.0:8080");
+        assert_eq!(config.admin_port, None);
+        assert_eq!(config.db_pool_max_size, 10);
+        assert!(config.db_auto_migrate);
+        assert_eq!(config.request_timeout, Duration::from_secs(30));
+        assert_eq!(config.shutdown_timeout, Duration::from_secs(10));
+        assert_eq!(config.error_format, ErrorFormat::Envelope);
+        assert!(config.tls.is_none());
+        assert!(config.otlp.is_none());
+        assert!(config.auth.is_none());
+        assert!(config.rate_limit.is_none());
+        assert!(config.cors.is_none());
+    }
+
+    #[test]
+    fn values_are_parsed() {
+        let config = load(&[
+            ("APP_PORT", "9000"),
+            ("APP_BIND", "[::1]"),
+            ("ADMIN_PORT", "9001"),
+            ("DB_POOL_MAX_SIZE", " 4 "),
+            ("DB_AUTO_MIGRATE", "false"),
+            ("REQUEST_TIMEOUT_MS", "1500"),
+            ("ERROR_FORMAT", "Problem"),
+        ])
+        .unwrap();
+        assert_eq!(config.listen.to_string(), "http://[::1]:9000");
+        assert_eq!(config.admin_port, Some(9001));
+        assert_eq!(config.db_pool_max_size, 4);
+        assert!(!config.db_auto_migrate);
+        assert_eq!(config.request_timeout, Duration::from_millis(1500));
+        assert_eq!(config.error_format, ErrorFormat::Problem);
+    }
+
+    #[test]
+    fn every_error_is_reported() {
+        let err = load(&[
+            ("DB_POOL_MAX_SIZE", "ten"),
+            ("APP_PORT", "0"),
+            ("LOG_FORMAT", "xml"),
+            ("TLS_CERT_FILE", "/etc/tls/cert.pem"),
+        ])
+        .unwrap_err();
+        assert_eq!(
+            err.errors,
+            [
+                "DB_POOL_MAX_SIZE has an invalid value: \"ten\"",
+                "APP_PORT must be between 1 and 65535",
+                "TLS_CERT_FILE and TLS_KEY_FILE must be set together",
+                "LOG_FORMAT has an invalid value: \"xml\"",
+            ]
+        );
+    }
+
+    #[test]
+    fn database_url_is_required_and_hidden() {
+        let err = Config::from_lookup(|_| None).unwrap_err();
+        assert_eq!(err.errors, ["DATABASE_URL is not set"]);
+
+        let err = load(&[("DATABASE_URL", "mysql://localhost/app")]).unwrap_err();
+        assert_eq!(err.errors.len(), 1);
+        assert!(err.errors[0].starts_with("DATABASE_URL: "));
+
+        let config = load(&[("DATABASE_URL", "postgres://app:hunter2@db/app")]).unwrap();
+        let debug = format!("{config:?}");
+        assert!(debug.contains("postgres://<hidden>"));
+        assert!(!debug.contains("hunter2"));
+    }
+
+    #[test]
+    fn disk_threshold_is_stored_in_bytes() {
+        let config = load(&[("HEALTH_DISK_MIN_FREE_MB", "2")]).unwrap();
+        assert_eq!(config.health_disk_min_free_bytes, 2 * 1024 * 1024);
+
+        let err = load(&[("HEALTH_DISK_MIN_FREE_MB", &u64::MAX.to_string())]).unwrap_err();
+        assert_eq!(
+            err.errors,
+            [format!(
+                "HEALTH_DISK_MIN_FREE_MB is too large: {}",
+                u64::MAX
+            )]
+        );
+    }
+
+    #[test]
+    fn admin_port_must_differ() {
+        let err = load(&[("APP_PORT", "9000"), ("ADMIN_PORT", "9000")]).unwrap_err();
+        assert_eq!(err.errors, ["ADMIN_PORT must differ from APP_PORT"]);
+    }
+
+    #[test]
+    fn cors_credentials_need_listed_origins() {
+        let err = load(&[
+            ("CORS_ALLOWED_ORIGINS", "*"),
+            ("CORS_ALLOW_CREDENTIALS", "true"),
+        ])
+        .unwrap_err();
+        assert_eq!(
+            err.errors,
+            [
+                "CORS_ALLOW_CREDENTIALS=true cannot be combined with CORS_ALLOWED_ORIGINS=*; \
+                 list the allowed origins instead"
+            ]
+        );
+
+        let config = load(&[
+            (
+                "CORS_ALLOWED_ORIGINS",
+                "https://app.example.com, https://*.example.net",
+            ),
+            ("CORS_ALLOW_CREDENTIALS", "true"),
+        ])
+        .unwrap();
+        let cors = config.cors.unwrap();
+        assert!(cors.credentials);
+        assert_eq!(cors.origins.len(), 2);
+    }
+
+    #[test]
+    fn otlp_signal_specific_variables_win() {
+        let config = load(&[
+            ("OTEL_EXPORTER_OTLP_ENDPOINT", "http://collector:4318/"),
+            (
+                "OTEL_EXPORTER_OTLP_HEADERS",
+                "authorization=Bearer%20generic,x-tenant=a",
+            ),
+            (
+                "OTEL_EXPORTER_OTLP_TRACES_HEADERS",
+                "authorization=Bearer%20traces",
+            ),
+            ("OTEL_EXPORTER_OTLP_TIMEOUT", "2500"),
+        ])
+        .unwrap();
+        let otlp = config.otlp.unwrap();
+        assert_eq!(otlp.endpoint.to_string(), "http://collector:4318/v1/traces");
+        assert_eq!(otlp.protocol, OtlpProtocol::HttpProtobuf);
+        assert_eq!(otlp.headers.len(), 1);
+        assert_eq!(otlp.headers["authorization"], "Bearer traces");
+        assert_eq!(otlp.timeout, Duration::from_millis(2500));
+    }
+
+    #[test]
+    fn otlp_grpc_uses_the_endpoint_as_is() {
+        let config = load(&[
+            ("OTEL_EXPORTER_OTLP_ENDPOINT", "http://collector:4317"),
+            ("OTEL_EXPORTER_OTLP_PROTOCOL", "grpc"),
+        ])
+        .unwrap();
+        let otlp = config.otlp.unwrap();
+        assert_eq!(otlp.protocol, OtlpProtocol::Grpc);
+        assert_eq!(otlp.endpoint.to_string(), "http://collector:4317/");
+
+        let err = load(&[
+            ("OTEL_EXPORTER_OTLP_ENDPOINT", "http://collector:4318"),
+            ("OTEL_EXPORTER_OTLP_TRACES_PROTOCOL", "http/json"),
+        ])
+        .unwrap_err();
+        assert_eq!(
+            err.errors,
+            [
+                "OTEL_EXPORTER_OTLP_TRACES_PROTOCOL \"http/json\" is not supported \
+                 (expected http/protobuf or grpc)"
+            ]
+        );
+    }
+
+    #[test]
+    fn otlp_headers_must_be_valid() {
+        let err = load(&[
+            ("OTEL_EXPORTER_OTLP_ENDPOINT", "http://collector:4318"),
+            (
+                "OTEL_EXPORTER_OTLP_HEADERS",
+                "authorization=secret%0D%0AX-Injected: 1,bad name=x,x-tenant=a",
+            ),
+        ])
+        .unwrap_err();
+        assert_eq!(
+            err.errors,
+            [
+                "OTEL_EXPORTER_OTLP_HEADERS: the value of authorization is not a valid header value",
+                "OTEL_EXPORTER_OTLP_HEADERS: \"bad name\" is not a valid header name",
+            ]
+        );
+        assert!(!err.to_string().contains("secret"));
+    }
+}
@@ -0,0 +1,578 @@
+// ==================================================
+// Cross-origin resource sharing
+// ==================================================
+// Lets browser frontends served from other origins call the API
+// (the CORS protocol of the Fetch standard). Off unless
+// CORS_ALLOWED_ORIGINS is set; its comma-separated entries are:
+//
+// - `https://app.example.com`: that origin exactly
+// - `https://*.example.com`: any subdomain of example.com, at any
+//   depth but not example.com itself, with that scheme and port
+// - `regex:https://(app|admin)\.example\.(com|net)`: origins the
+//   pattern (`regex` crate syntax, so matching takes linear time)
+//   matches in full; commas cannot be used
+// - `*`: any origin, which rules out CORS_ALLOW_CREDENTIALS
+//
+// Preflight requests (OPTIONS with Access-Control-Request-Method)
+// are answered here, before authentication, rate limiting or
+// routing: 204 when the origin, method and headers are allowed, 403
+// with the reason otherwise. Other requests run as usual, and the
+// response, errors included, gets Access-Control-Allow-Origin and
+// friends if their origin is allowed. Responses that depend on the
+// origin say so with `Vary`, so shared caches keep them apart.
+
+use crate::error::ApiError;
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use regex::Regex;
+use std::{fmt, str::FromStr, sync::Arc, time::Duration};
+use tracing::debug;
+
+// --------------------------------------------------
+// Settings
+// --------------------------------------------------
+
+/// One CORS_ALLOWED_ORIGINS entry.
+#[derive(Clone, Debug)]
+pub enum AllowedOrigin {
+    Any,
+    /// Lowercase `scheme://host[:port]`.
+    Exact(String),
+    /// `scheme://*.domain[:port]`, split around the `*`.
+    Subdomain {
+        prefix: String,
+        suffix: String,
+    },
+    /// `regex:` entries; `regex` is `pattern` anchored at both ends.
+    Pattern {
+        pattern: String,
+        regex: Regex,
+    },
+}
+
+impl AllowedOrigin {
+    fn matches(&self, origin: &str) -> bool {
+        match self {
+            Self::Any => true,
+            Self::Exact(exact) => origin.eq_ignore_ascii_case(exact),
+            Self::Subdomain { prefix, suffix } => {
+                let origin = origin.to_ascii_lowercase();
+                let Some(host) = origin
+                    .strip_prefix(prefix.as_str())
+                    .and_then(|rest| rest.strip_suffix(suffix.as_str()))
+                else {
+                    return false;
+                };
+                !host.is_empty()
+                    && host.split('.').all(|label| {
+                        !label.is_empty()
+                            && label
+                                .bytes()
+                                .all(|b| b.is_ascii_alphanumeric() || b == b'-')
+                    })
+            }
+            Self::Pattern { regex, .. } => regex.is_match(origin),
+        }
+    }
+}
+
+impl FromStr for AllowedOrigin {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        if s == "*" {
+            return Ok(Self::Any);
+        }
+        if let Some(pattern) = s.strip_prefix("regex:") {
+            // The whole origin has to match, so `https://app\.example\.com`
+            // does not also allow `https://app.example.com.evil.net`.
+            return Regex::new(&format!("^(?:{pattern})$"))
+                .map(|regex| Self::Pattern {
+                    pattern: pattern.to_string(),
+                    regex,
+                })
+                .map_err(|err| format!("invalid pattern {pattern:?}: {}", regex_error(&err)));
+        }
+
+        let origin = s.to_ascii_lowercase();
+        let not_an_origin = || format!("{s:?} is not an origin (scheme://host[:port], no path)");
+        let (scheme, authority) = origin.split_once("://").ok_or_else(not_an_origin)?;
+        if scheme.is_empty()
+            || !scheme
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'+' | b'-' | b'.'))
+            || authority.is_empty()
+            || authority.contains(['/', '?', '#', '@'])
+        {
+            return Err(not_an_origin());
+        }
+        match authority.strip_prefix('*') {
+            Some(suffix) if suffix.starts_with('.') && !suffix.contains('*') => {
+                Ok(Self::Subdomain {
+                    prefix: format!("{scheme}://"),
+                    suffix: suffix.to_string(),
+                })
+            }
+            _ if authority.contains('*') => Err(format!(
+                "{s:?}: only a leading `*.` subdomain wildcard is supported"
+            )),
+            _ => Ok(Self::Exact(origin)),
+        }
+    }
+}
+
+/// The reason alone: syntax errors span several lines, quoting the
+/// anchored pattern, and the last one says what is wrong.
+fn regex_error(err: &regex::Error) -> String {
+    match err {
+        regex::Error::Syntax(message) => message
+            .lines()
+            .last()
+            .unwrap_or_default()
+            .trim_start_matches("error: ")
+            .to_string(),
+        err => err.to_string(),
+    }
+}
+
+impl fmt::Display for AllowedOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Any => f.write_str("*"),
+            Self::Exact(origin) => f.write_str(origin),
+            Self::Subdomain { prefix, suffix } => write!(f, "{prefix}*{suffix}"),
+            Self::Pattern { pattern, .. } => write!(f, "regex:{pattern}"),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct CorsSettings {
+    pub origins: Vec<AllowedOrigin>,
+    pub methods: Vec<Method>,
+    /// Request headers allowed; `None` allows any (CORS_ALLOWED_HEADERS=*).
+    pub headers: Option<Vec<HeaderName>>,
+    /// Response headers scripts may read besides the safelisted ones.
+    pub expose_headers: Vec<HeaderName>,
+    pub credentials: bool,
+    /// How long browsers may cache a preflight result.
+    pub max_age: Duration,
+}
+
+/// Comma-separated CORS_ALLOWED_ORIGINS entries.
+pub fn parse_origins(raw: &str) -> Result<Vec<AllowedOrigin>, String> {
+    list(raw).map(str::parse).collect()
+}
+
+/// Comma-separated method names, in any case.
+pub fn parse_methods(raw: &str) -> Result<Vec<Method>, String> {
+    list(raw)
+        .map(|method| {
+            Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                .map_err(|_| format!("invalid method {method:?}"))
+        })
+        .collect()
+}
+
+/// Comma-separated header names; `*` alone (where allowed) is `None`.
+pub fn parse_headers(raw: &str) -> Result<Option<Vec<HeaderName>>, String> {
+    if raw.trim() == "*" {
+        return Ok(None);
+    }
+    list(raw)
+        .map(|name| {
+            HeaderName::from_bytes(name.as_bytes()).map_err(|_| format!("invalid header {name:?}"))
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+fn list(raw: &str) -> impl Iterator<Item = &str> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+}
+
+// --------------------------------------------------
+// Middleware
+// --------------------------------------------------
+
+/// Cloneable handle to the CORS settings.
+#[derive(Clone)]
+pub struct Cors {
+    settings: Arc<CorsSettings>,
+}
+
+impl Cors {
+    pub fn new(settings: CorsSettings) -> Self {
+        Self {
+            settings: Arc::new(settings),
+        }
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        self.settings
+            .origins
+            .iter()
+            .any(|allowed| allowed.matches(origin))
+    }
+
+    /// `*` is answered as is; anything else echoes the request's
+    /// origin, so the response depends on it.
+    fn is_wildcard(&self) -> bool {
+        self.settings
+            .origins
+            .iter()
+            .any(|allowed| matches!(allowed, AllowedOrigin::Any))
+    }
+
+    fn allow_origin(&self, headers: &mut HeaderMap, origin: &HeaderValue) {
+        let value = if self.is_wildcard() {
+            HeaderValue::from_static("*")
+        } else {
+            origin.clone()
+        };
+        headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, value);
+        if self.settings.credentials {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+    }
+
+    fn preflight(&self, origin: &HeaderValue, request: &HeaderMap) -> Response {
+        let settings = &self.settings;
+        let origin_str = origin.to_str().unwrap_or_default();
+        let method = request
+            .get(header::ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        let requested: Vec<String> = request
+            .get_all(header::ACCESS_CONTROL_REQUEST_HEADERS)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(list)
+            .map(str::to_ascii_lowercase)
+            .collect();
+
+        let rejection = if !self.allows(origin_str) {
+            Some(format!("Origin {origin_str} is not allowed"))
+        } else if !is_safelisted(method) && !settings.methods.iter().any(|m| m == method) {
+            Some(format!("Method {method} is not allowed"))
+        } else {
+            settings.headers.as_ref().and_then(|allowed| {
+                requested
+                    .iter()
+                    .find(|name| !allowed.iter().any(|allowed| allowed == name.as_str()))
+                    .map(|name| format!("Header {name} is not allowed"))
+            })
+        };
+
+        let mut response = match rejection {
+            Some(reason) => {
+                debug!("Rejected CORS preflight: {}", reason);
+                ApiError::Forbidden(reason).into_response()
+            }
+            None => {
+                let mut response = StatusCode::NO_CONTENT.into_response();
+                let headers = response.headers_mut();
+                self.allow_origin(headers, origin);
+                headers.insert(
+                    header::ACCESS_CONTROL_ALLOW_METHODS,
+                    join(settings.methods.iter().map(Method::as_str)),
+                );
+                let allowed_headers = match &settings.headers {
+                    Some(allowed) => join(allowed.iter().map(HeaderName::as_str)),
+                    None => join(requested.iter().map(String::as_str)),
+                };
+                if !allowed_headers.is_empty() {
+                    headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, allowed_headers);
+                }
+                headers.insert(
+                    header::ACCESS_CONTROL_MAX_AGE,
+                    HeaderValue::from(settings.max_age.as_secs()),
+                );
+                response
+            }
+        };
+        response.headers_mut().append(
+            header::VARY,
+            HeaderValue::from_static(
+                "Origin, Access-Control-Request-Method, Access-Control-Request-Headers",
+            ),
+        );
+        response
+    }
+}
+
+/// Answer preflights; add CORS headers to responses for allowed
+/// origins.
+pub async fn handle(State(cors): State<Cors>, request: Request, next: Next) -> Response {
+    let Some(origin) = request.headers().get(header::ORIGIN).cloned() else {
+        let mut response = next.run(request).await;
+        if !cors.is_wildcard() {
+            response
+                .headers_mut()
+                .append(header::VARY, HeaderValue::from_static("Origin"));
+        }
+        return response;
+    };
+
+    if request.method() == Method::OPTIONS
+        && request
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+    {
+        return cors.preflight(&origin, request.headers());
+    }
+
+    let allowed = origin.to_str().is_ok_and(|origin| cors.allows(origin));
+    let mut response = next.run(request).await;
+    let headers = response.headers_mut();
+    if allowed {
+        cors.allow_origin(headers, &origin);
+        if !cors.settings.expose_headers.is_empty() {
+            headers.insert(
+                header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                join(cors.settings.expose_headers.iter().map(HeaderName::as_str)),
+            );
+        }
+    }
+    if !cors.is_wildcard() {
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    }
+    response
+}
+
+/// Methods a browser may use without listing them in the response.
+fn is_safelisted(method: &str) -> bool {
+    matches!(method, "GET" | "HEAD" | "POST")
+}
+
+fn join<'a>(items: impl Iterator<Item = &'a str>) -> HeaderValue {
+    HeaderValue::from_str(&items.collect::<Vec<_>>().join(", "))
+        .expect("method and header names are valid header values")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    fn allows(entry: &str, origin: &str) -> bool {
+        entry.parse::<AllowedOrigin>().unwrap().matches(origin)
+    }
+
+    fn cors(origins: &str, credentials: bool) -> Cors {
+        Cors::new(CorsSettings {
+            origins: parse_origins(origins).unwrap(),
+            methods: parse_methods("GET,POST,DELETE").unwrap(),
+            headers: parse_headers("authorization,content-type").unwrap(),
+            expose_headers: parse_headers("x-request-id").unwrap().unwrap(),
+            credentials,
+            max_age: Duration::from_secs(600),
+        })
+    }
+
+    async fn call(cors: Cors, request: axum::http::request::Builder) -> Response {
+        let app = Router::new()
+            .route("/api", get(|| async {}).delete(|| async {}))
+            .layer(middleware::from_fn_with_state(cors, handle));
+        app.oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    fn preflight(origin: &str, method: &str, headers: &str) -> axum::http::request::Builder {
+        Request::options("/api")
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, method)
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, headers)
+    }
+
+    fn vary(response: &Response) -> Vec<&str> {
+        response
+            .headers()
+            .get_all(header::VARY)
+            .iter()
+            .map(|v| v.to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn exact_origins_match_in_any_case() {
+        assert!(allows("https://app.example.com", "https://app.example.com"));
+        assert!(allows("HTTPS://App.Example.com", "https://APP.example.com"));
+        assert!(!allows("https://app.example.com", "http://app.example.com"));
+        assert!(!allows(
+            "https://app.example.com",
+            "https://app.example.com:8443"
+        ));
+        assert!(!allows(
+            "https://app.example.com",
+            "https://app.example.com.evil.net"
+        ));
+
+        for invalid in [
+            "app.example.com",
+            "https://app.example.com/path",
+            "https://",
+        ] {
+            assert!(invalid.parse::<AllowedOrigin>().is_err(), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn subdomain_wildcards_match_any_depth_but_not_the_domain() {
+        let entry = "https://*.example.com";
+        assert!(allows(entry, "https://app.example.com"));
+        assert!(allows(entry, "https://eu.app.example.com"));
+        assert!(!allows(entry, "https://example.com"));
+        assert!(!allows(entry, "https://.example.com"));
+        assert!(!allows(entry, "https://evil.net/.example.com"));
+        assert!(!allows(entry, "http://app.example.com"));
+        assert!(!allows(entry, "https://app.example.com:8443"));
+        assert!(allows(
+            "https://*.example.com:8443",
+            "https://app.example.com:8443"
+        ));
+
+        assert!("https://app.*.com".parse::<AllowedOrigin>().is_err());
+    }
+
+    #[test]
+    fn regex_origins_match_in_full() {
+        let entry = r"regex:https://(app|admin)\.example\.(com|net)";
+        assert!(allows(entry, "https://app.example.com"));
+        assert!(allows(entry, "https://admin.example.net"));
+        assert!(!allows(entry, "https://api.example.com"));
+        assert!(!allows(entry, "https://app.example.com.evil.net"));
+        assert!(!allows(entry, "evil://https://app.example.com"));
+        assert!(allows(
+            r"regex:^https://[a-z]+\.example\.com$",
+            "https://app.example.com"
+        ));
+
+        let origin: AllowedOrigin = entry.parse().unwrap();
+        assert_eq!(origin.to_string(), entry);
+        let err = "regex:https://(app".parse::<AllowedOrigin>().unwrap_err();
+        assert_eq!(err, r#"invalid pattern "https://(app": unclosed group"#);
+    }
+
+    #[tokio::test]
+    async fn allowed_preflights_get_a_204() {
+        let cors = cors("https://app.example.com", true);
+        let response = call(
+            cors,
+            preflight("https://app.example.com", "DELETE", "Authorization"),
+        )
+        .await;
+
+        assert_eq!(response.status(), StatusCode::NO_CONTENT);
+        let headers = response.headers();
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_METHODS],
+            "GET, POST, DELETE"
+        );
+        assert_eq!(
+            headers[header::ACCESS_CONTROL_ALLOW_HEADERS],
+            "authorization, content-type"
+        );
+        assert_eq!(headers[header::ACCESS_CONTROL_MAX_AGE], "600");
+    }
+
+    #[tokio::test]
+    async fn refused_preflights_get_a_403() {
+        let cases = [
+            (
+                preflight("https://evil.net", "GET", ""),
+                "Origin https://evil.net is not allowed",
+            ),
+            (
+                preflight("https://app.example.com", "PUT", ""),
+                "Method PUT is not allowed",
+            ),
+            (
+                preflight("https://app.example.com", "GET", "authorization, x-secret"),
+                "Header x-secret is not allowed",
+            ),
+        ];
+        for (request, reason) in cases {
+            let response = call(cors("https://app.example.com", false), request).await;
+            assert_eq!(response.status(), StatusCode::FORBIDDEN);
+            assert!(!response
+                .headers()
+                .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+            let error = response.extensions().get::<ApiError>().unwrap();
+            assert_eq!(error.details(), Some(reason));
+        }
+    }
+
+    #[tokio::test]
+    async fn every_response_varies_on_the_origin() {
+        let restricted = || cors("https://app.example.com", false);
+
+        let allowed = call(
+            restricted(),
+            Request::get("/api").header(header::ORIGIN, "https://app.example.com"),
+        )
+        .await;
+        assert_eq!(
+            allowed.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://app.example.com"
+        );
+        assert_eq!(
+            allowed.headers()[header::ACCESS_CONTROL_EXPOSE_HEADERS],
+            "x-request-id"
+        );
+        assert_eq!(vary(&allowed), ["Origin"]);
+
+        let refused = call(
+            restricted(),
+            Request::get("/api").header(header::ORIGIN, "https://evil.net"),
+        )
+        .await;
+        assert!(!refused
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+        assert_eq!(vary(&refused), ["Origin"]);
+
+        let same_origin = call(restricted(), Request::get("/api")).await;
+        assert_eq!(vary(&same_origin), ["Origin"]);
+
+        let error = call(
+            restricted(),
+            Request::get("/missing").header(header::ORIGIN, "https://app.example.com"),
+        )
+        .await;
+        assert_eq!(error.status(), StatusCode::NOT_FOUND);
+        assert_eq!(vary(&error), ["Origin"]);
+
+        let preflight = call(restricted(), preflight("https://evil.net", "GET", "")).await;
+        assert_eq!(
+            vary(&preflight),
+            ["Origin, Access-Control-Request-Method, Access-Control-Request-Headers"]
+        );
+
+        // `*` answers every origin alike.
+        let any = call(
+            cors("*", false),
+            Request::get("/api").header(header::ORIGIN, "https://app.example.com"),
+        )
+        .await;
+        assert_eq!(any.headers()[header::ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(vary(&any).is_empty());
+    }
+}
@@ -0,0 +1,121 @@
+// ==================================================
+// Hashing, signatures and randomness primitives
+// ==================================================
+// - SHA-256, used for migration checksums and API key hashes
+// - HMAC-SHA256, used for HS256 tokens
+// - RSA PKCS#1 v1.5 and ECDSA P-256 signature verification (ring),
+//   used for RS256 / ES256 tokens
+// - OS randomness, used for identifiers
+
+use hmac::{Hmac, Mac};
+use ring::{
+    rand::{SecureRandom, SystemRandom},
+    signature::{self, RsaPublicKeyComponents, UnparsedPublicKey},
+};
+use sha2::{Digest, Sha256};
+
+/// SHA-256 digest of `data`.
+pub fn sha256(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(data).into()
+}
+
+/// Check an HMAC-SHA256 `tag` over `message`, in constant time.
+pub fn verify_hmac_sha256(key: &[u8], message: &[u8], tag: &[u8]) -> bool {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(message);
+    mac.verify_slice(tag).is_ok()
+}
+
+/// Compare secrets without leaking where they differ through timing.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Lowercase hex encoding.
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Fill `buf` from the operating system's CSPRNG.
+pub fn fill_random(buf: &mut [u8]) {
+    SystemRandom::new()
+        .fill(buf)
+        .expect("the OS random number generator failed");
+}
+
+// --------------------------------------------------
+// Public-key signatures
+// --------------------------------------------------
+
+/// A public key for SHA-256 signatures.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PublicKey {
+    /// RSA modulus and exponent, unsigned big-endian.
+    Rsa { n: Vec<u8>, e: Vec<u8> },
+    /// Uncompressed P-256 point (`04 || x || y`).
+    P256(Vec<u8>),
+}
+
+impl PublicKey {
+    /// A P-256 key from its affine coordinates.
+    pub fn p256(x: &[u8], y: &[u8]) -> Option<Self> {
+        (x.len() == 32 && y.len() == 32).then(|| Self::P256([&[0x04][..], x, y].concat()))
+    }
+
+    /// Verify a signature over `message`: RSA PKCS#1 v1.5 (keys of
+    /// 2048 to 8192 bits), or ECDSA as `r || s`, the JWS encoding.
+    pub fn verify(&self, message: &[u8], signature: &[u8]) -> bool {
+        match self {
+            Self::Rsa { n, e } => RsaPublicKeyComponents { n, e }
+                .verify(&signature::RSA_PKCS1_2048_8192_SHA256, message, signature)
+                .is_ok(),
+            Self::P256(point) => UnparsedPublicKey::new(&signature::ECDSA_P256_SHA256_FIXED, point)
+                .verify(message, signature)
+                .is_ok(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha256_and_hmac_match_published_vectors() {
+        // FIPS 180-4 example "abc".
+        assert_eq!(
+            to_hex(&sha256(b"abc")),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        // RFC 4231 test case 2.
+        let tag = hex("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+        assert!(verify_hmac_sha256(
+            b"Jefe",
+            b"what do ya want for nothing?",
+            &tag
+        ));
+        assert!(!verify_hmac_sha256(
+            b"Jefe",
+            b"what do ya want for nothing!",
+            &tag
+        ));
+        assert!(!verify_hmac_sha256(
+            b"Jefe",
+            b"what do ya want for nothing?",
+            &tag[..16]
+        ));
+    }
+
+    #[test]
+    fn p256_keys_need_both_coordinates() {
+        assert!(PublicKey::p256(&[1; 32], &[2; 32]).is_some());
+        assert!(PublicKey::p256(&[1; 32], &[2; 31]).is_none());
+    }
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+}
@@ -0,0 +1,398 @@
+// ==================================================
+// Request deadlines
+// ==================================================
+// Every application request gets a time budget; one that is still
+// running when it runs out is dropped and answered with
+// `504 gateway_timeout`, so a slow handler cannot hold a connection
+// (and its database connection) forever.
+//
+// - REQUEST_TIMEOUT_MS is the default budget
+// - Routes that need a different one say so on the router:
+//
+//     .route(
+//         "/api/reports",
+//         get(build_report).route_layer(middleware::from_fn_with_state(
+//             RequestTimeout(Duration::from_secs(120)),
+//             deadline::route_timeout,
+//         )),
+//     )
+//
+// - Clients can shrink the budget, never extend it, with
+//   `grpc-timeout` (`250m`, `5S`: relative, gRPC units) or
+//   `X-Request-Deadline` (absolute, Unix time in milliseconds)
+//
+// Handlers read what is left with the `Deadline` extractor, and bound
+// database work by it with `Database::until`, which cancels a query
+// that is still running when the time is up.
+
+use crate::{clock, db::Database, error::ApiError};
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request, State},
+    http::{request::Parts, HeaderMap},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{
+    pin::pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::Notify;
+use tracing::{debug, warn};
+
+// --------------------------------------------------
+// Deadline
+// --------------------------------------------------
+
+/// When the current request must be done.
+///
+/// Rejects with 500 outside the application routes, which are the
+/// only ones with a deadline.
+#[derive(Clone, Debug)]
+pub struct Deadline(Arc<Budget>);
+
+#[derive(Debug)]
+struct Budget {
+    started: Instant,
+    /// From the client's `grpc-timeout` / `X-Request-Deadline`.
+    client: Option<Instant>,
+    at: Mutex<Instant>,
+    /// Wakes `enforce` when a route changes the deadline.
+    changed: Notify,
+}
+
+impl Deadline {
+    fn new(started: Instant, timeout: Duration, client: Option<Instant>) -> Self {
+        Self(Arc::new(Budget {
+            started,
+            client,
+            at: Mutex::new(Self::bound(started + timeout, client)),
+            changed: Notify::new(),
+        }))
+    }
+
+    pub fn at(&self) -> Instant {
+        *self.0.at.lock().expect("deadline lock poisoned")
+    }
+
+    /// Time left; zero once the deadline has passed.
+    pub fn remaining(&self) -> Duration {
+        self.at().saturating_duration_since(Instant::now())
+    }
+
+    /// Replace the server-side budget, still within the client's.
+    fn set_timeout(&self, timeout: Duration) {
+        *self.0.at.lock().expect("deadline lock poisoned") =
+            Self::bound(self.0.started + timeout, self.0.client);
+        self.0.changed.notify_one();
+    }
+
+    fn bound(server: Instant, client: Option<Instant>) -> Instant {
+        client.map_or(server, |client| client.min(server))
+    }
+
+    /// Whether the client, rather than the server, set the deadline.
+    fn is_client(&self) -> bool {
+        self.0.client == Some(self.at())
+    }
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Deadline
+where
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<Deadline>()
+            .cloned()
+            .ok_or_else(|| ApiError::Internal("Deadline used on a route without one".to_string()))
+    }
+}
+
+/// `db`, bounded by `deadline` when there is one.
+pub fn bounded(db: &Database, deadline: Option<&Deadline>) -> Database {
+    match deadline {
+        Some(deadline) => db.until(deadline.at()),
+        None => db.clone(),
+    }
+}
+
+/// The client's deadline from `grpc-timeout` or `X-Request-Deadline`,
+/// whichever is sooner. Malformed values are ignored.
+fn client_deadline(headers: &HeaderMap, now: Instant) -> Option<Instant> {
+    let header = |name| headers.get(name).and_then(|value| value.to_str().ok());
+
+    let grpc = header("grpc-timeout")
+        .and_then(parse_grpc_timeout)
+        .and_then(|timeout| now.checked_add(timeout));
+
+    // Absolute time, mapped onto the monotonic clock.
+    let absolute = header("x-request-deadline")
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(|unix_ms| {
+            let ahead = unix_ms.saturating_sub(clock::unix_millis().max(0) as u64);
+            now.checked_add(Duration::from_millis(ahead)).unwrap_or(now)
+        });
+
+    match (grpc, absolute) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// `<up to 8 digits><unit>`, the unit being H, M, S, m (ms), u (µs)
+/// or n (ns), as in the gRPC HTTP/2 protocol.
+fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    let unit = value.chars().last()?;
+    let digits = &value[..value.len() - unit.len_utf8()];
+    if digits.is_empty() || digits.len() > 8 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let count: u64 = digits.parse().ok()?;
+    Some(match unit {
+        'H' => Duration::from_secs(count * 3600),
+        'M' => Duration::from_secs(count * 60),
+        'S' => Duration::from_secs(count),
+        'm' => Duration::from_millis(count),
+        'u' => Duration::from_micros(count),
+        'n' => Duration::from_nanos(count),
+        _ => return None,
+    })
+}
+
+// --------------------------------------------------
+// Middleware
+// --------------------------------------------------
+
+/// A request time budget: REQUEST_TIMEOUT_MS for `enforce`, a
+/// route's own for `route_timeout`.
+#[derive(Clone, Copy, Debug)]
+pub struct RequestTimeout(pub Duration);
+
+/// Give the request its deadline, and answer 504 if it is still
+/// running then.
+pub async fn enforce(
+    State(RequestTimeout(timeout)): State<RequestTimeout>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let started = Instant::now();
+    let deadline = Deadline::new(
+        started,
+        timeout,
+        client_deadline(request.headers(), started),
+    );
+    if deadline.remaining().is_zero() {
+        debug!("Client deadline already passed");
+        return timed_out();
+    }
+    request.extensions_mut().insert(deadline.clone());
+
+    let mut response = pin!(next.run(request));
+    loop {
+        let at = deadline.at();
+        tokio::select! {
+            response = &mut response => return response,
+            () = tokio::time::sleep_until(at.into()) => {
+                if deadline.at() > Instant::now() {
+                    continue;
+                }
+                if deadline.is_client() {
+                    debug!("Client deadline passed after {:?}", started.elapsed());
+                } else {
+                    warn!("Request timed out after {:?}", started.elapsed());
+                }
+                return timed_out();
+            }
+            () = deadline.0.changed.notified() => {}
+        }
+    }
+}
+
+/// Replace the default budget for one route (see the module docs).
+pub async fn route_timeout(
+    State(RequestTimeout(timeout)): State<RequestTimeout>,
+    request: Request,
+    next: Next,
+) -> Response {
+    if let Some(deadline) = request.extensions().get::<Deadline>() {
+        deadline.set_timeout(timeout);
+    }
+    next.run(request).await
+}
+
+fn timed_out() -> Response {
+    ApiError::GatewayTimeout("Request deadline exceeded".to_string()).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::{self, ErrorFormat};
+    use axum::{
+        body::{to_bytes, Body},
+        http::StatusCode,
+        middleware,
+        routing::get,
+        Router,
+    };
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    const SLOW: Duration = Duration::from_secs(5);
+
+    /// `/remaining` answers with the milliseconds left; `/slow` and
+    /// `/fast` take `SLOW`, `/fast` with a route budget of 50ms.
+    fn app(timeout: Duration) -> Router {
+        Router::new()
+            .route(
+                "/remaining",
+                get(|deadline: Deadline| async move { deadline.remaining().as_millis().to_string() }),
+            )
+            .route("/slow", get(|| tokio::time::sleep(SLOW)))
+            .route(
+                "/fast",
+                get(|| tokio::time::sleep(SLOW)).route_layer(middleware::from_fn_with_state(
+                    RequestTimeout(Duration::from_millis(50)),
+                    route_timeout,
+                )),
+            )
+            .layer(middleware::from_fn_with_state(
+                RequestTimeout(timeout),
+                enforce,
+            ))
+            .layer(middleware::from_fn_with_state(
+                ErrorFormat::Envelope,
+                error::render_errors,
+            ))
+    }
+
+    async fn call(app: Router, path: &str, headers: &[(&str, String)]) -> (StatusCode, String) {
+        let mut request = Request::get(path);
+        for (name, value) in headers {
+            request = request.header(*name, value);
+        }
+        let response = app
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    fn unix_ms_in(offset: Duration) -> String {
+        (clock::unix_millis() + offset.as_millis() as i64).to_string()
+    }
+
+    #[test]
+    fn grpc_timeouts_are_parsed() {
+        assert_eq!(parse_grpc_timeout("2H"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_grpc_timeout("3M"), Some(Duration::from_secs(180)));
+        assert_eq!(parse_grpc_timeout("5S"), Some(Duration::from_secs(5)));
+        assert_eq!(
+            parse_grpc_timeout(" 250m "),
+            Some(Duration::from_millis(250))
+        );
+        assert_eq!(parse_grpc_timeout("10u"), Some(Duration::from_micros(10)));
+        assert_eq!(
+            parse_grpc_timeout("99999999n"),
+            Some(Duration::from_nanos(99_999_999))
+        );
+
+        for invalid in ["", "m", "5", "5s", "5 m", "-5m", "1.5S", "123456789m"] {
+            assert_eq!(parse_grpc_timeout(invalid), None, "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn client_deadlines_take_the_sooner_header() {
+        let now = Instant::now();
+        let headers = |pairs: &[(&'static str, String)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in pairs {
+                headers.insert(*name, value.parse().unwrap());
+            }
+            headers
+        };
+
+        let grpc = headers(&[("grpc-timeout", "250m".to_string())]);
+        assert_eq!(
+            client_deadline(&grpc, now),
+            Some(now + Duration::from_millis(250))
+        );
+
+        let both = headers(&[
+            ("grpc-timeout", "10S".to_string()),
+            ("x-request-deadline", unix_ms_in(Duration::from_secs(2))),
+        ]);
+        let at = client_deadline(&both, now).unwrap();
+        assert!(at > now + Duration::from_secs(1) && at <= now + Duration::from_secs(2));
+
+        let past = headers(&[("x-request-deadline", "1000".to_string())]);
+        assert_eq!(client_deadline(&past, now), Some(now));
+
+        let malformed = headers(&[
+            ("grpc-timeout", "soon".to_string()),
+            ("x-request-deadline", "tomorrow".to_string()),
+        ]);
+        assert_eq!(client_deadline(&malformed, now), None);
+    }
+
+    #[tokio::test]
+    async fn late_requests_get_a_504_envelope() {
+        let (status, body) = call(app(Duration::from_millis(50)), "/slow", &[]).await;
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+        let body: Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(
+            body,
+            json!({
+                "status": "error",
+                "message": "Gateway Timeout",
+                "error": { "code": "gateway_timeout", "details": "Request deadline exceeded" },
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn client_deadlines_shrink_the_budget() {
+        let app = app(Duration::from_secs(30));
+
+        let (status, _) = call(app.clone(), "/remaining", &[]).await;
+        assert_eq!(status, StatusCode::OK);
+
+        let deadline = ("x-request-deadline", unix_ms_in(Duration::from_millis(500)));
+        let (status, remaining) = call(app.clone(), "/remaining", &[deadline]).await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(remaining.parse::<u64>().unwrap() <= 500, "{remaining}");
+
+        let started = Instant::now();
+        let timeout = ("grpc-timeout", "50m".to_string());
+        let (status, _) = call(app.clone(), "/slow", &[timeout]).await;
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+        assert!(started.elapsed() < SLOW);
+
+        // Never extended: a later client deadline leaves 30s.
+        let later = ("grpc-timeout", "1H".to_string());
+        let (_, remaining) = call(app.clone(), "/remaining", &[later]).await;
+        assert!(remaining.parse::<u64>().unwrap() <= 30_000, "{remaining}");
+
+        let passed = ("x-request-deadline", "1000".to_string());
+        let (status, _) = call(app, "/remaining", &[passed]).await;
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+    }
+
+    #[tokio::test]
+    async fn routes_can_replace_the_budget() {
+        let started = Instant::now();
+        let (status, _) = call(app(Duration::from_secs(30)), "/fast", &[]).await;
+        assert_eq!(status, StatusCode::GATEWAY_TIMEOUT);
+        assert!(started.elapsed() < SLOW);
+    }
+}
@@ -0,0 +1,445 @@
+// ==================================================
+// API errors
+// ==================================================
+// Every failure leaves the API in the same envelope as success
+// responses, with a machine-readable code:
+//
+// {
+//   "status": "error",
+//   "message": "Not Found",
+//   "error": { "code": "not_found", "details": "No route for GET /nope" }
+// }
+//
+// Handlers return `Result<_, ApiError>`; extractor rejections and
+// the router's 404/405 fallbacks are converted into `ApiError` too,
+// so clients never see axum's default plain-text bodies.
+//
+// Clients that speak RFC 7807 get `application/problem+json`
+// instead, either by sending `Accept: application/problem+json`
+// or for everyone when ERROR_FORMAT=problem:
+//
+// {
+//   "type": "about:blank",
+//   "title": "Not Found",
+//   "status": 404,
+//   "detail": "No route for GET /nope",
+//   "instance": "/nope",
+//   "code": "not_found",
+//   "request_id": "..."
+// }
+
+use crate::{db::DbError, request_id::RequestId};
+use axum::{
+    body::Body,
+    extract::{
+        rejection::{JsonRejection, QueryRejection},
+        Request, State,
+    },
+    http::{header, HeaderValue, Method, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use std::str::FromStr;
+use tracing::error;
+
+#[derive(Clone, Debug)]
+pub enum ApiError {
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    MethodNotAllowed(String),
+    PayloadTooLarge(String),
+    UnsupportedMediaType(String),
+    Unprocessable(String),
+    TooManyRequests(String),
+    Unavailable(String),
+    GatewayTimeout(String),
+    /// Details are logged, never sent to the client.
+    Internal(String),
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::BadRequest(_) => StatusCode::BAD_REQUEST,
+            Self::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            Self::Forbidden(_) => StatusCode::FORBIDDEN,
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::MethodNotAllowed(_) => StatusCode::METHOD_NOT_ALLOWED,
+            Self::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::Unprocessable(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::TooManyRequests(_) => StatusCode::TOO_MANY_REQUESTS,
+            Self::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            Self::GatewayTimeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::BadRequest(_) => "bad_request",
+            Self::Unauthorized(_) => "unauthorized",
+            Self::Forbidden(_) => "forbidden",
+            Self::NotFound(_) => "not_found",
+            Self::MethodNotAllowed(_) => "method_not_allowed",
+            Self::PayloadTooLarge(_) => "payload_too_large",
+            Self::UnsupportedMediaType(_) => "unsupported_media_type",
+            Self::Unprocessable(_) => "unprocessable_entity",
+            Self::TooManyRequests(_) => "too_many_requests",
+            Self::Unavailable(_) => "service_unavailable",
+            Self::GatewayTimeout(_) => "gateway_timeout",
+            Self::Internal(_) => "internal_error",
+        }
+    }
+
+    /// Client-facing details (none for internal errors).
+    pub fn details(&self) -> Option<&str> {
+        match self {
+            Self::Internal(_) => None,
+            Self::BadRequest(d)
+            | Self::Unauthorized(d)
+            | Self::Forbidden(d)
+            | Self::NotFound(d)
+            | Self::MethodNotAllowed(d)
+            | Self::PayloadTooLarge(d)
+            | Self::UnsupportedMediaType(d)
+            | Self::Unprocessable(d)
+            | Self::TooManyRequests(d)
+            | Self::Unavailable(d)
+            | Self::GatewayTimeout(d) => Some(d),
+        }
+    }
+
+    /// Map an axum rejection (status + text) onto the matching variant.
+    fn from_rejection(status: StatusCode, details: String) -> Self {
+        match status {
+            StatusCode::BAD_REQUEST => Self::BadRequest(details),
+            StatusCode::PAYLOAD_TOO_LARGE => Self::PayloadTooLarge(details),
+            StatusCode::UNSUPPORTED_MEDIA_TYPE => Self::UnsupportedMediaType(details),
+            StatusCode::UNPROCESSABLE_ENTITY => Self::Unprocessable(details),
+            _ => Self::Internal(details),
+        }
+    }
+}
+
+// --------------------------------------------------
+// Response envelope
+// --------------------------------------------------
+
+#[derive(Serialize)]
+struct ErrorResponse<'a> {
+    status: &'static str,
+    message: &'static str,
+    error: ErrorBody<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: &'static str,
+    details: Option<&'a str>,
+}
+
+impl ApiError {
+    fn envelope<'a>(&'a self, request_id: Option<&'a str>) -> ErrorResponse<'a> {
+        ErrorResponse {
+            status: "error",
+            message: self.status().canonical_reason().unwrap_or("Error"),
+            error: ErrorBody {
+                code: self.code(),
+                details: self.details(),
+            },
+            request_id,
+        }
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        if let Self::Internal(details) = &self {
+            error!("Internal error: {}", details);
+        }
+
+        // Kept on the response so `render_errors` can re-render it
+        // once the request (request ID, Accept header, path) is known.
+        let mut response = (self.status(), Json(self.envelope(None))).into_response();
+        response.extensions_mut().insert(self);
+        response
+    }
+}
+
+// --------------------------------------------------
+// Problem Details (RFC 7807)
+// --------------------------------------------------
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ErrorFormat {
+    /// The `ApiResponse`-style envelope, unless the client asks for
+    /// `application/problem+json`.
+    #[default]
+    Envelope,
+    /// Always `application/problem+json`.
+    Problem,
+}
+
+impl FromStr for ErrorFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "envelope" => Ok(Self::Envelope),
+            "problem" => Ok(Self::Problem),
+            _ => Err(()),
+        }
+    }
+}
+
+const PROBLEM_JSON: &str = "application/problem+json";
+
+#[derive(Serialize)]
+struct Problem<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    title: &'static str,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<&'a str>,
+    instance: &'a str,
+    code: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<&'a str>,
+}
+
+// --------------------------------------------------
+// Rendering middleware
+// --------------------------------------------------
+
+/// Re-render `ApiError` responses with the request ID, as Problem
+/// Details when configured or negotiated. Other responses pass through.
+pub async fn render_errors(
+    State(format): State<ErrorFormat>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let wants_problem = format == ErrorFormat::Problem
+        || request
+            .headers()
+            .get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .any(|v| v.contains(PROBLEM_JSON));
+
+    let instance = request.uri().path().to_string();
+    let request_id = request.extensions().get::<RequestId>().cloned();
+    let request_id = request_id.as_ref().map(|id| id.0.as_str());
+
+    let response = next.run(request).await;
+    let Some(err) = response.extensions().get::<ApiError>().cloned() else {
+        return response;
+    };
+
+    let (content_type, body) = if wants_problem {
+        let status = err.status();
+        let problem = Problem {
+            kind: "about:blank",
+            title: status.canonical_reason().unwrap_or("Error"),
+            status: status.as_u16(),
+            detail: err.details(),
+            instance: &instance,
+            code: err.code(),
+            request_id,
+        };
+        (PROBLEM_JSON, serde_json::to_vec(&problem))
+    } else {
+        (
+            "application/json",
+            serde_json::to_vec(&err.envelope(request_id)),
+        )
+    };
+
+    let (mut parts, _) = response.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts
+        .headers
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    let body = body.expect("error bodies serialize");
+    Response::from_parts(parts, Body::from(body))
+}
+
+// --------------------------------------------------
+// Conversions
+// --------------------------------------------------
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        Self::from_rejection(rejection.status(), rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for ApiError {
+    fn from(rejection: QueryRejection) -> Self {
+        Self::from_rejection(rejection.status(), rejection.body_text())
+    }
+}
+
+impl From<DbError> for ApiError {
+    fn from(err: DbError) -> Self {
+        match err {
+            DbError::Timeout => Self::GatewayTimeout("Request deadline exceeded".to_string()),
+            err => Self::Internal(format!("database error: {err}")),
+        }
+    }
+}
+
+// --------------------------------------------------
+// Router fallbacks
+// --------------------------------------------------
+
+pub async fn not_found_fallback(method: Method, uri: Uri) -> ApiError {
+    ApiError::NotFound(format!("No route for {} {}", method, uri.path()))
+}
+
+pub async fn method_not_allowed_fallback(method: Method, uri: Uri) -> ApiError {
+    ApiError::MethodNotAllowed(format!("{} is not allowed on {}", method, uri.path()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        extract::{Json, Query},
+        request_id::{self, X_REQUEST_ID},
+    };
+    use axum::{body::to_bytes, middleware, routing::get, Router};
+    use serde::Deserialize;
+    use serde_json::{json, Value};
+    use tower::ServiceExt;
+
+    #[derive(Deserialize)]
+    struct Page {
+        #[allow(dead_code)]
+        page: u32,
+    }
+
+    /// Error-producing routes behind the same rendering stack as the
+    /// real routers.
+    fn app(format: ErrorFormat) -> Router {
+        Router::new()
+            .route(
+                "/internal",
+                get(|| async { ApiError::Internal("db password is hunter2".to_string()) }),
+            )
+            .route("/query", get(|Query(_): Query<Page>| async {}))
+            .route(
+                "/json",
+                axum::routing::post(|Json(_): Json<Value>| async {}),
+            )
+            .fallback(not_found_fallback)
+            .method_not_allowed_fallback(method_not_allowed_fallback)
+            .layer(middleware::from_fn_with_state(format, render_errors))
+            .layer(middleware::from_fn(request_id::propagate_request_id))
+    }
+
+    async fn call(app: Router, request: Request) -> (StatusCode, String, Value) {
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let content_type = response.headers()[header::CONTENT_TYPE]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, content_type, serde_json::from_slice(&body).unwrap())
+    }
+
+    fn get_request(uri: &str) -> Request {
+        Request::get(uri)
+            .header(&X_REQUEST_ID, "req-1")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn errors_use_the_envelope() {
+        let (status, content_type, body) =
+            call(app(ErrorFormat::Envelope), get_request("/nope")).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(content_type, "application/json");
+        assert_eq!(
+            body,
+            json!({
+                "status": "error",
+                "message": "Not Found",
+                "error": { "code": "not_found", "details": "No route for GET /nope" },
+                "request_id": "req-1",
+            })
+        );
+
+        let request = Request::delete("/internal").body(Body::empty()).unwrap();
+        let (status, _, body) = call(app(ErrorFormat::Envelope), request).await;
+        assert_eq!(status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(body["error"]["code"], "method_not_allowed");
+    }
+
+    #[tokio::test]
+    async fn internal_details_stay_in_the_logs() {
+        let (status, _, body) = call(app(ErrorFormat::Envelope), get_request("/internal")).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            body["error"],
+            json!({ "code": "internal_error", "details": null })
+        );
+    }
+
+    #[tokio::test]
+    async fn extractor_rejections_are_enveloped() {
+        let (status, _, body) =
+            call(app(ErrorFormat::Envelope), get_request("/query?page=x")).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["code"], "bad_request");
+
+        let request = Request::post("/json").body(Body::from("{}")).unwrap();
+        let (status, _, body) = call(app(ErrorFormat::Envelope), request).await;
+        assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert_eq!(body["error"]["code"], "unsupported_media_type");
+
+        let request = Request::post("/json")
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from("{"))
+            .unwrap();
+        let (status, _, body) = call(app(ErrorFormat::Envelope), request).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(body["status"], "error");
+    }
+
+    #[tokio::test]
+    async fn problem_details_are_negotiated_or_configured() {
+        let request = Request::get("/nope")
+            .header(header::ACCEPT, PROBLEM_JSON)
+            .header(&X_REQUEST_ID, "req-1")
+            .body(Body::empty())
+            .unwrap();
+        let expected = json!({
+            "type": "about:blank",
+            "title": "Not Found",
+            "status": 404,
+            "detail": "No route for GET /nope",
+            "instance": "/nope",
+            "code": "not_found",
+            "request_id": "req-1",
+        });
+
+        let (status, content_type, body) = call(app(ErrorFormat::Envelope), request).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(content_type, PROBLEM_JSON);
+        assert_eq!(body, expected);
+
+        let (_, content_type, body) = call(app(ErrorFormat::Problem), get_request("/nope")).await;
+        assert_eq!(content_type, PROBLEM_JSON);
+        assert_eq!(body, expected);
+    }
+}
@@ -0,0 +1,275 @@
+// ==================================================
+// Extractors
+// ==================================================
+// Drop-in replacements for axum's `Json` and `Query` whose
+// rejections are `ApiError`, so malformed input is reported in the
+// standard error envelope. Use these instead of the axum versions.
+//
+// `ClientIdentity` exposes the verified client certificate of a
+// mutual-TLS connection.
+//
+// `client_ip` finds the address of the client behind any trusted
+// reverse proxies (TRUSTED_PROXIES).
+
+use crate::{
+    error::ApiError,
+    listener::{self, IpNet},
+};
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequest, FromRequestParts, Request},
+    http::{request::Parts, Extensions, HeaderMap},
+    response::{IntoResponse, Response},
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::net::{IpAddr, SocketAddr};
+
+/// JSON request body / response body.
+pub struct Json<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequest<S> for Json<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::Json(value) = axum::Json::<T>::from_request(req, state).await?;
+        Ok(Self(value))
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        axum::Json(self.0).into_response()
+    }
+}
+
+/// Query string parameters.
+pub struct Query<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Query<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::extract::Query(value) =
+            axum::extract::Query::<T>::from_request_parts(parts, state).await?;
+        Ok(Self(value))
+    }
+}
+
+/// The verified client certificate of the connection (mutual TLS).
+///
+/// Rejects with 401 when the client presented none; take
+/// `Option<ClientIdentity>` where a certificate is optional.
+#[derive(Clone, Debug)]
+pub struct ClientIdentity {
+    /// Subject distinguished name, RFC 2253 (`CN=billing,O=Example`).
+    pub subject: String,
+    /// Subject alternative names as `DNS:`, `URI:`, `email:` or `IP:`
+    /// entries, e.g. `URI:spiffe://example.org/billing`.
+    pub sans: Vec<String>,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientIdentity
+where
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<ClientIdentity>()
+            .cloned()
+            .ok_or_else(|| ApiError::Unauthorized("A client certificate is required".to_string()))
+    }
+}
+
+/// The client's IP address. Starting from the peer, `X-Forwarded-For`
+/// is walked from the right for as long as the hop that added an
+/// entry is a trusted proxy; entries further left could have been
+/// written by anyone. An entry that is not an address (`unknown`, an
+/// obfuscated identifier) ends the walk at the trusted hop that added
+/// it: the proxy withheld its client, and anything further left is the
+/// client's own say. `ip:port` entries count as their address.
+///
+/// Peers on a Unix socket are local, so always a proxy. `None` when
+/// such a peer sent no `X-Forwarded-For`.
+pub fn client_ip(
+    headers: &HeaderMap,
+    extensions: &Extensions,
+    trusted: &[IpNet],
+) -> Option<IpAddr> {
+    let is_trusted = |ip: IpAddr| trusted.iter().any(|net| net.contains(ip));
+
+    let peer = extensions
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip().to_canonical());
+    if let Some(peer) = peer
+        && !is_trusted(peer)
+    {
+        return Some(peer);
+    }
+
+    let forwarded: Vec<&str> = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect();
+    let mut client = peer;
+    for hop in forwarded.iter().rev() {
+        let Some(ip) = parse_hop(hop) else {
+            break;
+        };
+        client = Some(ip.to_canonical());
+        if !is_trusted(ip) {
+            break;
+        }
+    }
+    client
+}
+
+/// An `X-Forwarded-For` entry: an address, or `address:port` with
+/// IPv6 addresses in brackets.
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim();
+    if let Ok(ip) = listener::parse_ip(hop) {
+        return Some(ip);
+    }
+    let (host, port) = hop.rsplit_once(':')?;
+    port.parse::<u16>().ok()?;
+    match host.strip_prefix('[') {
+        Some(bracketed) => bracketed.strip_suffix(']')?.parse().ok(),
+        // A bare IPv6 address with a port would be ambiguous.
+        None => host.parse::<std::net::Ipv4Addr>().ok().map(IpAddr::V4),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The client IP of a request from `peer` (`None`: a Unix socket)
+    /// carrying `forwarded` as X-Forwarded-For headers.
+    fn client(peer: Option<&str>, forwarded: &[&str], trusted: &[&str]) -> Option<IpAddr> {
+        let mut headers = HeaderMap::new();
+        for value in forwarded {
+            headers.append("x-forwarded-for", value.parse().unwrap());
+        }
+        let mut extensions = Extensions::new();
+        if let Some(peer) = peer {
+            extensions.insert(ConnectInfo::<SocketAddr>(peer.parse().unwrap()));
+        }
+        let trusted: Vec<IpNet> = trusted.iter().map(|n| IpNet::parse(n).unwrap()).collect();
+        client_ip(&headers, &extensions, &trusted)
+    }
+
+    fn ip(s: &str) -> Option<IpAddr> {
+        Some(s.parse().unwrap())
+    }
+
+    const PROXIES: &[&str] = &["10.0.0.0/8", "fd00::/8"];
+
+    #[test]
+    fn untrusted_peers_are_the_client() {
+        let forwarded = &["203.0.113.7"];
+        assert_eq!(
+            client(Some("198.51.100.1:5000"), forwarded, &[]),
+            ip("198.51.100.1")
+        );
+        assert_eq!(
+            client(Some("198.51.100.1:5000"), forwarded, PROXIES),
+            ip("198.51.100.1")
+        );
+        assert_eq!(
+            client(Some("[::ffff:198.51.100.1]:5000"), &[], PROXIES),
+            ip("198.51.100.1")
+        );
+    }
+
+    #[test]
+    fn trusted_chains_are_walked_from_the_right() {
+        let peer = Some("10.0.0.1:5000");
+        assert_eq!(client(peer, &["203.0.113.7"], PROXIES), ip("203.0.113.7"));
+        assert_eq!(
+            client(peer, &["203.0.113.7, 10.0.0.2"], PROXIES),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            client(peer, &["203.0.113.7", "10.0.0.2"], PROXIES),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            client(peer, &["203.0.113.7, fd00::2"], PROXIES),
+            ip("203.0.113.7")
+        );
+        // Only the first untrusted hop is believed.
+        assert_eq!(
+            client(peer, &["6.6.6.6, 203.0.113.7, 10.0.0.2"], PROXIES),
+            ip("203.0.113.7")
+        );
+        // Proxies all the way: the leftmost entry.
+        assert_eq!(
+            client(peer, &["10.0.0.3, 10.0.0.2"], PROXIES),
+            ip("10.0.0.3")
+        );
+        // No header: the proxy itself.
+        assert_eq!(client(peer, &[], PROXIES), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn entries_that_are_not_addresses_end_the_walk() {
+        let peer = Some("10.0.0.1:5000");
+        // The proxy hid its client; what the client wrote is not believed.
+        assert_eq!(client(peer, &["6.6.6.6, unknown"], PROXIES), ip("10.0.0.1"));
+        assert_eq!(
+            client(peer, &["6.6.6.6, _hidden, 10.0.0.2"], PROXIES),
+            ip("10.0.0.2")
+        );
+        assert_eq!(
+            client(peer, &["6.6.6.6, , 10.0.0.2"], PROXIES),
+            ip("10.0.0.2")
+        );
+        assert_eq!(client(peer, &["unknown"], PROXIES), ip("10.0.0.1"));
+        // Past the first untrusted address nothing is read anyway.
+        assert_eq!(
+            client(peer, &["unknown, 203.0.113.7"], PROXIES),
+            ip("203.0.113.7")
+        );
+    }
+
+    #[test]
+    fn entries_with_ports_count_as_their_address() {
+        let peer = Some("10.0.0.1:5000");
+        assert_eq!(
+            client(peer, &["203.0.113.7:4711"], PROXIES),
+            ip("203.0.113.7")
+        );
+        assert_eq!(
+            client(peer, &["[2001:db8::7]:443"], PROXIES),
+            ip("2001:db8::7")
+        );
+        assert_eq!(client(peer, &["[2001:db8::7]"], PROXIES), ip("2001:db8::7"));
+        assert_eq!(client(peer, &["203.0.113.7:http"], PROXIES), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn unix_socket_peers_are_proxies() {
+        assert_eq!(client(None, &[], PROXIES), None);
+        assert_eq!(client(None, &["203.0.113.7"], &[]), ip("203.0.113.7"));
+        assert_eq!(
+            client(None, &["6.6.6.6, 203.0.113.7"], &[]),
+            ip("203.0.113.7")
+        );
+    }
+}
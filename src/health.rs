@@ -0,0 +1,486 @@
+// ==================================================
+// Health probes
+// ==================================================
+// Four endpoints with distinct semantics:
+//
+//   GET /health   -> legacy check, FAST and ALWAYS 200
+//   GET /livez    -> liveness: the process is running (always 200)
+//   GET /readyz   -> readiness: 503 until startup completes, while
+//                    shutdown is draining, or when any registered
+//                    component check fails
+//   GET /startupz -> startup: 503 until boot (migrations, warmup)
+//                    has completed, 200 afterwards
+//
+// Orchestrators should restart on liveness, route traffic on
+// readiness, and hold off liveness checks until startup passes.
+//
+// Components (database, disk, workers, outbound services) plug
+// into readiness by implementing `HealthCheck` and registering in
+// the `HealthRegistry`. `/readyz?verbose=1` shows each of them.
+
+use crate::{extract::Query, shutdown::Shutdown};
+use async_trait::async_trait;
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+// --------------------------------------------------
+// Response model
+// --------------------------------------------------
+
+#[derive(Serialize)]
+pub struct HealthResponse {
+    status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    components: Option<Vec<ComponentReport>>,
+}
+
+impl HealthResponse {
+    fn ok() -> Self {
+        Self {
+            status: "ok",
+            reason: None,
+            components: None,
+        }
+    }
+
+    fn unavailable(reason: &'static str) -> Self {
+        Self {
+            status: "unavailable",
+            reason: Some(reason),
+            components: None,
+        }
+    }
+}
+
+/// 200 when `ok`, 503 otherwise.
+impl IntoResponse for HealthResponse {
+    fn into_response(self) -> Response {
+        let code = if self.status == "ok" {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        (code, Json(self)).into_response()
+    }
+}
+
+#[derive(Clone, Serialize)]
+pub struct ComponentReport {
+    name: String,
+    status: &'static str,
+    latency_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_error: Option<String>,
+}
+
+impl ComponentReport {
+    fn is_up(&self) -> bool {
+        self.status == "up"
+    }
+}
+
+// --------------------------------------------------
+// Component checks
+// --------------------------------------------------
+
+/// A dependency whose failure should take the instance out of rotation.
+#[async_trait]
+pub trait HealthCheck: Send + Sync {
+    /// `Err` carries a short, human-readable reason (no secrets).
+    async fn check(&self) -> Result<(), String>;
+}
+
+/// Runs registered checks concurrently, each bounded by `timeout`,
+/// and caches the combined result for `cache_ttl` so frequent
+/// probes never translate into load on the dependencies.
+pub struct HealthRegistry {
+    checks: Vec<(String, Arc<dyn HealthCheck>)>,
+    timeout: Duration,
+    cache_ttl: Duration,
+    cache: Mutex<CheckCache>,
+}
+
+#[derive(Default)]
+struct CheckCache {
+    checked_at: Option<Instant>,
+    reports: Vec<ComponentReport>,
+    last_errors: HashMap<String, String>,
+}
+
+impl HealthRegistry {
+    pub fn new(timeout: Duration, cache_ttl: Duration) -> Self {
+        Self {
+            checks: Vec::new(),
+            timeout,
+            cache_ttl,
+            cache: Mutex::new(CheckCache::default()),
+        }
+    }
+
+    pub fn register(mut self, name: impl Into<String>, check: impl HealthCheck + 'static) -> Self {
+        self.checks.push((name.into(), Arc::new(check)));
+        self
+    }
+
+    /// Current report for every component, served from cache when fresh.
+    ///
+    /// The cache lock is held while checks run, so concurrent probes
+    /// wait for one shared run instead of starting their own.
+    pub async fn run(&self) -> Vec<ComponentReport> {
+        let mut cache = self.cache.lock().await;
+
+        if let Some(checked_at) = cache.checked_at
+            && checked_at.elapsed() < self.cache_ttl
+        {
+            return cache.reports.clone();
+        }
+
+        let handles: Vec<_> = self
+            .checks
+            .iter()
+            .map(|(name, check)| {
+                let check = check.clone();
+                let timeout = self.timeout;
+                let handle = tokio::spawn(async move {
+                    let started = Instant::now();
+                    let result = match tokio::time::timeout(timeout, check.check()).await {
+                        Ok(result) => result,
+                        Err(_) => Err(format!("timed out after {}ms", timeout.as_millis())),
+                    };
+                    (result, started.elapsed())
+                });
+                (name.clone(), handle)
+            })
+            .collect();
+
+        let mut reports = Vec::with_capacity(handles.len());
+        for (name, handle) in handles {
+            let (result, latency) = handle
+                .await
+                .unwrap_or_else(|err| (Err(format!("check panicked: {err}")), Duration::ZERO));
+
+            if let Err(err) = &result {
+                cache.last_errors.insert(name.clone(), err.clone());
+            }
+
+            reports.push(ComponentReport {
+                status: if result.is_ok() { "up" } else { "down" },
+                latency_ms: latency.as_millis() as u64,
+                last_error: cache.last_errors.get(&name).cloned(),
+                name,
+            });
+        }
+
+        cache.checked_at = Some(Instant::now());
+        cache.reports = reports.clone();
+        reports
+    }
+}
+
+// --------------------------------------------------
+// Built-in checks
+// --------------------------------------------------
+
+/// Fails when free space on the filesystem holding `path` drops
+/// below `min_free_bytes`.
+#[cfg(unix)]
+pub struct DiskSpaceCheck {
+    pub path: std::path::PathBuf,
+    pub min_free_bytes: u64,
+}
+
+#[cfg(unix)]
+#[async_trait]
+impl HealthCheck for DiskSpaceCheck {
+    async fn check(&self) -> Result<(), String> {
+        let path = self.path.clone();
+        let free = tokio::task::spawn_blocking(move || free_bytes(&path))
+            .await
+            .map_err(|err| err.to_string())?
+            .map_err(|err| format!("statvfs {}: {}", self.path.display(), err))?;
+
+        if free < self.min_free_bytes {
+            return Err(format!(
+                "{} MiB free on {}, need at least {} MiB",
+                free / (1024 * 1024),
+                self.path.display(),
+                self.min_free_bytes / (1024 * 1024)
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn free_bytes(path: &std::path::Path) -> std::io::Result<u64> {
+    use std::{ffi::CString, mem::MaybeUninit, os::unix::ffi::OsStrExt};
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat = MaybeUninit::<libc::statvfs>::uninit();
+
+    // SAFETY: `path` is a valid NUL-terminated string and `stat` is
+    // only read after statvfs reports success.
+    let stat = unsafe {
+        if libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        stat.assume_init()
+    };
+
+    // Field widths differ between platforms.
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+// --------------------------------------------------
+// Lifecycle state
+// --------------------------------------------------
+
+#[derive(Clone)]
+pub struct Health {
+    started: Arc<AtomicBool>,
+    shutdown: Shutdown,
+    registry: Arc<HealthRegistry>,
+}
+
+impl Health {
+    pub fn new(shutdown: Shutdown, registry: HealthRegistry) -> Self {
+        Self {
+            started: Arc::new(AtomicBool::new(false)),
+            shutdown,
+            registry: Arc::new(registry),
+        }
+    }
+
+    /// Called by `main` once every boot step has completed.
+    pub fn mark_started(&self) {
+        self.started.store(true, Ordering::SeqCst);
+    }
+
+    fn is_started(&self) -> bool {
+        self.started.load(Ordering::SeqCst)
+    }
+}
+
+// --------------------------------------------------
+// Handlers
+// --------------------------------------------------
+
+/// Healthcheck endpoint
+/// Must be FAST and ALWAYS return 200
+pub async fn health_handler() -> impl IntoResponse {
+    HealthResponse::ok()
+}
+
+pub async fn livez_handler() -> impl IntoResponse {
+    HealthResponse::ok()
+}
+
+#[derive(Deserialize)]
+pub struct ReadyzParams {
+    verbose: Option<String>,
+}
+
+pub async fn readyz_handler(
+    State(health): State<Health>,
+    Query(params): Query<ReadyzParams>,
+) -> impl IntoResponse {
+    if !health.is_started() {
+        return HealthResponse::unavailable("starting");
+    }
+    if health.shutdown.is_triggered() {
+        return HealthResponse::unavailable("draining");
+    }
+
+    let components = health.registry.run().await;
+
+    let mut response = if components.iter().all(ComponentReport::is_up) {
+        HealthResponse::ok()
+    } else {
+        HealthResponse::unavailable("dependency down")
+    };
+
+    if matches!(params.verbose.as_deref(), Some("1" | "true")) {
+        response.components = Some(components);
+    }
+    response
+}
+
+pub async fn startupz_handler(State(health): State<Health>) -> impl IntoResponse {
+    if !health.is_started() {
+        return HealthResponse::unavailable("starting");
+    }
+    HealthResponse::ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::{to_bytes, Body},
+        http::Request,
+        routing::get,
+        Router,
+    };
+    use std::sync::atomic::AtomicUsize;
+    use tower::ServiceExt;
+
+    /// Sleeps for `delay`, then fails while `failures` lasts.
+    #[derive(Clone, Default)]
+    struct Stub {
+        delay: Duration,
+        failures: Arc<AtomicUsize>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl HealthCheck for Stub {
+        async fn check(&self) -> Result<(), String> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(self.delay).await;
+            let failing = self
+                .failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok();
+            if failing {
+                return Err("connection refused".to_string());
+            }
+            Ok(())
+        }
+    }
+
+    fn slow(ms: u64) -> Stub {
+        Stub {
+            delay: Duration::from_millis(ms),
+            ..Stub::default()
+        }
+    }
+
+    fn statuses(reports: &[ComponentReport]) -> Vec<(&str, &str)> {
+        reports
+            .iter()
+            .map(|r| (r.name.as_str(), r.status))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn checks_run_concurrently() {
+        let registry = HealthRegistry::new(Duration::from_secs(5), Duration::ZERO)
+            .register("a", slow(200))
+            .register("b", slow(200))
+            .register("c", slow(200));
+
+        let started = Instant::now();
+        let reports = registry.run().await;
+        assert!(started.elapsed() < Duration::from_millis(500));
+        assert_eq!(statuses(&reports), [("a", "up"), ("b", "up"), ("c", "up")]);
+        assert!(reports.iter().all(|r| r.latency_ms >= 200));
+    }
+
+    #[tokio::test]
+    async fn slow_checks_time_out() {
+        let registry = HealthRegistry::new(Duration::from_millis(50), Duration::ZERO)
+            .register("fast", Stub::default())
+            .register("stuck", slow(10_000));
+
+        let started = Instant::now();
+        let reports = registry.run().await;
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(statuses(&reports), [("fast", "up"), ("stuck", "down")]);
+        assert_eq!(
+            reports[1].last_error.as_deref(),
+            Some("timed out after 50ms")
+        );
+    }
+
+    #[tokio::test]
+    async fn results_are_cached_within_the_ttl() {
+        let check = Stub::default();
+        let registry = HealthRegistry::new(Duration::from_secs(5), Duration::from_secs(3600))
+            .register("db", check.clone());
+        registry.run().await;
+        registry.run().await;
+        assert_eq!(check.calls.load(Ordering::SeqCst), 1);
+
+        let check = Stub::default();
+        let registry = HealthRegistry::new(Duration::from_secs(5), Duration::ZERO)
+            .register("db", check.clone());
+        registry.run().await;
+        registry.run().await;
+        assert_eq!(check.calls.load(Ordering::SeqCst), 2);
+    }
+
+    async fn readyz(health: Health, query: &str) -> (StatusCode, serde_json::Value) {
+        let app = Router::new()
+            .route("/readyz", get(readyz_handler))
+            .with_state(health);
+        let request = Request::get(format!("/readyz{query}"))
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn verbose_readiness_reports_each_component() {
+        let database = Stub {
+            failures: Arc::new(AtomicUsize::new(1)),
+            ..Stub::default()
+        };
+        let registry = HealthRegistry::new(Duration::from_secs(5), Duration::ZERO)
+            .register("database", database)
+            .register("disk", slow(20));
+        let shutdown = Shutdown::new();
+        let health = Health::new(shutdown.clone(), registry);
+
+        let (status, body) = readyz(health.clone(), "?verbose=1").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["reason"], "starting");
+        health.mark_started();
+
+        let (status, body) = readyz(health.clone(), "?verbose=1").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["status"], "unavailable");
+        assert_eq!(body["reason"], "dependency down");
+        let components = body["components"].as_array().unwrap();
+        assert_eq!(components[0]["name"], "database");
+        assert_eq!(components[0]["status"], "down");
+        assert_eq!(components[0]["last_error"], "connection refused");
+        assert_eq!(components[1]["name"], "disk");
+        assert_eq!(components[1]["status"], "up");
+        assert!(components[1]["latency_ms"].as_u64().unwrap() >= 20);
+        assert!(components[1].get("last_error").is_none());
+
+        // Recovered, but the last error is still shown.
+        let (status, body) = readyz(health.clone(), "?verbose=1").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["components"][0]["status"], "up");
+        assert_eq!(body["components"][0]["last_error"], "connection refused");
+
+        let (_, body) = readyz(health.clone(), "").await;
+        assert_eq!(body, serde_json::json!({"status": "ok"}));
+
+        shutdown.trigger();
+        let (status, body) = readyz(health, "").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body["reason"], "draining");
+    }
+}
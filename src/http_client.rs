@@ -0,0 +1,513 @@
+// ==================================================
+// Outbound HTTP
+// ==================================================
+// The few calls the service makes itself (OTLP export, JWKS
+// download), made with hyper's client on a connection of their own.
+// `https://` uses rustls, verifying the certificate and host name
+// against the system trust store.
+//
+// - `send` makes one HTTP/1.1 request and reads the whole response
+// - `grpc_unary` makes a unary gRPC call (OTLP/gRPC) over HTTP/2;
+//   over `https://` the server must negotiate `h2` through ALPN
+
+use axum::http::{header, HeaderMap, Method, Request, StatusCode};
+use http_body_util::{BodyExt, Full, LengthLimitError, Limited};
+use hyper::body::Bytes;
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use std::{fmt, io, time::Duration};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpStream,
+};
+
+/// Responses larger than this are rejected.
+const MAX_RESPONSE_BYTES: usize = 1024 * 1024;
+
+// --------------------------------------------------
+// Endpoints
+// --------------------------------------------------
+
+/// An `http://` or `https://` URL, e.g. `http://collector:4318/v1/traces`.
+#[derive(Clone)]
+pub struct Endpoint {
+    https: bool,
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Endpoint {
+    pub fn parse(url: &str) -> Result<Self, String> {
+        let (https, rest) = match url.split_once("://") {
+            Some(("http", rest)) => (false, rest),
+            Some(("https", rest)) if cfg!(unix) => (true, rest),
+            Some(("https", _)) => return Err("https is only supported on Unix".to_string()),
+            _ => return Err(format!("expected an http:// or https:// URL, got {url:?}")),
+        };
+
+        let (authority, path) = match rest.find('/') {
+            Some(i) => (&rest[..i], &rest[i..]),
+            None => (rest, "/"),
+        };
+        let (host, port) = match authority.strip_prefix('[') {
+            // [IPv6]:port
+            Some(bracketed) => {
+                let (host, port) = bracketed
+                    .split_once(']')
+                    .ok_or_else(|| format!("unterminated IPv6 address in {url:?}"))?;
+                (host, port.strip_prefix(':'))
+            }
+            None => match authority.rsplit_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port
+                .parse()
+                .map_err(|_| format!("invalid port in {url:?}"))?,
+            None if https => 443,
+            None => 80,
+        };
+        if host.is_empty() {
+            return Err(format!("missing host in {url:?}"));
+        }
+
+        Ok(Self {
+            https,
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+        })
+    }
+
+    pub fn is_https(&self) -> bool {
+        self.https
+    }
+
+    /// `host:port`, bracketing IPv6 addresses.
+    fn authority(&self) -> String {
+        if self.host.contains(':') {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = if self.https { "https" } else { "http" };
+        write!(f, "{scheme}://{}{}", self.authority(), self.path)
+    }
+}
+
+// --------------------------------------------------
+// Requests
+// --------------------------------------------------
+
+pub struct Response {
+    pub status: u16,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+/// Send one request and read the whole response. Callers bound the
+/// total time with `tokio::time::timeout`.
+pub async fn send(
+    endpoint: &Endpoint,
+    method: Method,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> io::Result<Response> {
+    let stream = TcpStream::connect((endpoint.host.as_str(), endpoint.port)).await?;
+    let request = request(endpoint, method, headers, body)?;
+
+    if endpoint.https {
+        #[cfg(unix)]
+        {
+            let stream = crate::tls::connect(stream, &endpoint.host, false).await?;
+            return exchange(stream, request).await;
+        }
+    }
+    exchange(stream, request).await
+}
+
+fn request(
+    endpoint: &Endpoint,
+    method: Method,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> io::Result<Request<Full<Bytes>>> {
+    let mut request = Request::builder()
+        .method(method)
+        .uri(&endpoint.path)
+        .header(header::HOST, endpoint.authority())
+        .body(Full::new(Bytes::copy_from_slice(body)))
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    request.headers_mut().extend(headers.clone());
+    Ok(request)
+}
+
+async fn exchange<S>(stream: S, request: Request<Full<Bytes>>) -> io::Result<Response>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream))
+        .await
+        .map_err(io::Error::other)?;
+    let connection = tokio::spawn(connection);
+
+    let result = async {
+        let response = sender
+            .send_request(request)
+            .await
+            .map_err(io::Error::other)?;
+        let status = response.status().as_u16();
+        let body = Limited::new(response.into_body(), MAX_RESPONSE_BYTES)
+            .collect()
+            .await
+            .map_err(|err| match err.downcast::<LengthLimitError>() {
+                Ok(_) => invalid("response too large"),
+                Err(err) => io::Error::other(err),
+            })?;
+        Ok(Response {
+            status,
+            body: body.to_bytes().to_vec(),
+        })
+    }
+    .await;
+    connection.abort();
+    result
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+// --------------------------------------------------
+// gRPC
+// --------------------------------------------------
+
+/// gRPC status code for success.
+const GRPC_OK: u32 = 0;
+
+/// Call `method` (`/package.Service/Method`) with one encoded
+/// protobuf message and return the response message. `timeout` is
+/// passed on as `grpc-timeout` (callers still bound the call
+/// themselves).
+pub async fn grpc_unary(
+    endpoint: &Endpoint,
+    method: &str,
+    metadata: &HeaderMap,
+    timeout: Duration,
+    message: &[u8],
+) -> io::Result<Vec<u8>> {
+    let stream = TcpStream::connect((endpoint.host.as_str(), endpoint.port)).await?;
+    let request = grpc_request(endpoint, method, metadata, timeout, message)?;
+
+    if endpoint.https {
+        #[cfg(unix)]
+        {
+            let stream = crate::tls::connect(stream, &endpoint.host, true).await?;
+            return grpc_exchange(stream, request).await;
+        }
+    }
+    grpc_exchange(stream, request).await
+}
+
+fn grpc_request(
+    endpoint: &Endpoint,
+    method: &str,
+    metadata: &HeaderMap,
+    timeout: Duration,
+    message: &[u8],
+) -> io::Result<Request<Full<Bytes>>> {
+    // Length-prefixed message: uncompressed flag, big-endian length.
+    let mut body = Vec::with_capacity(5 + message.len());
+    body.push(0);
+    body.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    body.extend_from_slice(message);
+
+    let scheme = if endpoint.https { "https" } else { "http" };
+    let mut request = Request::post(format!("{scheme}://{}{method}", endpoint.authority()))
+        .header(header::CONTENT_TYPE, "application/grpc")
+        .header(header::TE, "trailers")
+        // At most 8 digits are allowed.
+        .header(
+            "grpc-timeout",
+            format!("{}m", timeout.as_millis().min(99_999_999)),
+        )
+        .body(Full::new(Bytes::from(body)))
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    request.headers_mut().extend(metadata.clone());
+    Ok(request)
+}
+
+async fn grpc_exchange<S>(stream: S, request: Request<Full<Bytes>>) -> io::Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) =
+        hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+            .await
+            .map_err(io::Error::other)?;
+    let connection = tokio::spawn(connection);
+
+    let result = async {
+        let response = sender
+            .send_request(request)
+            .await
+            .map_err(io::Error::other)?;
+        if response.status() != StatusCode::OK {
+            return Err(io::Error::other(format!(
+                "server responded {}",
+                response.status().as_u16()
+            )));
+        }
+        let (parts, body) = response.into_parts();
+        let body = body.collect().await.map_err(io::Error::other)?;
+        // A call that fails before any message has its status in the
+        // headers ("Trailers-Only"); otherwise it is in the trailers.
+        let status = match body.trailers() {
+            Some(trailers) if trailers.contains_key("grpc-status") => grpc_status(trailers),
+            _ => grpc_status(&parts.headers),
+        }?;
+        if status != GRPC_OK {
+            return Err(io::Error::other(format!("gRPC status {status}")));
+        }
+
+        let body = body.to_bytes();
+        if body.is_empty() {
+            return Ok(Vec::new());
+        }
+        match body.split_first_chunk::<5>() {
+            Some(([0, len @ ..], message))
+                if u32::from_be_bytes(*len) as usize == message.len() =>
+            {
+                Ok(message.to_vec())
+            }
+            _ => Err(invalid("malformed gRPC response")),
+        }
+    }
+    .await;
+    connection.abort();
+    result
+}
+
+/// `grpc-status`, with `grpc-message` folded into the error.
+fn grpc_status(headers: &HeaderMap) -> io::Result<u32> {
+    let status = headers
+        .get("grpc-status")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| invalid("missing grpc-status"))?;
+    match headers.get("grpc-message").and_then(|v| v.to_str().ok()) {
+        Some(message) if status != GRPC_OK => {
+            Err(io::Error::other(format!("gRPC status {status}: {message}")))
+        }
+        _ => Ok(status),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{HeaderName, HeaderValue};
+    use hyper::{body::Incoming, server::conn::http1, service::service_fn};
+    use std::convert::Infallible;
+    use tokio::net::TcpListener;
+
+    /// An HTTP/1.1 server on loopback answering every request with
+    /// `reply`, returning its base URL.
+    async fn server<F>(reply: F) -> String
+    where
+        F: Fn(Request<Incoming>) -> axum::http::Response<Full<Bytes>>
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let reply = reply.clone();
+                let service = service_fn(move |request| {
+                    let reply = reply.clone();
+                    async move { Ok::<_, Infallible>(reply(request)) }
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+        url
+    }
+
+    #[test]
+    fn endpoints_are_parsed() {
+        for (url, https, host, port, path) in [
+            (
+                "http://collector:4318/v1/traces",
+                false,
+                "collector",
+                4318,
+                "/v1/traces",
+            ),
+            ("http://collector", false, "collector", 80, "/"),
+            (
+                "https://idp.example.com/jwks?v=2",
+                true,
+                "idp.example.com",
+                443,
+                "/jwks?v=2",
+            ),
+            ("http://[::1]:8080/x", false, "::1", 8080, "/x"),
+            ("https://[2001:db8::1]/", true, "2001:db8::1", 443, "/"),
+        ] {
+            let endpoint = Endpoint::parse(url).unwrap();
+            assert_eq!(
+                (
+                    endpoint.is_https(),
+                    endpoint.host.as_str(),
+                    endpoint.port,
+                    endpoint.path.as_str()
+                ),
+                (https, host, port, path),
+                "{url}"
+            );
+        }
+        assert_eq!(
+            Endpoint::parse("http://[::1]").unwrap().to_string(),
+            "http://[::1]:80/"
+        );
+    }
+
+    #[test]
+    fn invalid_endpoints_are_rejected() {
+        for (url, error) in [
+            (
+                "collector:4318",
+                "expected an http:// or https:// URL, got \"collector:4318\"",
+            ),
+            (
+                "ftp://collector/",
+                "expected an http:// or https:// URL, got \"ftp://collector/\"",
+            ),
+            ("http://:4318/", "missing host in \"http://:4318/\""),
+            (
+                "http://collector:otlp/",
+                "invalid port in \"http://collector:otlp/\"",
+            ),
+            (
+                "http://collector:99999/",
+                "invalid port in \"http://collector:99999/\"",
+            ),
+            (
+                "http://[::1/",
+                "unterminated IPv6 address in \"http://[::1/\"",
+            ),
+        ] {
+            assert_eq!(Endpoint::parse(url).err().as_deref(), Some(error), "{url}");
+        }
+    }
+
+    #[tokio::test]
+    async fn requests_are_sent_and_responses_read() {
+        let url = server(|request| {
+            let (parts, _) = request.into_parts();
+            let echo = format!(
+                "{} {} host={} accept={}",
+                parts.method,
+                parts.uri,
+                parts.headers[header::HOST].to_str().unwrap(),
+                parts.headers[header::ACCEPT].to_str().unwrap(),
+            );
+            axum::http::Response::builder()
+                .status(StatusCode::CREATED)
+                .body(Full::new(Bytes::from(echo)))
+                .unwrap()
+        })
+        .await;
+        let endpoint = Endpoint::parse(&format!("{url}/v1/traces?x=1")).unwrap();
+        let headers =
+            HeaderMap::from_iter([(header::ACCEPT, HeaderValue::from_static("application/json"))]);
+
+        let response = send(&endpoint, Method::POST, &headers, b"spans")
+            .await
+            .unwrap();
+        assert_eq!(response.status, 201);
+        assert!(response.is_success());
+        let authority = url.trim_start_matches("http://");
+        assert_eq!(
+            String::from_utf8(response.body).unwrap(),
+            format!("POST /v1/traces?x=1 host={authority} accept=application/json")
+        );
+    }
+
+    #[tokio::test]
+    async fn large_responses_are_rejected() {
+        let url = server(|_| {
+            let body = vec![b'x'; MAX_RESPONSE_BYTES + 1];
+            axum::http::Response::new(Full::new(Bytes::from(body)))
+        })
+        .await;
+        let endpoint = Endpoint::parse(&url).unwrap();
+
+        let err = send(&endpoint, Method::GET, &HeaderMap::new(), &[])
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), "response too large");
+    }
+
+    #[tokio::test]
+    async fn error_statuses_are_returned() {
+        let url = server(|_| {
+            axum::http::Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(Full::default())
+                .unwrap()
+        })
+        .await;
+        let endpoint = Endpoint::parse(&url).unwrap();
+
+        let response = send(&endpoint, Method::GET, &HeaderMap::new(), &[])
+            .await
+            .unwrap();
+        assert_eq!(response.status, 503);
+        assert!(!response.is_success());
+    }
+
+    #[test]
+    fn grpc_statuses_are_read_from_headers() {
+        let headers = |pairs: &[(&'static str, &'static str)]| {
+            HeaderMap::from_iter(
+                pairs
+                    .iter()
+                    .map(|(k, v)| (HeaderName::from_static(k), HeaderValue::from_static(v))),
+            )
+        };
+        assert_eq!(grpc_status(&headers(&[("grpc-status", "0")])).unwrap(), 0);
+        assert_eq!(grpc_status(&headers(&[("grpc-status", "14")])).unwrap(), 14);
+        assert_eq!(
+            grpc_status(&headers(&[
+                ("grpc-status", "3"),
+                ("grpc-message", "bad span")
+            ]))
+            .unwrap_err()
+            .to_string(),
+            "gRPC status 3: bad span"
+        );
+        assert_eq!(
+            grpc_status(&headers(&[])).unwrap_err().to_string(),
+            "missing grpc-status"
+        );
+    }
+}
@@ -0,0 +1,723 @@
+// ==================================================
+// Listeners
+// ==================================================
+// Where the HTTP server accepts connections:
+//
+// - TCP on APP_BIND:APP_PORT (IPv4, or IPv6 `::` which is bound
+//   dual-stack so IPv4 clients are accepted too)
+// - A Unix domain socket from APP_LISTEN=unix:/path, created with
+//   APP_SOCKET_MODE permissions
+//
+// A leftover socket file from a crashed process is removed at boot,
+// but only after checking nothing is accepting on it; the file is
+// removed again once the server has shut down gracefully.
+//
+// Listeners can also be adopted from an already-open descriptor
+// (systemd socket activation, binary upgrades). Socket files of
+// systemd sockets belong to systemd and are left alone.
+//
+// Plain TCP is served by `axum::serve`. Unix sockets and TLS use an
+// equivalent accept loop, since `axum::serve` only takes a plain
+// `TcpListener`; it serves HTTP/1.1 and HTTP/2, told apart by the
+// connection preface (for TLS, as negotiated with ALPN). Either way,
+// requests over TCP carry the peer address as
+// `ConnectInfo<SocketAddr>`.
+
+use crate::shutdown::Shutdown;
+#[cfg(unix)]
+use crate::{
+    extract::ClientIdentity,
+    tls::{self, TlsAcceptor, TlsStream},
+};
+#[cfg(unix)]
+use axum::extract::ConnectInfo;
+use axum::Router;
+use std::{
+    fmt, io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+};
+#[cfg(unix)]
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpSocket};
+use tracing::{debug, info, warn};
+
+/// Pending connections queued by the kernel.
+const BACKLOG: u32 = 1024;
+
+/// Name that marks an inherited socket as the admin listener.
+#[cfg(unix)]
+const ADMIN_FD_NAME: &str = "admin";
+
+// --------------------------------------------------
+// Addresses
+// --------------------------------------------------
+
+#[derive(Clone, Debug)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix {
+        path: PathBuf,
+        mode: u32,
+    },
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "http://{addr}"),
+            #[cfg(unix)]
+            Self::Unix { path, .. } => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// An IP address, optionally in brackets (`[::]`) as is usual for IPv6.
+pub fn parse_ip(value: &str) -> Result<IpAddr, String> {
+    let value = value.trim();
+    let unbracketed = value
+        .strip_prefix('[')
+        .and_then(|v| v.strip_suffix(']'))
+        .unwrap_or(value);
+    unbracketed
+        .parse()
+        .map_err(|_| format!("not an IP address: {value:?}"))
+}
+
+/// An address block in CIDR notation, e.g. `10.0.0.0/8` or `fd00::/8`.
+/// A bare address is a block of one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IpNet {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl IpNet {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let value = value.trim();
+        let (addr, prefix) = match value.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (value, None),
+        };
+        let addr = parse_ip(addr)?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            None => max,
+            Some(prefix) => prefix
+                .parse()
+                .ok()
+                .filter(|prefix| *prefix <= max)
+                .ok_or_else(|| format!("invalid prefix length in {value:?}"))?,
+        };
+        Ok(Self { addr, prefix })
+    }
+
+    /// IPv4-mapped IPv6 addresses (`::ffff:10.0.0.1`, as seen on a
+    /// dual-stack socket) match IPv4 blocks.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+/// `unix:/path/to.sock`; `None` for any other scheme.
+pub fn parse_unix_path(value: &str) -> Option<PathBuf> {
+    value
+        .trim()
+        .strip_prefix("unix:")
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+}
+
+// --------------------------------------------------
+// Binding
+// --------------------------------------------------
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, Option<SocketFile>),
+    #[cfg(unix)]
+    Tls(TcpListener, TlsAcceptor),
+}
+
+impl Listener {
+    pub async fn bind(addr: &ListenAddr) -> io::Result<Self> {
+        match addr {
+            ListenAddr::Tcp(addr) => bind_tcp(*addr).map(Self::Tcp),
+            #[cfg(unix)]
+            ListenAddr::Unix { path, mode } => bind_unix(path, *mode).await,
+        }
+    }
+
+    /// Adopt an already-bound, listening TCP or Unix stream socket.
+    #[cfg(unix)]
+    pub fn from_fd(fd: std::os::fd::OwnedFd) -> io::Result<Self> {
+        match socket_family(&fd)? {
+            libc::AF_INET | libc::AF_INET6 => {
+                let listener = std::net::TcpListener::from(fd);
+                listener.set_nonblocking(true)?;
+                TcpListener::from_std(listener).map(Self::Tcp)
+            }
+            libc::AF_UNIX => {
+                let listener = std::os::unix::net::UnixListener::from(fd);
+                listener.set_nonblocking(true)?;
+                tokio::net::UnixListener::from_std(listener).map(|l| Self::Unix(l, None))
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "inherited descriptor is not a TCP or Unix socket",
+            )),
+        }
+    }
+
+    /// Sort inherited descriptors into (public, admin) listeners: the
+    /// one named `admin` serves the admin router, the first other one
+    /// the API. Extra descriptors are closed.
+    #[cfg(unix)]
+    pub fn from_named_fds(
+        fds: Vec<(std::os::fd::OwnedFd, String)>,
+    ) -> io::Result<(Option<Self>, Option<Self>)> {
+        let mut public = None;
+        let mut admin = None;
+
+        for (fd, name) in fds {
+            let slot = if name == ADMIN_FD_NAME {
+                &mut admin
+            } else {
+                &mut public
+            };
+            if slot.is_some() {
+                warn!("Ignoring extra inherited listener {:?}", name);
+                continue;
+            }
+            info!("Using inherited listener {:?}", name);
+            *slot = Some(Self::from_fd(fd)?);
+        }
+        Ok((public, admin))
+    }
+
+    /// Take over removal of an inherited Unix socket's file, as if
+    /// this process had bound it.
+    #[cfg(unix)]
+    pub fn adopt_socket_file(self) -> Self {
+        match self {
+            Self::Unix(listener, None) => {
+                let path = listener
+                    .local_addr()
+                    .ok()
+                    .and_then(|addr| addr.as_pathname().map(PathBuf::from));
+                Self::Unix(listener, path.map(SocketFile))
+            }
+            other => other,
+        }
+    }
+
+    /// Terminate TLS on this listener; only TCP listeners qualify.
+    #[cfg(unix)]
+    pub fn with_tls(self, acceptor: TlsAcceptor) -> io::Result<Self> {
+        match self {
+            Self::Tcp(listener) => Ok(Self::Tls(listener, acceptor)),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "TLS needs a TCP listener",
+            )),
+        }
+    }
+
+    #[cfg(unix)]
+    pub fn as_raw_fd(&self) -> std::os::fd::RawFd {
+        use std::os::fd::AsRawFd;
+        match self {
+            Self::Tcp(listener) | Self::Tls(listener, _) => listener.as_raw_fd(),
+            Self::Unix(listener, _) => listener.as_raw_fd(),
+        }
+    }
+
+    /// Serve `app` until shutdown is triggered and every connection
+    /// has finished.
+    pub async fn serve(self, app: Router, shutdown: Shutdown) -> io::Result<()> {
+        match self {
+            Self::Tcp(listener) => {
+                axum::serve(
+                    listener,
+                    app.into_make_service_with_connect_info::<SocketAddr>(),
+                )
+                .with_graceful_shutdown(async move { shutdown.triggered().await })
+                .await
+            }
+            #[cfg(unix)]
+            Self::Unix(listener, socket_file) => {
+                serve_http(listener, app, shutdown).await;
+                // Only now is the socket file safe to remove.
+                drop(socket_file);
+                Ok(())
+            }
+            #[cfg(unix)]
+            Self::Tls(listener, acceptor) => {
+                serve_http(TlsListener(listener, acceptor), app, shutdown).await;
+                Ok(())
+            }
+        }
+    }
+}
+
+fn bind_tcp(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => {
+            let socket = TcpSocket::new_v6()?;
+            if addr.ip().is_unspecified() {
+                set_dual_stack(&socket)?;
+            }
+            socket
+        }
+    };
+    // Same as std/axum: allow quick restarts while old connections
+    // linger in TIME_WAIT.
+    #[cfg(unix)]
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(BACKLOG)
+}
+
+/// Accept IPv4 on an IPv6 wildcard socket regardless of the
+/// `net.ipv6.bindv6only` sysctl.
+#[cfg(unix)]
+fn set_dual_stack(socket: &TcpSocket) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let off: libc::c_int = 0;
+    // SAFETY: valid socket fd and a correctly sized option value.
+    let rc = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_IPV6,
+            libc::IPV6_V6ONLY,
+            (&off as *const libc::c_int).cast(),
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Address family of a listening stream socket.
+#[cfg(unix)]
+fn socket_family(fd: &impl std::os::fd::AsRawFd) -> io::Result<libc::c_int> {
+    let fd = fd.as_raw_fd();
+
+    let mut listening: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    // SAFETY: valid out-pointers sized for a c_int option.
+    let rc = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ACCEPTCONN,
+            (&mut listening as *mut libc::c_int).cast(),
+            &mut len,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if listening == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "inherited descriptor is not a listening socket",
+        ));
+    }
+
+    // SAFETY: sockaddr_storage is valid when zeroed and large enough
+    // for any address family.
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    // SAFETY: valid out-pointers sized for sockaddr_storage.
+    let rc = unsafe {
+        libc::getsockname(
+            fd,
+            (&mut addr as *mut libc::sockaddr_storage).cast(),
+            &mut len,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(libc::c_int::from(addr.ss_family))
+}
+
+#[cfg(not(unix))]
+fn set_dual_stack(_socket: &TcpSocket) -> io::Result<()> {
+    Ok(())
+}
+
+// --------------------------------------------------
+// Unix domain sockets
+// --------------------------------------------------
+
+/// Set once the listening sockets have been handed to a successor
+/// process, which now owns their files.
+#[cfg(unix)]
+static SOCKET_FILES_RELEASED: AtomicBool = AtomicBool::new(false);
+
+/// Stop removing socket files on shutdown (see `upgrade`).
+#[cfg(unix)]
+pub fn release_socket_files() {
+    SOCKET_FILES_RELEASED.store(true, Ordering::SeqCst);
+}
+
+/// Removes the socket file when dropped.
+#[cfg(unix)]
+pub struct SocketFile(PathBuf);
+
+#[cfg(unix)]
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        if SOCKET_FILES_RELEASED.load(Ordering::SeqCst) {
+            return;
+        }
+        if let Err(err) = std::fs::remove_file(&self.0) {
+            warn!("Failed to remove socket {}: {}", self.0.display(), err);
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn bind_unix(path: &std::path::Path, mode: u32) -> io::Result<Listener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            if tokio::net::UnixStream::connect(path).await.is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    format!("{} is in use by another process", path.display()),
+                ));
+            }
+            warn!("Removing stale socket {}", path.display());
+            std::fs::remove_file(path)?;
+        }
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+
+    // Bound under a umask that leaves at most `mode`, so the socket is
+    // never reachable with wider permissions than asked for, not even
+    // until `set_permissions` below. The umask is per process: it is
+    // only ever narrowed, by one bind at a time, and restored right
+    // after.
+    let listener = {
+        static UMASK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+        let _serialized = UMASK.lock().unwrap_or_else(|err| err.into_inner());
+        let narrow = |previous: libc::mode_t| previous | (!mode & 0o777) as libc::mode_t;
+        // SAFETY: umask only swaps the process file creation mask.
+        let previous = unsafe { libc::umask(0o777) };
+        unsafe { libc::umask(narrow(previous)) };
+        let bound = tokio::net::UnixListener::bind(path);
+        unsafe { libc::umask(previous) };
+        bound?
+    };
+    let socket_file = SocketFile(path.to_path_buf());
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(Listener::Unix(listener, Some(socket_file)))
+}
+
+// --------------------------------------------------
+// HTTP serving
+// --------------------------------------------------
+
+/// A listener served by `serve_http`. Accepting is split in two so
+/// that slow work on a new connection (the TLS handshake) runs in the
+/// connection's task rather than holding up the accept loop.
+#[cfg(unix)]
+trait Accept {
+    type Stream: Send + 'static;
+    type Io: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    /// A new connection and, for TCP, the peer's address.
+    fn accept(&self)
+        -> impl Future<Output = io::Result<(Self::Stream, Option<SocketAddr>)>> + Send;
+
+    fn handshake(
+        &self,
+        stream: Self::Stream,
+    ) -> impl Future<Output = io::Result<Self::Io>> + Send + 'static;
+
+    /// Verified client certificate of a connection (mutual TLS).
+    fn client_identity(_io: &Self::Io) -> Option<ClientIdentity> {
+        None
+    }
+}
+
+#[cfg(unix)]
+impl Accept for tokio::net::UnixListener {
+    type Stream = tokio::net::UnixStream;
+    type Io = tokio::net::UnixStream;
+
+    async fn accept(&self) -> io::Result<(Self::Stream, Option<SocketAddr>)> {
+        self.accept().await.map(|(stream, _)| (stream, None))
+    }
+
+    fn handshake(
+        &self,
+        stream: Self::Stream,
+    ) -> impl Future<Output = io::Result<Self::Io>> + Send + 'static {
+        std::future::ready(Ok(stream))
+    }
+}
+
+#[cfg(unix)]
+struct TlsListener(TcpListener, TlsAcceptor);
+
+#[cfg(unix)]
+impl Accept for TlsListener {
+    type Stream = tokio::net::TcpStream;
+    type Io = TlsStream;
+
+    fn client_identity(io: &Self::Io) -> Option<ClientIdentity> {
+        tls::client_identity(io)
+    }
+
+    async fn accept(&self) -> io::Result<(Self::Stream, Option<SocketAddr>)> {
+        let (stream, peer) = self.0.accept().await?;
+        stream.set_nodelay(true)?;
+        Ok((stream, Some(peer)))
+    }
+
+    fn handshake(
+        &self,
+        stream: Self::Stream,
+    ) -> impl Future<Output = io::Result<Self::Io>> + Send + 'static {
+        let acceptor = self.1.clone();
+        async move {
+            tokio::time::timeout(tls::HANDSHAKE_TIMEOUT, acceptor.accept(stream))
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))?
+        }
+    }
+}
+
+/// HTTP/1.1 and HTTP/2 accept loop with the same graceful shutdown as
+/// `axum::serve`: stop accepting, let open connections finish, then
+/// return.
+#[cfg(unix)]
+async fn serve_http<L: Accept>(listener: L, app: Router, shutdown: Shutdown) {
+    use hyper::service::{service_fn, Service};
+    use hyper_util::{
+        rt::{TokioExecutor, TokioIo},
+        server::conn::auto,
+        service::TowerToHyperService,
+    };
+    use std::sync::Arc;
+    use tokio::sync::{mpsc, Notify};
+
+    /// How long a just-accepted connection may take to send its first
+    /// request once shutdown has begun.
+    const FIRST_REQUEST_GRACE: std::time::Duration = std::time::Duration::from_secs(1);
+
+    // Every connection task holds a sender; `recv` returns `None`
+    // once the last one is dropped.
+    let (done_tx, mut done_rx) = mpsc::channel::<()>(1);
+
+    loop {
+        // Check shutdown first: a connection accepted after it fired
+        // would be closed before its first request is read.
+        let accepted = tokio::select! {
+            biased;
+            _ = shutdown.triggered() => break,
+            accepted = listener.accept() => accepted,
+        };
+        let (stream, peer) = match accepted {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                // Usually fd exhaustion; back off like axum::serve does.
+                warn!("Failed to accept connection: {}", err);
+                tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let handshake = listener.handshake(stream);
+        let app = app.clone();
+        let shutdown = shutdown.clone();
+        let done_tx = done_tx.clone();
+
+        tokio::spawn(async move {
+            let stream = match handshake.await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    // Port scanners, clients rejecting our certificate
+                    // or lacking one; not worth more than debug.
+                    debug!("Connection setup failed: {}", err);
+                    return;
+                }
+            };
+            let client_identity = L::client_identity(&stream);
+
+            // Signalled (and remembered) once the first request begins.
+            let first_request = Arc::new(Notify::new());
+            let service = TowerToHyperService::new(app);
+            let service = service_fn({
+                let first_request = first_request.clone();
+                move |mut request: hyper::Request<_>| {
+                    first_request.notify_one();
+                    if let Some(identity) = &client_identity {
+                        request.extensions_mut().insert(identity.clone());
+                    }
+                    // What `axum::serve` provides for plain TCP.
+                    if let Some(peer) = peer {
+                        request.extensions_mut().insert(ConnectInfo(peer));
+                    }
+                    service.call(request)
+                }
+            });
+
+            let builder = auto::Builder::new(TokioExecutor::new());
+            let conn = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+            tokio::pin!(conn);
+
+            tokio::select! {
+                _ = conn.as_mut() => {}
+                _ = shutdown.triggered() => {
+                    // hyper closes a connection that is idle, which
+                    // includes one whose first request has not been
+                    // read yet; give that request a moment to arrive.
+                    tokio::select! {
+                        _ = conn.as_mut() => {
+                            drop(done_tx);
+                            return;
+                        }
+                        _ = tokio::time::timeout(FIRST_REQUEST_GRACE, first_request.notified()) => {}
+                    }
+                    conn.as_mut().graceful_shutdown();
+                    let _ = conn.await;
+                }
+            }
+            drop(done_tx);
+        });
+    }
+
+    drop(listener);
+    drop(done_tx);
+    done_rx.recv().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn wildcard_ipv6_accepts_ipv4() {
+        let listener = bind_tcp("[::]:0".parse().unwrap()).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let (client, accepted) = tokio::join!(
+            tokio::net::TcpStream::connect(("127.0.0.1", port)),
+            listener.accept()
+        );
+        client.unwrap();
+        let (_, peer) = accepted.unwrap();
+        assert_eq!(peer.ip().to_canonical(), IpAddr::from([127, 0, 0, 1]));
+    }
+
+    #[cfg(unix)]
+    mod unix {
+        use super::*;
+        use std::{
+            os::unix::fs::PermissionsExt,
+            path::{Path, PathBuf},
+        };
+
+        /// A socket path of its own for each test.
+        fn socket_path(name: &str) -> PathBuf {
+            let path =
+                std::env::temp_dir().join(format!("hello-api-{}-{name}.sock", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            path
+        }
+
+        fn mode_of(path: &Path) -> u32 {
+            std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+        }
+
+        async fn bind(path: &Path, mode: u32) -> io::Result<Listener> {
+            Listener::bind(&ListenAddr::Unix {
+                path: path.to_path_buf(),
+                mode,
+            })
+            .await
+        }
+
+        #[tokio::test]
+        async fn sockets_get_the_configured_mode() {
+            for mode in [0o600, 0o660, 0o666] {
+                let path = socket_path(&format!("mode-{mode:o}"));
+                let listener = bind(&path, mode).await.unwrap();
+                assert_eq!(mode_of(&path), mode, "{mode:o}");
+                drop(listener);
+            }
+        }
+
+        #[tokio::test]
+        async fn stale_sockets_are_replaced() {
+            let path = socket_path("stale");
+            drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+            assert!(path.exists());
+
+            let listener = bind(&path, 0o660).await.unwrap();
+            tokio::net::UnixStream::connect(&path).await.unwrap();
+            drop(listener);
+        }
+
+        #[tokio::test]
+        async fn live_sockets_are_refused() {
+            let path = socket_path("live");
+            let live = bind(&path, 0o660).await.unwrap();
+
+            let err = bind(&path, 0o660).await.err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+            assert!(path.exists());
+            drop(live);
+
+            std::fs::write(&path, "not a socket").unwrap();
+            let err = bind(&path, 0o660).await.err().unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+            std::fs::remove_file(&path).unwrap();
+        }
+
+        #[tokio::test]
+        async fn socket_files_are_removed_on_drop() {
+            let path = socket_path("drop");
+            let listener = bind(&path, 0o660).await.unwrap();
+            assert!(matches!(listener, Listener::Unix(_, Some(_))));
+            assert!(path.exists());
+
+            drop(listener);
+            assert!(!path.exists());
+        }
+    }
+}
@@ -0,0 +1,197 @@
+// ==================================================
+// Logging
+// ==================================================
+// Stdout-first logging, configured by two variables:
+//
+// - LOG_LEVEL:  a default level and/or per-target directives,
+//               e.g. `info` or `hello_api=debug,tower_http=info`
+// - LOG_FORMAT: `full` (default), `compact`, `pretty` or `json`
+//
+// The `json` format emits one object per line, including the
+// fields of every span the event happened in, so log shippers
+// can ingest container output without regex parsing.
+
+use serde_json::{Map, Value};
+use std::{fmt, str::FromStr};
+use tracing::{
+    field::{Field, Visit},
+    Event, Level, Subscriber,
+};
+use tracing_subscriber::{
+    field::RecordFields,
+    filter::Targets,
+    fmt::{
+        format::Writer,
+        time::{FormatTime, SystemTime},
+        FmtContext, FormatEvent, FormatFields, FormattedFields,
+    },
+    prelude::*,
+    registry::LookupSpan,
+    Layer,
+};
+
+// --------------------------------------------------
+// Settings
+// --------------------------------------------------
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Full,
+    Compact,
+    Pretty,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "full" => Ok(Self::Full),
+            "compact" => Ok(Self::Compact),
+            "pretty" => Ok(Self::Pretty),
+            "json" => Ok(Self::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Default filter when `LOG_LEVEL` is unset: everything at INFO and
+/// above, except the server notices sqlx relays at INFO (such as
+/// PostgreSQL's "relation already exists, skipping").
+pub fn default_level() -> Targets {
+    Targets::new()
+        .with_default(Level::INFO)
+        .with_target("sqlx::postgres::notice", Level::WARN)
+}
+
+// --------------------------------------------------
+// Initialisation
+// --------------------------------------------------
+
+/// Install the global subscriber. Must be called once, before anything logs.
+pub fn init(level: Targets, format: LogFormat) {
+    let layer = match format {
+        LogFormat::Full => tracing_subscriber::fmt::layer().boxed(),
+        LogFormat::Compact => tracing_subscriber::fmt::layer().compact().boxed(),
+        LogFormat::Pretty => tracing_subscriber::fmt::layer().pretty().boxed(),
+        LogFormat::Json => tracing_subscriber::fmt::layer()
+            .fmt_fields(JsonFields)
+            .event_format(JsonFormat)
+            .boxed(),
+    };
+
+    tracing_subscriber::registry()
+        .with(layer.with_filter(level))
+        .init();
+}
+
+// --------------------------------------------------
+// JSON output
+// --------------------------------------------------
+
+/// Records span fields as a JSON object, so `JsonFormat` can embed them.
+struct JsonFields;
+
+impl<'writer> FormatFields<'writer> for JsonFields {
+    fn format_fields<R: RecordFields>(
+        &self,
+        mut writer: Writer<'writer>,
+        fields: R,
+    ) -> fmt::Result {
+        let mut map = Map::new();
+        fields.record(&mut JsonVisitor(&mut map));
+        write!(writer, "{}", Value::Object(map))
+    }
+
+    fn add_fields(
+        &self,
+        current: &mut FormattedFields<Self>,
+        fields: &tracing::span::Record<'_>,
+    ) -> fmt::Result {
+        let mut map: Map<String, Value> = serde_json::from_str(&current.fields).unwrap_or_default();
+        fields.record(&mut JsonVisitor(&mut map));
+        current.fields = Value::Object(map).to_string();
+        Ok(())
+    }
+}
+
+/// One JSON object per event:
+/// `{"timestamp","level","target","fields":{..},"spans":[{"name",..}]}`
+struct JsonFormat;
+
+impl<S> FormatEvent<S, JsonFields> for JsonFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, JsonFields>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let meta = event.metadata();
+
+        let mut timestamp = String::new();
+        SystemTime.format_time(&mut Writer::new(&mut timestamp))?;
+
+        let mut fields = Map::new();
+        event.record(&mut JsonVisitor(&mut fields));
+
+        let spans: Vec<Value> = ctx
+            .event_scope()
+            .into_iter()
+            .flat_map(|scope| scope.from_root())
+            .map(|span| {
+                let mut object: Map<String, Value> = span
+                    .extensions()
+                    .get::<FormattedFields<JsonFields>>()
+                    .and_then(|f| serde_json::from_str(&f.fields).ok())
+                    .unwrap_or_default();
+                object.insert("name".into(), span.name().into());
+                Value::Object(object)
+            })
+            .collect();
+
+        let mut line = Map::new();
+        line.insert("timestamp".into(), timestamp.into());
+        line.insert("level".into(), meta.level().as_str().into());
+        line.insert("target".into(), meta.target().into());
+        line.insert("fields".into(), Value::Object(fields));
+        if !spans.is_empty() {
+            line.insert("spans".into(), Value::Array(spans));
+        }
+
+        writeln!(writer, "{}", Value::Object(line))
+    }
+}
+
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().into(), format!("{value:?}").into());
+    }
+}
@@ -5,29 +5,92 @@
 // documented in the README, with **standard JSON responses**:
 //
 // - Reads configuration from environment variables
-// - Stdout-first logging
+// - Stdout-first logging (LOG_LEVEL / LOG_FORMAT, optional JSON)
+// - W3C trace propagation with optional OTLP span export
+// - Optional TLS termination with certificate hot reload
+// - Optional JWT bearer (HS256, JWKS) and API key authentication for /api
+// - Optional per-client rate limiting with RateLimit-* headers
+// - Optional concurrency limit with adaptive load shedding
+// - Request timeouts, shortened by client deadline headers
+// - Optional CORS for browser frontends on other origins
 // - Graceful shutdown handling
+// - systemd socket activation and sd_notify readiness (optional)
+// - Zero-downtime binary upgrades on SIGUSR2
 // - Health endpoint
 // - Minimal HTTP endpoints:
 //     GET /      -> JSON Hello World
 //     GET /api   -> JSON Hello API
 //     GET /health -> JSON health status
+//     GET /livez, /readyz, /startupz -> Kubernetes probes
+//     GET /metrics -> Prometheus metrics
+// - Optional admin listener (ADMIN_PORT) that takes over the
+//   operational endpoints, keeping them off the public port
 //
 // This code is intentionally simple, explicit, and production-safe.
 
+mod apikey;
+mod auth;
+mod cli;
+mod clock;
+mod config;
+mod cors;
+mod crypto;
+mod db;
+mod deadline;
+mod error;
+mod extract;
+mod health;
+mod http_client;
+mod listener;
+mod logging;
+mod metrics;
+mod migrate;
+mod overload;
+mod policy;
+mod ratelimit;
+mod request_id;
+mod shutdown;
+mod state;
+#[cfg(unix)]
+mod systemd;
+mod telemetry;
+#[cfg(unix)]
+mod tls;
+#[cfg(unix)]
+mod upgrade;
+
 use axum::{
+    middleware,
     response::IntoResponse,
-    routing::get,
-    Json, Router,
+    routing::{get, MethodRouter},
+    Router,
 };
+use auth::Auth;
+use cli::Command;
+use config::Config;
+use cors::Cors;
+use db::{Database, DbSettings};
+use deadline::RequestTimeout;
+use extract::Json;
+use health::{Health, HealthRegistry};
+use listener::{ListenAddr, Listener};
+use logging::LogFormat;
+use metrics::Metrics;
+use overload::ConcurrencyLimiter;
+use policy::{Guard, Policies, Policy};
+use ratelimit::RateLimiter;
+use request_id::RequestId;
 use serde::Serialize;
+use shutdown::{InFlight, Shutdown};
+use state::AppState;
+use telemetry::Telemetry;
 use std::{
-    env,
     net::SocketAddr,
+    sync::Arc,
     time::Duration,
 };
-use tokio::{net::TcpListener, signal};
-use tracing::{info, warn};
+use tokio::task::JoinHandle;
+use tracing::{error, info, warn};
 
 // --------------------------------------------------
 // Response Models
@@ -38,11 +101,7 @@ struct ApiResponse<T> {
     status: &'static str,
     message: &'static str,
     data: T,
-}
-
-#[derive(Serialize)]
-struct HealthResponse {
-    status: &'static str,
+    request_id: String,
 }
 
 // --------------------------------------------------
@@ -51,120 +110,646 @@ struct HealthResponse {
 
 #[tokio::main]
 async fn main() {
+    let command = match Command::parse(std::env::args().skip(1)) {
+        Ok(command) => command,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, cli::USAGE);
+            std::process::exit(2);
+        }
+    };
+
     // --------------------------------------------------
-    // Init logging FIRST (critical for Docker)
+    // Load configuration, then init logging FIRST thing
+    // (critical for Docker). Config errors are only logged
+    // once the subscriber is installed, using defaults if
+    // the logging variables themselves are invalid.
     // --------------------------------------------------
-    tracing_subscriber::fmt::init();
+
+    let config = Config::from_env();
+
+    match &config {
+        Ok(config) => logging::init(config.log_level.clone(), config.log_format),
+        Err(_) => logging::init(logging::default_level(), LogFormat::default()),
+    }
 
     info!("Booting application");
 
+    let config = match config {
+        Ok(config) => Arc::new(config),
+        Err(err) => {
+            for problem in &err.errors {
+                error!("Configuration error: {}", problem);
+            }
+            std::process::exit(1);
+        }
+    };
+
+    info!("Configuration loaded: {:?}", config);
+
     // --------------------------------------------------
-    // Load environment variables (fail-fast but logged)
+    // Connect to the database (fail-fast, credentials redacted)
     // --------------------------------------------------
 
-    let database_url = match env::var("DATABASE_URL") {
-        Ok(v) => v,
-        Err(_) => {
-            warn!("DATABASE_URL is not set");
+    let db = match Database::connect(DbSettings {
+        url: config.database_url.clone(),
+        max_connections: config.db_pool_max_size,
+        acquire_timeout: config.db_pool_timeout,
+        connect_timeout: config.db_connect_timeout,
+    })
+    .await
+    {
+        Ok(db) => db,
+        Err(err) => {
+            error!(
+                "Database connection to {} failed: {}",
+                db::redact_url(&config.database_url),
+                err
+            );
             std::process::exit(1);
         }
     };
 
-    info!("DATABASE_URL loaded (value hidden)");
+    info!("Connected to {:?} database", db.backend());
 
-    let app_port: u16 = env::var("APP_PORT")
-        .unwrap_or_else(|_| "8080".into())
-        .parse()
-        .unwrap_or(8080);
+    // --------------------------------------------------
+    // Subcommands run against the same config, then exit
+    // --------------------------------------------------
 
-    let shutdown_timeout: u64 = env::var("GRACEFUL_SHUTDOWN_TIMEOUT")
-        .unwrap_or_else(|_| "10".into())
-        .parse()
-        .unwrap_or(10);
+    match command {
+        Command::Serve => {}
+        Command::Migrate(migrate_command) => {
+            if let Err(err) = migrate::run_command(&db, migrate_command).await {
+                error!("Migration command failed: {}", err);
+                std::process::exit(1);
+            }
+            return;
+        }
+        Command::ApiKey(apikey_command) => {
+            // The same schema guarantee the server gives itself at boot.
+            if config.db_auto_migrate
+                && let Err(err) = migrate::up(&db).await
+            {
+                error!("Database migration failed: {}", err);
+                std::process::exit(1);
+            }
+            if let Err(err) = apikey::run_command(&db, apikey_command).await {
+                error!("API key command failed: {}", err);
+                std::process::exit(1);
+            }
+            return;
+        }
+    }
 
     // --------------------------------------------------
-    // Build router
+    // Start HTTP server
     // --------------------------------------------------
 
-    let app = Router::new()
-        .route("/", get(root_handler))
-        .route("/api", get(api_handler))
-        .route("/health", get(health_handler));
+    // Listeners handed over by a previous process (upgrade) or by
+    // systemd socket activation take precedence over binding our own.
+    #[cfg(unix)]
+    let inherited = upgrade::listeners().and_then(|listeners| match listeners {
+        (None, None) => systemd::listeners(),
+        listeners => Ok(listeners),
+    });
+    #[cfg(unix)]
+    let (inherited, inherited_admin) = match inherited {
+        Ok(listeners) => listeners,
+        Err(err) => {
+            error!("Invalid inherited listener: {}", err);
+            std::process::exit(1);
+        }
+    };
+    #[cfg(not(unix))]
+    let (inherited, inherited_admin) = (None, None);
+
+    let listener = match inherited {
+        Some(listener) => listener,
+        None => bind("HTTP", &config.listen).await,
+    };
+
+    #[cfg(unix)]
+    let listener = match &config.tls {
+        None => listener,
+        Some(settings) => {
+            let tls_listener = tls::TlsAcceptor::load(settings.clone()).and_then(|acceptor| {
+                acceptor.spawn_reload();
+                listener.with_tls(acceptor)
+            });
+            match tls_listener {
+                Ok(listener) => {
+                    info!("TLS enabled with {}", settings.cert_file.display());
+                    listener
+                }
+                Err(err) => {
+                    error!("Failed to set up TLS: {}", err);
+                    std::process::exit(1);
+                }
+            }
+        }
+    };
+
+    let admin_listener = match (inherited_admin, config.admin_port) {
+        (Some(listener), _) => Some(listener),
+        (None, Some(admin_port)) => {
+            let admin_addr = ListenAddr::Tcp(SocketAddr::new(config.admin_bind_addr, admin_port));
+            Some(bind("Admin", &admin_addr).await)
+        }
+        (None, None) => None,
+    };
+
+    let telemetry = Telemetry::start(config.otlp.clone());
+    if let Some(otlp) = &config.otlp {
+        info!("Exporting traces to {} ({})", otlp.endpoint, otlp.protocol);
+    }
+
+    let auth = match config.auth.clone() {
+        None => None,
+        Some(settings) => match Auth::start(settings, db.clone()).await {
+            Ok(auth) => {
+                info!("Authentication enabled for API routes");
+                Some(auth)
+            }
+            Err(err) => {
+                error!("Failed to load JWKS: {}", err);
+                std::process::exit(1);
+            }
+        },
+    };
+
+    let policy_file = config.auth.as_ref().and_then(|auth| auth.policy_file.clone());
+    let policies = match Policies::load(policy_file.clone()) {
+        Ok(policies) => policies,
+        Err(err) => {
+            error!("Failed to load route policies: {}", err);
+            std::process::exit(1);
+        }
+    };
+    if let Some(path) = &policy_file {
+        info!(
+            "Loaded {} route policies from {}",
+            policies.rule_count(),
+            path.display()
+        );
+        policies.spawn_reload();
+    }
+
+    let rate_limiter = config.rate_limit.clone().map(|settings| {
+        info!("Rate limiting API routes ({} store)", settings.store);
+        let limiter = RateLimiter::new(settings, config.trusted_proxies.clone(), db.clone());
+        limiter.spawn_cleanup();
+        limiter
+    });
+
+    let concurrency = config.concurrency.clone().map(|settings| {
+        info!(
+            "Limiting concurrent requests to {} ({} mode, queue of {})",
+            settings.max, settings.mode, settings.queue_size
+        );
+        ConcurrencyLimiter::new(settings)
+    });
+
+    let cors = config.cors.clone().map(|settings| {
+        let origins: Vec<_> = settings.origins.iter().map(ToString::to_string).collect();
+        info!("Allowing cross-origin requests from {}", origins.join(", "));
+        Cors::new(settings)
+    });
+
+    let shutdown = Shutdown::new();
+    tokio::spawn(shutdown::shutdown_signal(
+        shutdown.clone(),
+        telemetry.clone(),
+    ));
+
+    let registry = HealthRegistry::new(config.health_check_timeout, config.health_check_cache_ttl);
+
+    let registry = registry.register("database", db::DatabaseCheck(db.clone()));
+
+    #[cfg(unix)]
+    let registry = registry.register(
+        "disk",
+        health::DiskSpaceCheck {
+            path: config.health_disk_path.clone(),
+            min_free_bytes: config.health_disk_min_free_bytes,
+        },
+    );
+
+    let state = AppState {
+        config: config.clone(),
+        db,
+        health: Health::new(shutdown.clone(), registry),
+        in_flight: InFlight::default(),
+        metrics: Metrics::default(),
+        telemetry,
+        auth,
+        policies,
+        rate_limiter,
+        concurrency,
+        cors,
+    };
+
+    #[cfg(unix)]
+    let handoff = upgrade::Handoff {
+        fds: std::iter::once((listener.as_raw_fd(), "http"))
+            .chain(admin_listener.as_ref().map(|l| (l.as_raw_fd(), "admin")))
+            .collect(),
+        timeout: config.upgrade_timeout,
+    };
+
+    // Serve right away so probes answer while the remaining boot
+    // steps run; /startupz and /readyz report 503 until they finish.
+    let mut servers = vec![spawn_server(
+        "HTTP",
+        listener,
+        build_router(state.clone(), admin_listener.is_none()),
+        shutdown.clone(),
+    )];
+
+    if let Some(admin_listener) = admin_listener {
+        servers.push(spawn_server(
+            "Admin",
+            admin_listener,
+            build_admin_router(state.clone()),
+            shutdown.clone(),
+        ));
+    }
+
+    // Only now are all guarded routes known.
+    state.policies.warn_unguarded();
 
     // --------------------------------------------------
-    // Start HTTP server
+    // Boot steps that need the server up (migrations, warmup)
     // --------------------------------------------------
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], app_port));
-    info!("Listening on http://{}", addr);
+    if config.db_auto_migrate {
+        match migrate::up(&state.db).await {
+            Ok(applied) => info!("Database schema up to date ({} applied)", applied.len()),
+            Err(err) => {
+                error!("Database migration failed: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
 
-    let listener = TcpListener::bind(addr)
-        .await
-        .expect("Failed to bind TCP listener");
+    state.health.mark_started();
+    info!("Startup complete");
 
-    if let Err(err) = axum::serve(listener, app)
-        .with_graceful_shutdown(shutdown_signal(shutdown_timeout))
-        .await
+    #[cfg(unix)]
     {
-        warn!("Server terminated: {}", err);
+        systemd::notify("READY=1");
+        systemd::spawn_watchdog();
+        upgrade::notify_ready();
+        tokio::spawn(upgrade::upgrade_signal(shutdown.clone(), handoff));
+    }
+
+    // --------------------------------------------------
+    // Drain: exit as soon as in-flight requests finish,
+    // force-close whatever is left when the timeout hits
+    // --------------------------------------------------
+
+    let servers = async {
+        for server in servers {
+            if let Err(err) = server.await {
+                warn!("Server task failed: {}", err);
+            }
+        }
+    };
+    shutdown::drain(&shutdown, servers, &state.in_flight, config.shutdown_timeout).await;
+
+    // Spans of requests that finished while draining.
+    state.telemetry.flush().await;
+}
+
+// --------------------------------------------------
+// Router
+// --------------------------------------------------
+
+/// Budget of `/`, which does no I/O: anything slower is stuck.
+const ROOT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Build the public application router.
+///
+/// Kept separate from `main` so tests can construct an `AppState`
+/// directly and exercise the routes without binding a socket.
+/// Operational endpoints are included unless an admin listener
+/// serves them instead; they are never subject to the concurrency
+/// limit or request timeouts.
+fn build_router(state: AppState, with_operational: bool) -> Router {
+    let router = Router::new()
+        .route(
+            "/",
+            get(root_handler).route_layer(middleware::from_fn_with_state(
+                RequestTimeout(ROOT_TIMEOUT.min(state.config.request_timeout)),
+                deadline::route_timeout,
+            )),
+        )
+        .merge(api_routes(&state));
+
+    let router = match &state.concurrency {
+        Some(limiter) => router.layer(middleware::from_fn_with_state(
+            limiter.clone(),
+            overload::limit_concurrency,
+        )),
+        None => router,
+    };
+
+    // Outside the concurrency limit: time spent queued counts too.
+    let router = router.layer(middleware::from_fn_with_state(
+        RequestTimeout(state.config.request_timeout),
+        deadline::enforce,
+    ));
+
+    let router = if with_operational {
+        router.merge(operational_routes())
+    } else {
+        router
+    };
+
+    with_middleware(router, state)
+}
+
+/// Application API, behind authentication and rate limiting when
+/// configured. Each route declares its authorization policy with a
+/// `Guard`, enforced when authentication is configured.
+fn api_routes(state: &AppState) -> Router<AppState> {
+    let guarded = |route: MethodRouter<AppState>, name: &str, policy| match &state.auth {
+        Some(_) => route.route_layer(middleware::from_fn_with_state(
+            Guard::new(&state.policies, name, policy),
+            policy::enforce,
+        )),
+        None => route,
+    };
+
+    let router = Router::new().route(
+        "/api",
+        guarded(
+            get(api_handler),
+            "GET /api",
+            Policy::new().scope("greetings:read"),
+        ),
+    );
+
+    // Layered inside authentication, so quotas are per principal.
+    let router = match &state.rate_limiter {
+        Some(limiter) => router.route_layer(middleware::from_fn_with_state(
+            limiter.clone(),
+            ratelimit::limit,
+        )),
+        None => router,
+    };
+
+    let router = match &state.auth {
+        Some(auth) => router.route_layer(middleware::from_fn_with_state(
+            auth.clone(),
+            auth::require_auth,
+        )),
+        None => router,
+    };
+
+    // Outside authentication, so failed attempts are limited by IP.
+    match (&state.auth, &state.rate_limiter) {
+        (Some(_), Some(limiter)) => router.route_layer(middleware::from_fn_with_state(
+            limiter.clone(),
+            ratelimit::limit_auth_failures,
+        )),
+        _ => router,
     }
+}
+
+/// Router for the admin listener: operational endpoints only.
+fn build_admin_router(state: AppState) -> Router {
+    with_middleware(operational_routes(), state)
+}
 
-    info!("Server exited cleanly");
+/// Probes, health and metrics.
+fn operational_routes() -> Router<AppState> {
+    Router::new()
+        .route("/health", get(health::health_handler))
+        .route("/livez", get(health::livez_handler))
+        .route("/readyz", get(health::readyz_handler))
+        .route("/startupz", get(health::startupz_handler))
+        .route("/metrics", get(metrics::metrics_handler))
+}
+
+/// Fallbacks and the middleware stack shared by both listeners.
+fn with_middleware(router: Router<AppState>, state: AppState) -> Router {
+    let router = router
+        .fallback(error::not_found_fallback)
+        .method_not_allowed_fallback(error::method_not_allowed_fallback);
+
+    // Inside error rendering, so rejected preflights get the envelope.
+    let router = match &state.cors {
+        Some(cors) => router.layer(middleware::from_fn_with_state(cors.clone(), cors::handle)),
+        None => router,
+    };
+
+    router
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            error::render_errors,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            shutdown::track_in_flight,
+        ))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            metrics::track_metrics,
+        ))
+        .layer(middleware::from_fn(request_id::propagate_request_id))
+        .layer(middleware::from_fn_with_state(
+            state.clone(),
+            telemetry::trace_requests,
+        ))
+        .with_state(state)
+}
+
+/// Bind `addr`, exiting the process if that fails.
+async fn bind(name: &str, addr: &ListenAddr) -> Listener {
+    match Listener::bind(addr).await {
+        Ok(listener) => {
+            info!("{} listening on {}", name, addr);
+            listener
+        }
+        Err(err) => {
+            error!("Failed to bind {}: {}", addr, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Serve `app` on `listener` until shutdown is triggered.
+fn spawn_server(
+    name: &'static str,
+    listener: Listener,
+    app: Router,
+    shutdown: Shutdown,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        match listener.serve(app, shutdown).await {
+            Ok(()) => info!("{} server exited cleanly", name),
+            Err(err) => warn!("{} server terminated: {}", name, err),
+        }
+    })
 }
 
 // --------------------------------------------------
 // HTTP Handlers
 // --------------------------------------------------
 
-async fn root_handler() -> impl IntoResponse {
+async fn root_handler(RequestId(request_id): RequestId) -> impl IntoResponse {
     Json(ApiResponse {
         status: "success",
         message: "Hello World",
         data: (),
+        request_id,
     })
 }
 
-async fn api_handler() -> impl IntoResponse {
+async fn api_handler(RequestId(request_id): RequestId) -> impl IntoResponse {
     Json(ApiResponse {
         status: "success",
         message: "Hello API",
         data: (),
+        request_id,
     })
 }
 
-/// Healthcheck endpoint
-/// Must be FAST and ALWAYS return 200
-async fn health_handler() -> impl IntoResponse {
-    Json(HealthResponse { status: "ok" })
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::{to_bytes, Body},
+        http::{Request, StatusCode},
+    };
+    use error::ApiError;
+    use request_id::X_REQUEST_ID;
+    use std::collections::HashMap;
+    use tower::ServiceExt;
 
-// --------------------------------------------------
-// Graceful shutdown
-// --------------------------------------------------
+    /// The state `main` would build from `vars`, on an in-memory
+    /// database and without binding anything.
+    async fn state(vars: &[(&str, &str)]) -> AppState {
+        let vars: HashMap<String, String> = [("DATABASE_URL", "sqlite::memory:")]
+            .iter()
+            .chain(vars)
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let config = Arc::new(Config::from_lookup(|key| vars.get(key).cloned()).unwrap());
+        let db = db::memory().await;
+        let registry =
+            HealthRegistry::new(config.health_check_timeout, config.health_check_cache_ttl);
 
-async fn shutdown_signal(timeout_secs: u64) {
-    let ctrl_c = async {
-        signal::ctrl_c()
-            .await
-            .expect("Failed to install Ctrl+C handler");
-    };
+        AppState {
+            config,
+            db,
+            health: Health::new(Shutdown::new(), registry),
+            in_flight: InFlight::default(),
+            metrics: Metrics::default(),
+            telemetry: Telemetry::start(None),
+            auth: None,
+            policies: Policies::load(None).unwrap(),
+            rate_limiter: None,
+            concurrency: None,
+            cors: None,
+        }
+    }
 
-    #[cfg(unix)]
-    let terminate = async {
-        use tokio::signal::unix::{signal, SignalKind};
-        let mut sigterm =
-            signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
-        sigterm.recv().await;
-    };
+    async fn get(app: &Router, path: &str) -> (StatusCode, serde_json::Value) {
+        let request = Request::get(path)
+            .header(&X_REQUEST_ID, "test-request")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.clone().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = serde_json::from_slice(&body).unwrap_or(serde_json::Value::Null);
+        (status, body)
+    }
 
-    #[cfg(not(unix))]
-    let terminate = std::future::pending::<()>();
+    #[tokio::test]
+    async fn hello_routes_answer() {
+        let app = build_router(state(&[]).await, true);
 
-    tokio::select! {
-        _ = ctrl_c => {},
-        _ = terminate => {},
+        let (status, body) = get(&app, "/").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["message"], "Hello World");
+        assert_eq!(body["request_id"], "test-request");
+
+        let (status, body) = get(&app, "/api").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["message"], "Hello API");
+    }
+
+    #[tokio::test]
+    async fn operational_routes_move_to_the_admin_router() {
+        let state = state(&[]).await;
+        let public = build_router(state.clone(), true);
+        assert_eq!(get(&public, "/livez").await.0, StatusCode::OK);
+        assert_eq!(get(&public, "/metrics").await.0, StatusCode::OK);
+
+        let public = build_router(state.clone(), false);
+        assert_eq!(get(&public, "/livez").await.0, StatusCode::NOT_FOUND);
+        assert_eq!(get(&public, "/metrics").await.0, StatusCode::NOT_FOUND);
+
+        let admin = build_admin_router(state);
+        assert_eq!(get(&admin, "/livez").await.0, StatusCode::OK);
+        assert_eq!(get(&admin, "/metrics").await.0, StatusCode::OK);
+        assert_eq!(get(&admin, "/api").await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn operational_routes_bypass_the_concurrency_limit() {
+        let mut state = state(&[("CONCURRENCY_LIMIT", "1"), ("CONCURRENCY_QUEUE_SIZE", "0")]).await;
+        let limiter = ConcurrencyLimiter::new(state.config.concurrency.clone().unwrap());
+        state.concurrency = Some(limiter.clone());
+        let app = build_router(state, true);
+
+        let _running = limiter.acquire().await.unwrap();
+        assert_eq!(get(&app, "/").await.0, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(get(&app, "/api").await.0, StatusCode::SERVICE_UNAVAILABLE);
+        for path in ["/health", "/livez", "/metrics"] {
+            assert_eq!(get(&app, path).await.0, StatusCode::OK, "{path}");
+        }
     }
 
-    info!("Shutdown signal received, draining for {}s", timeout_secs);
-    tokio::time::sleep(Duration::from_secs(timeout_secs)).await;
+    #[tokio::test]
+    async fn request_ids_are_in_every_body() {
+        let app = build_router(state(&[]).await, true);
+        for (path, status) in [("/", StatusCode::OK), ("/nope", StatusCode::NOT_FOUND)] {
+            let (actual, body) = get(&app, path).await;
+            assert_eq!(actual, status);
+            assert_eq!(body["request_id"], "test-request", "{path}");
+        }
+
+        let request = Request::get("/nope")
+            .header(&X_REQUEST_ID, "not valid!")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let id = response.headers()[&X_REQUEST_ID].to_str().unwrap().to_string();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(id.len(), 26);
+        assert_eq!(body["request_id"], id.as_str());
+    }
+
+    #[tokio::test]
+    async fn unknown_routes_and_methods_are_errors() {
+        let app = build_router(state(&[]).await, true);
+
+        let response = app
+            .clone()
+            .oneshot(Request::get("/nope").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        let error = response.extensions().get::<ApiError>().unwrap();
+        assert_eq!(error.details(), Some("No route for GET /nope"));
+
+        let response = app
+            .oneshot(Request::delete("/api").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
 }
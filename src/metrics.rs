@@ -0,0 +1,341 @@
+// ==================================================
+// Prometheus metrics
+// ==================================================
+// RED metrics for every request, labeled by the *matched route*
+// (`/users/:id`, never the raw path) so unknown or parameterised
+// URLs cannot blow up cardinality; requests that match no route
+// share the `unmatched` label. Likewise, methods other than the
+// standard ones (RFC 9110, plus PATCH) are counted as `OTHER`.
+//
+// - http_requests_total{route, method, status}      counter
+// - http_request_errors_total{route, method}        counter (5xx)
+// - http_request_duration_seconds{route, method, status} histogram
+//
+// Process and runtime gauges are sampled at scrape time:
+//
+// - process_resident_memory_bytes, process_open_fds (Linux /proc)
+// - tokio_workers, tokio_alive_tasks, tokio_global_queue_depth
+//
+// Everything is rendered in the Prometheus text exposition format
+// (version 0.0.4) on GET /metrics.
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, HeaderValue, Method},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// Histogram bucket upper bounds, in seconds (Prometheus defaults).
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+const UNMATCHED_ROUTE: &str = "unmatched";
+const OTHER_METHOD: &str = "OTHER";
+
+// --------------------------------------------------
+// Registry
+// --------------------------------------------------
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct SeriesKey {
+    route: String,
+    /// A standard method or `OTHER`.
+    method: &'static str,
+    /// Status class: "2xx", "4xx", ...
+    status: &'static str,
+}
+
+#[derive(Default)]
+struct Series {
+    count: u64,
+    sum_seconds: f64,
+    /// Non-cumulative counts per bucket; the last slot is `+Inf`.
+    buckets: [u64; BUCKETS.len() + 1],
+}
+
+/// Cloneable handle to the in-process metric store.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    series: Arc<Mutex<BTreeMap<SeriesKey, Series>>>,
+}
+
+impl Metrics {
+    fn observe(&self, key: SeriesKey, seconds: f64) {
+        let mut series = self.series.lock().expect("metrics lock poisoned");
+        let entry = series.entry(key).or_default();
+        entry.count += 1;
+        entry.sum_seconds += seconds;
+        let bucket = BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(BUCKETS.len());
+        entry.buckets[bucket] += 1;
+    }
+
+    /// Render every metric in the text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        self.render_http(&mut out);
+        render_process(&mut out);
+        render_runtime(&mut out);
+        out
+    }
+
+    fn render_http(&self, out: &mut String) {
+        let series = self.series.lock().expect("metrics lock poisoned");
+
+        out.push_str("# HELP http_requests_total Total HTTP requests handled.\n");
+        out.push_str("# TYPE http_requests_total counter\n");
+        for (key, s) in series.iter() {
+            let _ = writeln!(out, "http_requests_total{{{}}} {}", labels(key), s.count);
+        }
+
+        // Errors are the 5xx subset, summed across status classes.
+        let mut errors: BTreeMap<(&str, &str), u64> = BTreeMap::new();
+        for (key, s) in series.iter() {
+            let total = errors.entry((&key.route, key.method)).or_default();
+            if key.status == "5xx" {
+                *total += s.count;
+            }
+        }
+        out.push_str(
+            "# HELP http_request_errors_total HTTP requests that failed with a 5xx status.\n",
+        );
+        out.push_str("# TYPE http_request_errors_total counter\n");
+        for ((route, method), count) in errors {
+            let _ = writeln!(
+                out,
+                "http_request_errors_total{{route=\"{}\",method=\"{}\"}} {}",
+                escape(route),
+                method,
+                count
+            );
+        }
+
+        out.push_str("# HELP http_request_duration_seconds HTTP request latency.\n");
+        out.push_str("# TYPE http_request_duration_seconds histogram\n");
+        for (key, s) in series.iter() {
+            let labels = labels(key);
+            let mut cumulative = 0;
+            for (bound, count) in BUCKETS.iter().zip(s.buckets) {
+                cumulative += count;
+                let _ = writeln!(
+                    out,
+                    "http_request_duration_seconds_bucket{{{labels},le=\"{bound}\"}} {cumulative}"
+                );
+            }
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_bucket{{{labels},le=\"+Inf\"}} {}",
+                s.count
+            );
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_sum{{{labels}}} {}",
+                s.sum_seconds
+            );
+            let _ = writeln!(
+                out,
+                "http_request_duration_seconds_count{{{labels}}} {}",
+                s.count
+            );
+        }
+    }
+}
+
+fn labels(key: &SeriesKey) -> String {
+    format!(
+        "route=\"{}\",method=\"{}\",status=\"{}\"",
+        escape(&key.route),
+        key.method,
+        key.status
+    )
+}
+
+/// Escape a label value (backslash, double quote, newline).
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::DELETE => "DELETE",
+        Method::CONNECT => "CONNECT",
+        Method::OPTIONS => "OPTIONS",
+        Method::TRACE => "TRACE",
+        Method::PATCH => "PATCH",
+        _ => OTHER_METHOD,
+    }
+}
+
+fn status_class(status: u16) -> &'static str {
+    match status {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        _ => "5xx",
+    }
+}
+
+// --------------------------------------------------
+// Process and runtime gauges
+// --------------------------------------------------
+
+fn gauge(out: &mut String, name: &str, help: &str, value: impl std::fmt::Display) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} gauge");
+    let _ = writeln!(out, "{name} {value}");
+}
+
+/// RSS and open file descriptors from /proc; omitted where unavailable.
+fn render_process(out: &mut String) {
+    if let Some(rss) = resident_memory_bytes() {
+        gauge(
+            out,
+            "process_resident_memory_bytes",
+            "Resident memory size in bytes.",
+            rss,
+        );
+    }
+    if let Ok(fds) = std::fs::read_dir("/proc/self/fd") {
+        gauge(
+            out,
+            "process_open_fds",
+            "Number of open file descriptors.",
+            fds.count(),
+        );
+    }
+}
+
+#[cfg(unix)]
+fn resident_memory_bytes() -> Option<u64> {
+    // /proc/self/statm: "size resident shared ..." in pages.
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    // SAFETY: sysconf has no preconditions.
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * u64::try_from(page_size).ok()?)
+}
+
+#[cfg(not(unix))]
+fn resident_memory_bytes() -> Option<u64> {
+    None
+}
+
+fn render_runtime(out: &mut String) {
+    let metrics = tokio::runtime::Handle::current().metrics();
+    gauge(
+        out,
+        "tokio_workers",
+        "Number of Tokio worker threads.",
+        metrics.num_workers(),
+    );
+    gauge(
+        out,
+        "tokio_alive_tasks",
+        "Number of Tokio tasks currently alive.",
+        metrics.num_alive_tasks(),
+    );
+    gauge(
+        out,
+        "tokio_global_queue_depth",
+        "Tasks waiting in the Tokio global run queue.",
+        metrics.global_queue_depth(),
+    );
+}
+
+// --------------------------------------------------
+// Middleware and handler
+// --------------------------------------------------
+
+/// Middleware recording count, status class and latency per matched route.
+pub async fn track_metrics(
+    State(metrics): State<Metrics>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or(UNMATCHED_ROUTE, |path| path.as_str())
+        .to_string();
+    let method = method_label(request.method());
+    let started = Instant::now();
+
+    let response = next.run(request).await;
+
+    metrics.observe(
+        SeriesKey {
+            route,
+            method,
+            status: status_class(response.status().as_u16()),
+        },
+        started.elapsed().as_secs_f64(),
+    );
+    response
+}
+
+pub async fn metrics_handler(State(metrics): State<Metrics>) -> impl IntoResponse {
+    (
+        [(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("text/plain; version=0.0.4; charset=utf-8"),
+        )],
+        metrics.render(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware, routing::any, Router};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn nonstandard_methods_share_one_label() {
+        let metrics = Metrics::default();
+        let app = Router::new().route("/items", any(|| async { "ok" })).layer(
+            middleware::from_fn_with_state(metrics.clone(), track_metrics),
+        );
+
+        for method in ["GET", "PATCH", "PURGE", "X-RANDOM-1", "X-RANDOM-2"] {
+            let request = Request::builder()
+                .method(method)
+                .uri("/items")
+                .body(Body::empty())
+                .unwrap();
+            app.clone().oneshot(request).await.unwrap();
+        }
+
+        let rendered = metrics.render();
+        let count = |method: &str| {
+            let series = format!(
+                "http_requests_total{{route=\"/items\",method=\"{method}\",status=\"2xx\"}} "
+            );
+            rendered
+                .lines()
+                .find_map(|line| line.strip_prefix(series.as_str()))
+                .map(|count| count.parse::<u64>().unwrap())
+        };
+        assert_eq!(count("GET"), Some(1));
+        assert_eq!(count("PATCH"), Some(1));
+        assert_eq!(count("OTHER"), Some(3));
+        assert_eq!(count("PURGE"), None);
+    }
+}
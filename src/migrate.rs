@@ -0,0 +1,481 @@
+// ==================================================
+// Schema migrations
+// ==================================================
+// Versioned SQL migrations are compiled into the binary from
+// `migrations/NNNN_name.{up,down}.sql` and listed in `MIGRATIONS`.
+//
+// - Applied automatically at startup unless DB_AUTO_MIGRATE=false
+// - Also runnable with `hello-api migrate up|down|status`
+// - Guarded by a database lock (PostgreSQL advisory lock, SQLite
+//   write transaction) so replicas booting together don't race
+// - Each applied migration's SHA-256 checksum (of both scripts) is
+//   recorded; an applied migration whose files have since been
+//   edited is reported by `status` and blocks `up`
+//
+// Never edit a migration that has shipped: add a new one.
+
+use crate::{
+    cli::MigrateCommand,
+    clock::unix_now,
+    crypto,
+    db::{Backend, Database, DbError},
+};
+use sqlx::{AnyConnection, Row};
+use std::fmt;
+use tracing::info;
+
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub up: &'static str,
+    pub down: &'static str,
+}
+
+impl Migration {
+    /// Covers `down` as well as `up`: an edited rollback script
+    /// would otherwise only be noticed when it runs.
+    fn checksum(&self) -> String {
+        let mut digests = crypto::sha256(self.up.as_bytes()).to_vec();
+        digests.extend_from_slice(&crypto::sha256(self.down.as_bytes()));
+        crypto::to_hex(&crypto::sha256(&digests))
+    }
+
+    fn matches(&self, recorded: &str) -> bool {
+        recorded == self.checksum()
+    }
+}
+
+/// Every migration, in version order.
+pub static MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create_api_keys",
+        up: include_str!("../migrations/0001_create_api_keys.up.sql"),
+        down: include_str!("../migrations/0001_create_api_keys.down.sql"),
+    },
+    Migration {
+        version: 2,
+        name: "create_rate_limits",
+        up: include_str!("../migrations/0002_create_rate_limits.up.sql"),
+        down: include_str!("../migrations/0002_create_rate_limits.down.sql"),
+    },
+];
+
+/// Arbitrary key identifying the migration advisory lock in PostgreSQL.
+const PG_LOCK_KEY: i64 = 0x6865_6c6c_6f5f_6170;
+
+// --------------------------------------------------
+// Status
+// --------------------------------------------------
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum State {
+    Applied,
+    Pending,
+    /// Applied, but the embedded file no longer matches the checksum.
+    Modified,
+    /// Recorded in the database but unknown to this binary.
+    Unknown,
+}
+
+pub struct MigrationStatus {
+    pub version: i64,
+    pub name: String,
+    pub state: State,
+    pub checksum: String,
+}
+
+struct AppliedMigration {
+    version: i64,
+    name: String,
+    checksum: String,
+}
+
+// --------------------------------------------------
+// Commands
+// --------------------------------------------------
+
+/// Entry point for `hello-api migrate ...`.
+pub async fn run_command(db: &Database, command: MigrateCommand) -> Result<(), MigrateError> {
+    match command {
+        MigrateCommand::Up => {
+            let applied = up(db).await?;
+            println!("Applied {} migration(s)", applied.len());
+        }
+        MigrateCommand::Down => match down(db).await? {
+            Some(version) => println!("Rolled back migration {version:04}"),
+            None => println!("No applied migrations to roll back"),
+        },
+        MigrateCommand::Status => {
+            println!("{:<8} {:<9} {:<64} NAME", "VERSION", "STATE", "CHECKSUM");
+            for m in status(db).await? {
+                let state = format!("{:?}", m.state).to_lowercase();
+                println!(
+                    "{:<8} {:<9} {:<64} {}",
+                    format!("{:04}", m.version),
+                    state,
+                    m.checksum,
+                    m.name
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Apply every pending migration, returning the versions applied.
+pub async fn up(db: &Database) -> Result<Vec<i64>, MigrateError> {
+    let mut conn = db.acquire().await?;
+    with_lock(&mut conn, db.backend(), async |conn| {
+        let applied = applied(conn).await?;
+        verify_checksums(&applied)?;
+
+        let mut versions = Vec::new();
+        for migration in MIGRATIONS {
+            if applied.iter().any(|a| a.version == migration.version) {
+                continue;
+            }
+
+            in_transaction(conn, db.backend(), async |conn| {
+                sqlx::raw_sql(migration.up).execute(&mut *conn).await?;
+                sqlx::query(
+                    "INSERT INTO schema_migrations (version, name, checksum, applied_at) \
+                     VALUES ($1, $2, $3, $4)",
+                )
+                .bind(migration.version)
+                .bind(migration.name)
+                .bind(migration.checksum())
+                .bind(unix_now())
+                .execute(&mut *conn)
+                .await?;
+                Ok(())
+            })
+            .await?;
+
+            info!(
+                "Applied migration {:04}_{}",
+                migration.version, migration.name
+            );
+            versions.push(migration.version);
+        }
+        Ok(versions)
+    })
+    .await
+}
+
+/// Roll back the most recently applied migration, if any.
+pub async fn down(db: &Database) -> Result<Option<i64>, MigrateError> {
+    let mut conn = db.acquire().await?;
+    with_lock(&mut conn, db.backend(), async |conn| {
+        let applied = applied(conn).await?;
+        let Some(latest) = applied.last() else {
+            return Ok(None);
+        };
+
+        let migration = MIGRATIONS
+            .iter()
+            .find(|m| m.version == latest.version)
+            .ok_or(MigrateError::Unknown(latest.version))?;
+
+        in_transaction(conn, db.backend(), async |conn| {
+            sqlx::raw_sql(migration.down).execute(&mut *conn).await?;
+            sqlx::query("DELETE FROM schema_migrations WHERE version = $1")
+                .bind(migration.version)
+                .execute(&mut *conn)
+                .await?;
+            Ok(())
+        })
+        .await?;
+
+        info!(
+            "Rolled back migration {:04}_{}",
+            migration.version, migration.name
+        );
+        Ok(Some(migration.version))
+    })
+    .await
+}
+
+/// Every known migration (embedded or recorded) with its state.
+pub async fn status(db: &Database) -> Result<Vec<MigrationStatus>, MigrateError> {
+    let mut conn = db.acquire().await?;
+    ensure_table(&mut conn).await?;
+    let applied = applied(&mut conn).await?;
+    drop(conn);
+
+    let mut statuses: Vec<MigrationStatus> = MIGRATIONS
+        .iter()
+        .map(|m| {
+            let record = applied.iter().find(|a| a.version == m.version);
+            let state = match record {
+                None => State::Pending,
+                Some(a) if m.matches(&a.checksum) => State::Applied,
+                Some(_) => State::Modified,
+            };
+            MigrationStatus {
+                version: m.version,
+                name: m.name.to_string(),
+                state,
+                checksum: m.checksum(),
+            }
+        })
+        .collect();
+
+    for a in applied {
+        if !MIGRATIONS.iter().any(|m| m.version == a.version) {
+            statuses.push(MigrationStatus {
+                version: a.version,
+                name: a.name,
+                state: State::Unknown,
+                checksum: a.checksum,
+            });
+        }
+    }
+
+    statuses.sort_by_key(|s| s.version);
+    Ok(statuses)
+}
+
+// --------------------------------------------------
+// Helpers (run on a single pooled connection)
+// --------------------------------------------------
+
+async fn ensure_table(conn: &mut AnyConnection) -> Result<(), DbError> {
+    sqlx::raw_sql(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+            version BIGINT PRIMARY KEY,
+            name TEXT NOT NULL,
+            checksum TEXT NOT NULL,
+            applied_at BIGINT NOT NULL
+        )",
+    )
+    .execute(conn)
+    .await?;
+    Ok(())
+}
+
+async fn applied(conn: &mut AnyConnection) -> Result<Vec<AppliedMigration>, MigrateError> {
+    let rows =
+        sqlx::query("SELECT version, name, checksum FROM schema_migrations ORDER BY version")
+            .fetch_all(conn)
+            .await
+            .map_err(DbError::from)?;
+
+    rows.iter()
+        .map(|row| {
+            Ok(AppliedMigration {
+                version: row.try_get("version").map_err(DbError::from)?,
+                name: row.try_get("name").map_err(DbError::from)?,
+                checksum: row.try_get("checksum").map_err(DbError::from)?,
+            })
+        })
+        .collect()
+}
+
+fn verify_checksums(applied: &[AppliedMigration]) -> Result<(), MigrateError> {
+    for a in applied {
+        if let Some(m) = MIGRATIONS.iter().find(|m| m.version == a.version)
+            && !m.matches(&a.checksum)
+        {
+            return Err(MigrateError::Modified(a.version));
+        }
+    }
+    Ok(())
+}
+
+/// Hold the migration lock for the duration of `f`.
+///
+/// PostgreSQL uses a session advisory lock; SQLite takes the
+/// database write lock with `BEGIN IMMEDIATE` and commits at the end.
+async fn with_lock<T>(
+    conn: &mut AnyConnection,
+    backend: Backend,
+    f: impl AsyncFnOnce(&mut AnyConnection) -> Result<T, MigrateError>,
+) -> Result<T, MigrateError> {
+    match backend {
+        Backend::Postgres => {
+            // The lock function returns `void`, which the `Any` driver
+            // cannot decode, so the row returned is a constant.
+            sqlx::query("SELECT 1 FROM (SELECT pg_advisory_lock($1)) AS l")
+                .bind(PG_LOCK_KEY)
+                .execute(&mut *conn)
+                .await
+                .map_err(DbError::from)?;
+            let result = match ensure_table(conn).await {
+                Ok(()) => f(conn).await,
+                Err(err) => Err(err.into()),
+            };
+            sqlx::query("SELECT pg_advisory_unlock($1)")
+                .bind(PG_LOCK_KEY)
+                .execute(&mut *conn)
+                .await
+                .map_err(DbError::from)?;
+            result
+        }
+        Backend::Sqlite => {
+            execute(conn, "BEGIN IMMEDIATE").await?;
+            let result = match ensure_table(conn).await {
+                Ok(()) => f(conn).await,
+                Err(err) => Err(err.into()),
+            };
+            match &result {
+                Ok(_) => execute(conn, "COMMIT").await?,
+                Err(_) => execute(conn, "ROLLBACK").await?,
+            }
+            result
+        }
+    }
+}
+
+/// Run one migration atomically. Inside the SQLite lock transaction
+/// a savepoint provides the same all-or-nothing behaviour.
+async fn in_transaction(
+    conn: &mut AnyConnection,
+    backend: Backend,
+    f: impl AsyncFnOnce(&mut AnyConnection) -> Result<(), sqlx::Error>,
+) -> Result<(), MigrateError> {
+    let (begin, commit, rollback) = match backend {
+        Backend::Postgres => ("BEGIN", "COMMIT", "ROLLBACK"),
+        Backend::Sqlite => (
+            "SAVEPOINT migration",
+            "RELEASE migration",
+            "ROLLBACK TO migration; RELEASE migration",
+        ),
+    };
+
+    execute(conn, begin).await?;
+    match f(conn).await {
+        Ok(()) => Ok(execute(conn, commit).await?),
+        Err(err) => {
+            execute(conn, rollback).await?;
+            Err(DbError::from(err).into())
+        }
+    }
+}
+
+/// Run statements that take no parameters.
+async fn execute(conn: &mut AnyConnection, sql: &'static str) -> Result<(), DbError> {
+    sqlx::raw_sql(sql).execute(conn).await?;
+    Ok(())
+}
+
+// --------------------------------------------------
+// Errors
+// --------------------------------------------------
+
+#[derive(Debug)]
+pub enum MigrateError {
+    Db(DbError),
+    Modified(i64),
+    Unknown(i64),
+}
+
+impl From<DbError> for MigrateError {
+    fn from(err: DbError) -> Self {
+        Self::Db(err)
+    }
+}
+
+impl fmt::Display for MigrateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Db(err) => write!(f, "{err}"),
+            Self::Modified(version) => write!(
+                f,
+                "migration {version:04} was edited after being applied (checksum mismatch)"
+            ),
+            Self::Unknown(version) => write!(
+                f,
+                "migration {version:04} is applied but not embedded in this binary"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MigrateError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::memory;
+
+    async fn set_checksum(db: &Database, version: i64, checksum: &str) {
+        db.execute(
+            sqlx::query("UPDATE schema_migrations SET checksum = $1 WHERE version = $2")
+                .bind(checksum)
+                .bind(version),
+        )
+        .await
+        .unwrap();
+    }
+
+    async fn states(db: &Database) -> Vec<(i64, State)> {
+        status(db)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|s| (s.version, s.state))
+            .collect()
+    }
+
+    #[test]
+    fn checksum_covers_both_scripts() {
+        let migration = Migration {
+            version: 1,
+            name: "test",
+            up: "CREATE TABLE t (id BIGINT)",
+            down: "DROP TABLE t",
+        };
+        let edited_down = Migration {
+            down: "DROP TABLE IF EXISTS t",
+            ..migration
+        };
+        assert_ne!(migration.checksum(), edited_down.checksum());
+        assert_eq!(migration.checksum().len(), 64);
+        assert!(migration.matches(&migration.checksum()));
+        assert!(!migration.matches(&edited_down.checksum()));
+        let up_only = crypto::to_hex(&crypto::sha256(migration.up.as_bytes()));
+        assert!(!migration.matches(&up_only));
+    }
+
+    #[tokio::test]
+    async fn up_status_down() {
+        let db = memory().await;
+        assert!(states(&db).await.iter().all(|(_, s)| *s == State::Pending));
+
+        let versions: Vec<i64> = MIGRATIONS.iter().map(|m| m.version).collect();
+        assert_eq!(up(&db).await.unwrap(), versions);
+        assert!(up(&db).await.unwrap().is_empty());
+        assert!(states(&db).await.iter().all(|(_, s)| *s == State::Applied));
+
+        let latest = *versions.last().unwrap();
+        assert_eq!(down(&db).await.unwrap(), Some(latest));
+        assert_eq!(states(&db).await.last(), Some(&(latest, State::Pending)));
+        assert_eq!(up(&db).await.unwrap(), [latest]);
+    }
+
+    #[tokio::test]
+    async fn edited_migrations_block_up() {
+        let db = memory().await;
+        up(&db).await.unwrap();
+        set_checksum(&db, 1, &"0".repeat(64)).await;
+
+        assert_eq!(states(&db).await[0], (1, State::Modified));
+        assert!(matches!(up(&db).await, Err(MigrateError::Modified(1))));
+    }
+
+    #[tokio::test]
+    async fn unknown_migrations_block_down() {
+        let db = memory().await;
+        up(&db).await.unwrap();
+        db.execute(sqlx::query(
+            "INSERT INTO schema_migrations (version, name, checksum, applied_at) \
+             VALUES (9999, 'future', '', 0)",
+        ))
+        .await
+        .unwrap();
+
+        assert_eq!(states(&db).await.last(), Some(&(9999, State::Unknown)));
+        assert!(matches!(down(&db).await, Err(MigrateError::Unknown(9999))));
+    }
+}
@@ -0,0 +1,488 @@
+// ==================================================
+// Overload protection
+// ==================================================
+// A cap on how many application requests run at once. Requests over
+// the cap wait in a bounded queue, and are shed with a fast 503 when
+// the queue is full or they have waited too long, instead of piling
+// up until every request is slow.
+//
+// - CONCURRENCY_LIMIT requests run at once; up to
+//   CONCURRENCY_QUEUE_SIZE more wait, each for at most
+//   CONCURRENCY_QUEUE_TIMEOUT_MS
+// - CONCURRENCY_MODE=aimd adapts the limit to observed latency:
+//   when a request takes longer than CONCURRENCY_TARGET_LATENCY_MS
+//   the limit is cut by 10% (at most once per target latency, and
+//   never below CONCURRENCY_MIN_LIMIT); while requests are fast and
+//   the limit is in use it grows back by about one per limit's worth
+//   of requests, up to CONCURRENCY_LIMIT
+//
+// Only the application routes are limited. `/health`, the probes and
+// `/metrics` always answer, so an overloaded but alive instance is
+// not restarted by its orchestrator.
+
+use crate::error::ApiError;
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{
+    collections::VecDeque,
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::oneshot;
+use tracing::debug;
+
+/// Factor the adaptive limit is multiplied by when latency is too high.
+const BACKOFF: f64 = 0.9;
+
+/// Seconds clients are asked to wait after being shed.
+const RETRY_AFTER_SECS: u32 = 1;
+
+// --------------------------------------------------
+// Settings
+// --------------------------------------------------
+
+/// How the limit is chosen (CONCURRENCY_MODE).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LimitMode {
+    /// Always CONCURRENCY_LIMIT.
+    #[default]
+    Fixed,
+    /// Additive increase, multiplicative decrease on slow requests.
+    Aimd,
+}
+
+impl FromStr for LimitMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "fixed" => Ok(Self::Fixed),
+            "aimd" => Ok(Self::Aimd),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for LimitMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Fixed => "fixed",
+            Self::Aimd => "aimd",
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ConcurrencySettings {
+    /// Requests running at once; the upper bound in AIMD mode.
+    pub max: usize,
+    /// Lower bound in AIMD mode.
+    pub min: usize,
+    pub queue_size: usize,
+    pub queue_timeout: Duration,
+    pub mode: LimitMode,
+    /// Latency above which AIMD mode lowers the limit.
+    pub target_latency: Duration,
+}
+
+// --------------------------------------------------
+// Limiter
+// --------------------------------------------------
+
+/// Cloneable handle to the shared limit and queue.
+#[derive(Clone)]
+pub struct ConcurrencyLimiter {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    settings: ConcurrencySettings,
+    state: Mutex<LimitState>,
+}
+
+struct LimitState {
+    /// Fractional so additive increase can grow it by less than one.
+    limit: f64,
+    in_flight: usize,
+    /// Waiting requests, oldest first. A waiter that gave up leaves a
+    /// closed sender behind, skipped when it reaches the front.
+    queue: VecDeque<oneshot::Sender<Permit>>,
+    last_decrease: Option<Instant>,
+}
+
+impl LimitState {
+    fn effective_limit(&self) -> usize {
+        (self.limit as usize).max(1)
+    }
+}
+
+/// A running request's slot, given back on drop. Queued requests
+/// are handed theirs, so one dropped while waiting frees its slot.
+pub(crate) struct Permit {
+    limiter: ConcurrencyLimiter,
+    started: Instant,
+    /// Cleared on a permit that was never handed over.
+    counted: bool,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if self.counted {
+            self.limiter.release(self.started.elapsed());
+        }
+    }
+}
+
+impl ConcurrencyLimiter {
+    pub fn new(settings: ConcurrencySettings) -> Self {
+        let state = LimitState {
+            limit: settings.max as f64,
+            in_flight: 0,
+            queue: VecDeque::new(),
+            last_decrease: None,
+        };
+        Self {
+            inner: Arc::new(Inner {
+                settings,
+                state: Mutex::new(state),
+            }),
+        }
+    }
+
+    /// Run now, or wait in the queue. `Err` with the reason when the
+    /// request is shed.
+    pub(crate) async fn acquire(&self) -> Result<Permit, &'static str> {
+        let settings = &self.inner.settings;
+        let receiver = {
+            let mut state = self.inner.state.lock().expect("limiter lock poisoned");
+            if state.in_flight < state.effective_limit() && state.queue.is_empty() {
+                state.in_flight += 1;
+                return Ok(self.permit());
+            }
+            if state.queue.len() >= settings.queue_size {
+                state.queue.retain(|waiter| !waiter.is_closed());
+                if state.queue.len() >= settings.queue_size {
+                    return Err("queue full");
+                }
+            }
+            let (sender, receiver) = oneshot::channel();
+            state.queue.push_back(sender);
+            receiver
+        };
+
+        let mut receiver = receiver;
+        match tokio::time::timeout(settings.queue_timeout, &mut receiver).await {
+            Ok(Ok(permit)) => Ok(permit),
+            Ok(Err(_)) => Err("queue closed"),
+            Err(_) => timed_out(receiver),
+        }
+    }
+
+    fn permit(&self) -> Permit {
+        Permit {
+            limiter: self.clone(),
+            started: Instant::now(),
+            counted: true,
+        }
+    }
+
+    fn release(&self, latency: Duration) {
+        let settings = &self.inner.settings;
+        let mut state = self.inner.state.lock().expect("limiter lock poisoned");
+
+        if settings.mode == LimitMode::Aimd {
+            let before = state.effective_limit();
+            if latency > settings.target_latency {
+                let now = Instant::now();
+                if state
+                    .last_decrease
+                    .is_none_or(|at| now.duration_since(at) >= settings.target_latency)
+                {
+                    state.limit = (state.limit * BACKOFF).max(settings.min as f64);
+                    state.last_decrease = Some(now);
+                }
+            } else if state.in_flight * 2 >= state.effective_limit() {
+                state.limit = (state.limit + 1.0 / state.limit).min(settings.max as f64);
+            }
+            if state.effective_limit() != before {
+                debug!(
+                    "Concurrency limit now {} (latency {:?})",
+                    state.effective_limit(),
+                    latency
+                );
+            }
+        }
+
+        // Hand the slot (and any the limit grew by) to waiters.
+        state.in_flight -= 1;
+        while state.in_flight < state.effective_limit() {
+            let Some(waiter) = state.queue.pop_front() else {
+                break;
+            };
+            match waiter.send(self.permit()) {
+                Ok(()) => state.in_flight += 1,
+                Err(mut permit) => permit.counted = false,
+            }
+        }
+    }
+}
+
+/// A slot may have been handed over just as the wait ran out; use it
+/// rather than give it back unused. Once closed, `release` skips the
+/// waiter.
+fn timed_out(mut receiver: oneshot::Receiver<Permit>) -> Result<Permit, &'static str> {
+    receiver.close();
+    receiver.try_recv().map_err(|_| "queue timeout")
+}
+
+// --------------------------------------------------
+// Middleware
+// --------------------------------------------------
+
+/// Admit, queue or shed the request.
+pub async fn limit_concurrency(
+    State(limiter): State<ConcurrencyLimiter>,
+    request: Request,
+    next: Next,
+) -> Response {
+    match limiter.acquire().await {
+        Ok(permit) => {
+            let response = next.run(request).await;
+            drop(permit);
+            response
+        }
+        Err(reason) => {
+            debug!(
+                "Shed {} {} ({})",
+                request.method(),
+                request.uri().path(),
+                reason
+            );
+            let mut response =
+                ApiError::Unavailable("Server is overloaded, try again later".to_string())
+                    .into_response();
+            response
+                .headers_mut()
+                .insert(header::RETRY_AFTER, HeaderValue::from(RETRY_AFTER_SECS));
+            response
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::StatusCode, middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    fn limiter(max: usize, queue_size: usize) -> ConcurrencyLimiter {
+        ConcurrencyLimiter::new(ConcurrencySettings {
+            max,
+            min: 1,
+            queue_size,
+            queue_timeout: Duration::from_secs(5),
+            mode: LimitMode::Fixed,
+            target_latency: Duration::from_millis(100),
+        })
+    }
+
+    fn aimd(min: usize, max: usize) -> ConcurrencyLimiter {
+        ConcurrencyLimiter::new(ConcurrencySettings {
+            min,
+            mode: LimitMode::Aimd,
+            ..limiter(max, 0).inner.settings.clone()
+        })
+    }
+
+    fn state(limiter: &ConcurrencyLimiter) -> std::sync::MutexGuard<'_, LimitState> {
+        limiter.inner.state.lock().unwrap()
+    }
+
+    /// Queue a request, returning once it is waiting.
+    async fn enqueue(
+        limiter: &ConcurrencyLimiter,
+    ) -> tokio::task::JoinHandle<Result<Permit, &'static str>> {
+        let waiting = |limiter| {
+            let state = state(limiter);
+            state.queue.iter().filter(|w| !w.is_closed()).count()
+        };
+        let queued = waiting(limiter);
+        let waiter = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.acquire().await }
+        });
+        while waiting(limiter) == queued {
+            tokio::task::yield_now().await;
+        }
+        waiter
+    }
+
+    /// Release `permit` as if its request had taken `latency`.
+    fn finish(mut permit: Permit, latency: Duration) {
+        permit.started = Instant::now() - latency;
+    }
+
+    #[tokio::test]
+    async fn requests_under_the_limit_run_at_once() {
+        let limiter = limiter(2, 0);
+        let first = limiter.acquire().await.unwrap();
+        let _second = limiter.acquire().await.unwrap();
+        assert_eq!(state(&limiter).in_flight, 2);
+        assert_eq!(limiter.acquire().await.err(), Some("queue full"));
+
+        drop(first);
+        assert_eq!(state(&limiter).in_flight, 1);
+        assert!(limiter.acquire().await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn full_queues_shed() {
+        let limiter = limiter(1, 1);
+        let _running = limiter.acquire().await.unwrap();
+        let waiter = enqueue(&limiter).await;
+        assert_eq!(limiter.acquire().await.err(), Some("queue full"));
+
+        // A waiter that gave up leaves its place to the next.
+        waiter.abort();
+        let _ = waiter.await;
+        let _next = enqueue(&limiter).await;
+        assert_eq!(state(&limiter).queue.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn released_permits_go_to_waiters() {
+        let limiter = limiter(1, 2);
+        let running = limiter.acquire().await.unwrap();
+        let waiter = enqueue(&limiter).await;
+
+        drop(running);
+        let permit = waiter.await.unwrap().unwrap();
+        assert_eq!(state(&limiter).in_flight, 1);
+        assert!(state(&limiter).queue.is_empty());
+
+        drop(permit);
+        assert_eq!(state(&limiter).in_flight, 0);
+    }
+
+    #[tokio::test]
+    async fn waiters_that_gave_up_are_skipped() {
+        let limiter = limiter(1, 2);
+        let running = limiter.acquire().await.unwrap();
+        let gone = enqueue(&limiter).await;
+        let waiting = enqueue(&limiter).await;
+        gone.abort();
+        let _ = gone.await;
+
+        drop(running);
+        let permit = waiting.await.unwrap().unwrap();
+        assert_eq!(state(&limiter).in_flight, 1);
+
+        drop(permit);
+        assert_eq!(state(&limiter).in_flight, 0);
+    }
+
+    #[tokio::test]
+    async fn waits_time_out() {
+        let limiter = ConcurrencyLimiter::new(ConcurrencySettings {
+            queue_timeout: Duration::from_millis(20),
+            ..limiter(1, 1).inner.settings.clone()
+        });
+        let running = limiter.acquire().await.unwrap();
+        assert_eq!(limiter.acquire().await.err(), Some("queue timeout"));
+
+        // The timed out waiter is not handed the slot.
+        drop(running);
+        assert_eq!(state(&limiter).in_flight, 0);
+        assert!(state(&limiter).queue.is_empty());
+    }
+
+    #[tokio::test]
+    async fn slots_handed_over_as_the_wait_ends_are_used() {
+        let limiter = limiter(1, 1);
+        let running = limiter.acquire().await.unwrap();
+
+        let (sender, receiver) = oneshot::channel();
+        state(&limiter).queue.push_back(sender);
+        drop(running);
+        let permit = timed_out(receiver).unwrap();
+        assert_eq!(state(&limiter).in_flight, 1);
+
+        drop(permit);
+        let (sender, receiver) = oneshot::channel();
+        state(&limiter).queue.push_back(sender);
+        assert_eq!(timed_out(receiver).err(), Some("queue timeout"));
+    }
+
+    #[tokio::test]
+    async fn aimd_backs_off_on_slow_requests() {
+        let limiter = aimd(2, 10);
+        let slow = Duration::from_millis(500);
+
+        finish(limiter.acquire().await.unwrap(), slow);
+        assert_eq!(state(&limiter).effective_limit(), 9);
+        // At most once per target latency.
+        finish(limiter.acquire().await.unwrap(), slow);
+        assert_eq!(state(&limiter).effective_limit(), 9);
+
+        for _ in 0..50 {
+            state(&limiter).last_decrease = None;
+            finish(limiter.acquire().await.unwrap(), slow);
+        }
+        assert_eq!(state(&limiter).effective_limit(), 2);
+    }
+
+    #[tokio::test]
+    async fn aimd_grows_back_while_the_limit_is_in_use() {
+        let limiter = aimd(2, 10);
+
+        // Idle: a lone fast request does not raise the limit.
+        state(&limiter).limit = 8.0;
+        finish(limiter.acquire().await.unwrap(), Duration::ZERO);
+        assert_eq!(state(&limiter).limit, 8.0);
+
+        state(&limiter).limit = 2.0;
+        let free = |state: &LimitState| state.in_flight < state.effective_limit();
+        let mut running = Vec::new();
+        for _ in 0..500 {
+            while free(&state(&limiter)) {
+                running.push(limiter.acquire().await.unwrap());
+            }
+            finish(running.pop().unwrap(), Duration::ZERO);
+        }
+        assert_eq!(state(&limiter).limit, 10.0);
+    }
+
+    async fn shed_response(limiter: ConcurrencyLimiter) -> Response {
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(limiter, limit_concurrency));
+        let request = axum::http::Request::get("/").body(Body::empty()).unwrap();
+        app.oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn shed_requests_get_a_503_with_retry_after() {
+        let busy = ConcurrencyLimiter::new(ConcurrencySettings {
+            queue_timeout: Duration::from_millis(20),
+            ..limiter(1, 1).inner.settings.clone()
+        });
+        assert_eq!(shed_response(busy.clone()).await.status(), StatusCode::OK);
+
+        let _running = busy.acquire().await.unwrap();
+        let response = shed_response(busy).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+
+        let full = limiter(1, 0);
+        let _running = full.acquire().await.unwrap();
+        let response = shed_response(full).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+    }
+}
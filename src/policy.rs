@@ -0,0 +1,464 @@
+// ==================================================
+// Authorization policies
+// ==================================================
+// Routes declare what a caller needs beyond being authenticated
+// when the router is built, with a `Guard` layer naming the method
+// and route pattern it protects:
+//
+//   .route(
+//       "/api/greetings",
+//       post(create_greeting).route_layer(middleware::from_fn_with_state(
+//           Guard::new(
+//               &policies,
+//               "POST /api/greetings",
+//               Policy::new().scope("greetings:write"),
+//           ),
+//           policy::enforce,
+//       )),
+//   )
+//
+// A policy lists scopes and roles; the caller's `Principal` must
+// hold every one of them. Requests without credentials get a 401,
+// callers missing a permission a 403, logged with the principal,
+// route and what was missing.
+//
+// AUTH_POLICY_FILE (JSON) lets ops change requirements without a
+// rebuild. Entries are keyed by method (in any case; `*` matches any
+// method) and route pattern as given to the guard, and replace the
+// in-code policy of that route:
+//
+//   {
+//     "GET /api": { "scopes": ["greetings:read"] },
+//     "* /api/admin/:id": { "roles": ["admin"] }
+//   }
+//
+// Entries that match no guarded route have no effect and are logged
+// as warnings. The file is checked for changes every 10 seconds; one
+// that fails to load is logged and the previous rules stay in effect.
+
+use crate::{auth::Principal, error::ApiError};
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime},
+};
+use tracing::{error, info, warn};
+
+/// How often AUTH_POLICY_FILE is checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+// --------------------------------------------------
+// Policy
+// --------------------------------------------------
+
+/// Permissions a route requires. An empty policy only requires
+/// authentication.
+#[derive(Clone, Debug, Default)]
+pub struct Policy {
+    scopes: Vec<String>,
+    roles: Vec<String>,
+}
+
+impl Policy {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Require an OAuth scope (API key scopes count too).
+    pub fn scope(mut self, scope: &str) -> Self {
+        self.scopes.push(scope.to_string());
+        self
+    }
+
+    /// Require a role from the token's `roles` claim.
+    pub fn role(mut self, role: &str) -> Self {
+        self.roles.push(role.to_string());
+        self
+    }
+
+    fn is_empty(&self) -> bool {
+        self.scopes.is_empty() && self.roles.is_empty()
+    }
+
+    /// What `principal` lacks, e.g. `["scope greetings:write"]`.
+    fn missing(&self, principal: &Principal) -> Vec<String> {
+        let scopes = self
+            .scopes
+            .iter()
+            .filter(|scope| !principal.scopes.contains(scope))
+            .map(|scope| format!("scope {scope}"));
+        let roles = self
+            .roles
+            .iter()
+            .filter(|role| !principal.roles.contains(role))
+            .map(|role| format!("role {role}"));
+        scopes.chain(roles).collect()
+    }
+}
+
+// --------------------------------------------------
+// File overrides
+// --------------------------------------------------
+
+/// One AUTH_POLICY_FILE entry.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Rule {
+    #[serde(default)]
+    scopes: Vec<String>,
+    #[serde(default)]
+    roles: Vec<String>,
+}
+
+impl From<Rule> for Policy {
+    fn from(rule: Rule) -> Self {
+        let policy = rule.scopes.iter().fold(Policy::new(), |p, s| p.scope(s));
+        rule.roles.iter().fold(policy, |p, r| p.role(r))
+    }
+}
+
+/// Cloneable handle to the AUTH_POLICY_FILE rules, keyed by
+/// `METHOD /path` with the method in uppercase.
+#[derive(Clone, Default)]
+pub struct Policies {
+    file: Option<PathBuf>,
+    overrides: Arc<RwLock<HashMap<String, Arc<Policy>>>>,
+    /// Routes with a `Guard`, as `METHOD /path`.
+    guarded: Arc<Mutex<BTreeSet<String>>>,
+}
+
+impl Policies {
+    /// Read the policy file, if any.
+    pub fn load(file: Option<PathBuf>) -> Result<Self, String> {
+        let policies = Self {
+            overrides: Arc::new(RwLock::new(match &file {
+                Some(path) => read_file(path)?,
+                None => HashMap::new(),
+            })),
+            file,
+            guarded: Arc::default(),
+        };
+        Ok(policies)
+    }
+
+    /// Number of routes the file has rules for.
+    pub fn rule_count(&self) -> usize {
+        self.overrides.read().expect("policy lock poisoned").len()
+    }
+
+    /// Re-read the file whenever it changes.
+    pub fn spawn_reload(&self) {
+        let Some(path) = self.file.clone() else {
+            return;
+        };
+        let policies = self.clone();
+        tokio::spawn(async move {
+            let stamp = || {
+                fs::metadata(&path)
+                    .ok()
+                    .and_then(|meta| Some((meta.modified().ok()?, meta.len())))
+            };
+            let mut loaded: Option<(SystemTime, u64)> = stamp();
+            let mut ticker = tokio::time::interval(RELOAD_INTERVAL);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let current = stamp();
+                if current == loaded {
+                    continue;
+                }
+                loaded = current;
+                match read_file(&path) {
+                    Ok(rules) => {
+                        info!(
+                            "Reloaded {} route policies from {}",
+                            rules.len(),
+                            path.display()
+                        );
+                        *policies.overrides.write().expect("policy lock poisoned") = rules;
+                        policies.warn_unguarded();
+                    }
+                    Err(err) => error!("Policy reload failed, keeping the old rules: {}", err),
+                }
+            }
+        });
+    }
+
+    /// Log the file rules that match no guarded route, which would
+    /// otherwise be silently ignored. Call once the routers are built.
+    pub fn warn_unguarded(&self) {
+        for rule in self.unguarded() {
+            warn!(
+                "Policy rule {:?} matches no guarded route and has no effect",
+                rule
+            );
+        }
+    }
+
+    fn unguarded(&self) -> Vec<String> {
+        let guarded = self.guarded.lock().expect("policy lock poisoned");
+        let overrides = self.overrides.read().expect("policy lock poisoned");
+        let mut unguarded: Vec<String> = overrides
+            .keys()
+            .filter(|rule| match rule.split_once(' ') {
+                Some(("*", path)) => !guarded
+                    .iter()
+                    .any(|route| route.split_once(' ').is_some_and(|(_, p)| p == path)),
+                _ => !guarded.contains(rule.as_str()),
+            })
+            .cloned()
+            .collect();
+        unguarded.sort();
+        unguarded
+    }
+
+    fn get(&self, route: &str) -> Option<Arc<Policy>> {
+        let overrides = self.overrides.read().expect("policy lock poisoned");
+        let (_, path) = route.split_once(' ')?;
+        overrides
+            .get(route)
+            .or_else(|| overrides.get(&format!("* {path}")))
+            .cloned()
+    }
+}
+
+/// `method /path` with the method in uppercase, or `None` if `route`
+/// is not in that form.
+fn normalize_route(route: &str) -> Option<String> {
+    match route.trim().split_once(' ') {
+        Some((method, path)) if !method.is_empty() && path.trim_start().starts_with('/') => Some(
+            format!("{} {}", method.to_ascii_uppercase(), path.trim_start()),
+        ),
+        _ => None,
+    }
+}
+
+fn read_file(path: &Path) -> Result<HashMap<String, Arc<Policy>>, String> {
+    let raw = fs::read(path).map_err(|err| format!("{}: {err}", path.display()))?;
+    let rules: HashMap<String, Rule> =
+        serde_json::from_slice(&raw).map_err(|err| format!("{}: {err}", path.display()))?;
+
+    rules
+        .into_iter()
+        .map(|(route, rule)| match normalize_route(&route) {
+            Some(route) => Ok((route, Arc::new(Policy::from(rule)))),
+            None => Err(format!(
+                "{}: route {route:?} must be \"METHOD /path\"",
+                path.display()
+            )),
+        })
+        .collect()
+}
+
+// --------------------------------------------------
+// Middleware
+// --------------------------------------------------
+
+/// A route's declared policy, plus the file rules that may replace it.
+#[derive(Clone)]
+pub struct Guard {
+    policies: Policies,
+    /// `METHOD /path`, as file rules are keyed.
+    route: Arc<str>,
+    policy: Arc<Policy>,
+}
+
+impl Guard {
+    /// Guard `route` (`GET /api/greetings`), the method and pattern
+    /// of the route this layer is applied to.
+    ///
+    /// # Panics
+    ///
+    /// If `route` is not in `METHOD /path` form.
+    pub fn new(policies: &Policies, route: &str, policy: Policy) -> Self {
+        let route = normalize_route(route)
+            .unwrap_or_else(|| panic!("guarded route {route:?} must be \"METHOD /path\""));
+        policies
+            .guarded
+            .lock()
+            .expect("policy lock poisoned")
+            .insert(route.clone());
+        Self {
+            policies: policies.clone(),
+            route: route.into(),
+            policy: Arc::new(policy),
+        }
+    }
+}
+
+/// Enforce the route's policy against the request's `Principal`.
+pub async fn enforce(State(guard): State<Guard>, request: Request, next: Next) -> Response {
+    let route = &guard.route;
+    let policy = guard
+        .policies
+        .get(route)
+        .unwrap_or_else(|| guard.policy.clone());
+
+    if policy.is_empty() {
+        return next.run(request).await;
+    }
+    let Some(principal) = request.extensions().get::<Principal>() else {
+        warn!("Denied unauthenticated request to {}", route);
+        return ApiError::Unauthorized("Authentication required".to_string()).into_response();
+    };
+
+    let missing = policy.missing(principal);
+    if !missing.is_empty() {
+        warn!(
+            "Denied {} access to {}: missing {}",
+            principal,
+            route,
+            missing.join(", ")
+        );
+        return ApiError::Forbidden(format!("Missing {}", missing.join(", "))).into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Credential;
+    use axum::{body::Body, middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    fn principal(scopes: &[&str], roles: &[&str]) -> Principal {
+        Principal {
+            id: "alice".to_string(),
+            credential: Credential::Token,
+            scopes: scopes.iter().map(ToString::to_string).collect(),
+            roles: roles.iter().map(ToString::to_string).collect(),
+        }
+    }
+
+    /// `GET /api` guarded by `policy`, called as `caller`.
+    async fn call(policies: &Policies, policy: Policy, caller: Option<Principal>) -> Response {
+        let app = Router::new()
+            .route(
+                "/api",
+                get(|| async {}).route_layer(middleware::from_fn_with_state(
+                    Guard::new(policies, "GET /api", policy),
+                    enforce,
+                )),
+            )
+            .layer(middleware::from_fn(
+                move |mut request: Request, next: Next| {
+                    if let Some(caller) = caller.clone() {
+                        request.extensions_mut().insert(caller);
+                    }
+                    next.run(request)
+                },
+            ));
+        let request = Request::get("/api").body(Body::empty()).unwrap();
+        app.oneshot(request).await.unwrap()
+    }
+
+    fn details(response: &Response) -> Option<&str> {
+        response.extensions().get::<ApiError>()?.details()
+    }
+
+    /// Policies read from a file with `rules`.
+    fn from_file(name: &str, rules: &str) -> Result<Policies, String> {
+        let path =
+            std::env::temp_dir().join(format!("hello-api-policy-{}-{name}", std::process::id()));
+        fs::write(&path, rules).unwrap();
+        let policies = Policies::load(Some(path.clone()));
+        let _ = fs::remove_file(path);
+        policies
+    }
+
+    #[tokio::test]
+    async fn callers_with_every_permission_are_allowed() {
+        let policy = Policy::new().scope("greetings:read").role("admin");
+        let caller = principal(&["greetings:read", "other"], &["admin"]);
+        let response = call(&Policies::default(), policy, Some(caller)).await;
+        assert_eq!(response.status(), 200);
+
+        let anyone = principal(&[], &[]);
+        let response = call(&Policies::default(), Policy::new(), Some(anyone)).await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[tokio::test]
+    async fn missing_permissions_are_denied() {
+        let policy = || Policy::new().scope("greetings:read").role("admin");
+
+        let caller = principal(&["greetings:read"], &[]);
+        let response = call(&Policies::default(), policy(), Some(caller)).await;
+        assert_eq!(response.status(), 403);
+        assert_eq!(details(&response), Some("Missing role admin"));
+
+        let caller = principal(&[], &["user"]);
+        let response = call(&Policies::default(), policy(), Some(caller)).await;
+        assert_eq!(response.status(), 403);
+        assert_eq!(
+            details(&response),
+            Some("Missing scope greetings:read, role admin")
+        );
+
+        let response = call(&Policies::default(), policy(), None).await;
+        assert_eq!(response.status(), 401);
+        assert_eq!(details(&response), Some("Authentication required"));
+    }
+
+    #[tokio::test]
+    async fn file_rules_replace_the_declared_policy() {
+        // Methods match in any case.
+        let policies = from_file("replace", r#"{ "get /api": { "roles": ["admin"] } }"#).unwrap();
+        let reader = || principal(&["greetings:read"], &[]);
+        let response = call(
+            &policies,
+            Policy::new().scope("greetings:read"),
+            Some(reader()),
+        )
+        .await;
+        assert_eq!(response.status(), 403);
+        assert_eq!(details(&response), Some("Missing role admin"));
+
+        // `*` covers every method; an empty rule only needs a caller.
+        let policies = from_file("relax", r#"{ "* /api": {} }"#).unwrap();
+        let nobody = principal(&[], &[]);
+        let response = call(&policies, Policy::new().role("admin"), Some(nobody)).await;
+        assert_eq!(response.status(), 200);
+    }
+
+    #[test]
+    fn rules_for_unguarded_routes_are_reported() {
+        let policies = from_file(
+            "unguarded",
+            r#"{
+                "GET /api": {},
+                "* /api": {},
+                "post /api": {},
+                "* /api/admin": {}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(policies.unguarded().len(), 4);
+
+        Guard::new(&policies, "get /api", Policy::new());
+        assert_eq!(policies.unguarded(), ["* /api/admin", "POST /api"]);
+    }
+
+    #[test]
+    fn malformed_files_are_rejected() {
+        let err = from_file("path", r#"{ "/api": {} }"#).err().unwrap();
+        assert!(
+            err.ends_with(r#"route "/api" must be "METHOD /path""#),
+            "{err}"
+        );
+
+        let err = from_file("field", r#"{ "GET /api": { "scope": ["a"] } }"#)
+            .err()
+            .unwrap();
+        assert!(err.contains("unknown field `scope`"), "{err}");
+    }
+}
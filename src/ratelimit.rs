@@ -0,0 +1,838 @@
+// ==================================================
+// Rate limiting
+// ==================================================
+// Per-client request quotas on the API routes, enforced with GCRA
+// (the generic cell rate algorithm: a token bucket that stores one
+// timestamp per client instead of a count and a refill time).
+//
+// - Clients are told apart by API key, JWT subject or, without
+//   credentials, IP address (see `extract::client_ip` for
+//   TRUSTED_PROXIES and X-Forwarded-For)
+// - With authentication configured, requests that fail it count
+//   against a quota of their IP address, checked before the
+//   credentials are: guessing keys or tokens is limited too
+// - RATE_LIMIT is the quota every client gets across the API, e.g.
+//   `100/m`. RATE_LIMIT_ROUTES gives routes a quota of their own,
+//   keyed like AUTH_POLICY_FILE rules:
+//   `GET /api=10/s, * /api/admin/:id=5/m`
+// - Responses on limited routes carry `RateLimit-Limit`,
+//   `RateLimit-Remaining`, `RateLimit-Reset` and `RateLimit-Policy`
+//   (draft-ietf-httpapi-ratelimit-headers); rejected requests get a
+//   429 with `Retry-After`
+//
+// Quotas are tracked per process unless RATE_LIMIT_STORE=database,
+// which keeps them in the `rate_limits` table so that replicas share
+// them, at the cost of a database write per request. If that write
+// fails the request is let through: a database outage should not
+// also take down every API route.
+
+use crate::{
+    auth::Principal,
+    clock::unix_millis,
+    db::{Database, DbError},
+    deadline::{self, Deadline},
+    error::ApiError,
+    extract,
+    listener::IpNet,
+};
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, HeaderName, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sqlx::Row;
+use std::{
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tracing::{debug, warn};
+
+/// How often state of clients whose quota is full again is dropped.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+const RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+const RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+const RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+const RATELIMIT_POLICY: HeaderName = HeaderName::from_static("ratelimit-policy");
+
+// --------------------------------------------------
+// Settings
+// --------------------------------------------------
+
+/// `limit` requests per `period`, as a burst or spread out.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quota {
+    pub limit: u32,
+    pub period: Duration,
+}
+
+impl Quota {
+    fn period_ms(&self) -> i64 {
+        self.period.as_millis() as i64
+    }
+
+    /// Time one request uses up; the quota refills at this rate.
+    fn interval_ms(&self) -> i64 {
+        (self.period_ms() / i64::from(self.limit)).max(1)
+    }
+}
+
+/// `<limit>/<period>`, the period being `s`, `m`, `h` or `d`,
+/// optionally with a count: `100/m`, `10/s`, `500/15m`. State is
+/// kept in whole milliseconds, so at most 1000 requests a second.
+impl FromStr for Quota {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (limit, period) = s.trim().split_once('/').ok_or(())?;
+        let limit: u32 = limit.trim().parse().map_err(|_| ())?;
+
+        let period = period.trim();
+        let unit = period.chars().last().ok_or(())?;
+        let count = &period[..period.len() - unit.len_utf8()];
+        let count: u64 = if count.is_empty() {
+            1
+        } else {
+            count.parse().map_err(|_| ())?
+        };
+        let unit_secs = match unit {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86_400,
+            _ => return Err(()),
+        };
+
+        let secs = count.checked_mul(unit_secs).ok_or(())?;
+        if limit == 0 || count == 0 || u64::from(limit) > secs.saturating_mul(1000) {
+            return Err(());
+        }
+        Ok(Self {
+            limit,
+            period: Duration::from_secs(secs),
+        })
+    }
+}
+
+/// Where quota state is kept (RATE_LIMIT_STORE).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StoreKind {
+    /// Per process; replicas each allow the full quota.
+    #[default]
+    Memory,
+    /// The `rate_limits` table, shared by every replica.
+    Database,
+}
+
+impl FromStr for StoreKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "memory" => Ok(Self::Memory),
+            "database" => Ok(Self::Database),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for StoreKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Memory => "memory",
+            Self::Database => "database",
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RateLimitSettings {
+    /// Quota for routes without their own (RATE_LIMIT).
+    pub default: Option<Quota>,
+    /// Per-route quotas keyed `METHOD /pattern` (RATE_LIMIT_ROUTES).
+    pub routes: HashMap<String, Quota>,
+    pub store: StoreKind,
+}
+
+/// RATE_LIMIT_ROUTES: comma-separated `METHOD /pattern=<quota>`.
+pub fn parse_routes(raw: &str) -> Result<HashMap<String, Quota>, String> {
+    raw.split(',')
+        .filter(|entry| !entry.trim().is_empty())
+        .map(|entry| {
+            let (route, quota) = entry.rsplit_once('=').ok_or_else(|| {
+                format!("expected \"METHOD /path=<quota>\", got {:?}", entry.trim())
+            })?;
+            let route = route.split_whitespace().collect::<Vec<_>>().join(" ");
+            match route.split_once(' ') {
+                Some((_, pattern)) if pattern.starts_with('/') => {}
+                _ => return Err(format!("route {route:?} must be \"METHOD /path\"")),
+            }
+            let quota = quota
+                .parse()
+                .map_err(|_| format!("invalid quota for {route}: {:?}", quota.trim()))?;
+            Ok((route, quota))
+        })
+        .collect()
+}
+
+// --------------------------------------------------
+// Limiter
+// --------------------------------------------------
+
+/// Cloneable handle to the quotas and their state.
+#[derive(Clone)]
+pub struct RateLimiter {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    settings: RateLimitSettings,
+    trusted_proxies: Vec<IpNet>,
+    store: Store,
+}
+
+enum Store {
+    /// Theoretical arrival time of each client's next request.
+    Memory(Mutex<HashMap<String, i64>>),
+    Database(Database),
+}
+
+/// The outcome of one request against a quota.
+struct Decision {
+    quota: Quota,
+    allowed: bool,
+    remaining: u32,
+    /// Seconds until the full quota is available again.
+    reset: u64,
+    /// Seconds until a rejected request may be retried.
+    retry_after: u64,
+}
+
+impl Decision {
+    /// From the client's theoretical arrival time (`tat`): after the
+    /// request when it was allowed, as it stands when it was not.
+    fn new(quota: Quota, now: i64, tat: i64, allowed: bool) -> Self {
+        let ahead = (tat - now).max(0);
+        let remaining = if allowed {
+            (quota.period_ms() - ahead) / quota.interval_ms()
+        } else {
+            0
+        };
+        let retry_after = if allowed {
+            0
+        } else {
+            ahead + quota.interval_ms() - quota.period_ms()
+        };
+        Self {
+            quota,
+            allowed,
+            remaining: remaining.clamp(0, i64::from(quota.limit)) as u32,
+            reset: ceil_secs(ahead),
+            retry_after: ceil_secs(retry_after),
+        }
+    }
+
+    fn apply(&self, response: &mut Response) {
+        let headers = response.headers_mut();
+        headers.insert(RATELIMIT_LIMIT, HeaderValue::from(self.quota.limit));
+        headers.insert(RATELIMIT_REMAINING, HeaderValue::from(self.remaining));
+        headers.insert(RATELIMIT_RESET, HeaderValue::from(self.reset));
+        if let Ok(policy) = HeaderValue::from_str(&format!(
+            "{};w={}",
+            self.quota.limit,
+            self.quota.period.as_secs()
+        )) {
+            headers.insert(RATELIMIT_POLICY, policy);
+        }
+        if !self.allowed {
+            headers.insert(header::RETRY_AFTER, HeaderValue::from(self.retry_after));
+        }
+    }
+}
+
+impl RateLimiter {
+    pub fn new(settings: RateLimitSettings, trusted_proxies: Vec<IpNet>, db: Database) -> Self {
+        let store = match settings.store {
+            StoreKind::Memory => Store::Memory(Mutex::new(HashMap::new())),
+            StoreKind::Database => Store::Database(db),
+        };
+        Self {
+            inner: Arc::new(Inner {
+                settings,
+                trusted_proxies,
+                store,
+            }),
+        }
+    }
+
+    /// Periodically forget clients whose quota has fully refilled;
+    /// their state is the same as having none.
+    pub fn spawn_cleanup(&self) {
+        let limiter = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(CLEANUP_INTERVAL);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let now = unix_millis();
+                match &limiter.inner.store {
+                    Store::Memory(tats) => tats
+                        .lock()
+                        .expect("rate limit lock poisoned")
+                        .retain(|_, tat| *tat > now),
+                    Store::Database(db) => {
+                        let result = db
+                            .execute(
+                                sqlx::query("DELETE FROM rate_limits WHERE tat <= $1").bind(now),
+                            )
+                            .await;
+                        if let Err(err) = result {
+                            warn!("Failed to clean up rate limits: {}", err);
+                        }
+                    }
+                }
+            }
+        });
+    }
+
+    /// The quota for a route and the name its state is kept under:
+    /// the route for its own quota, `*` for the shared default.
+    fn quota(&self, method: &str, path: &str) -> Option<(String, Quota)> {
+        let settings = &self.inner.settings;
+        [format!("{method} {path}"), format!("* {path}")]
+            .into_iter()
+            .find_map(|route| settings.routes.get(&route).map(|quota| (route, *quota)))
+            .or_else(|| settings.default.map(|quota| ("*".to_string(), quota)))
+    }
+
+    /// Count one request by `key` against `quota`, with database
+    /// calls bounded by the request's `deadline`.
+    async fn acquire(
+        &self,
+        key: String,
+        quota: Quota,
+        deadline: Option<&Deadline>,
+    ) -> Result<Decision, DbError> {
+        let now = unix_millis();
+        let interval = quota.interval_ms();
+
+        match &self.inner.store {
+            Store::Memory(tats) => {
+                let mut tats = tats.lock().expect("rate limit lock poisoned");
+                let tat = tats.get(&key).map_or(now, |tat| (*tat).max(now));
+                if tat + interval - now > quota.period_ms() {
+                    return Ok(Decision::new(quota, now, tat, false));
+                }
+                tats.insert(key, tat + interval);
+                Ok(Decision::new(quota, now, tat + interval, true))
+            }
+            Store::Database(db) => {
+                let db = deadline::bounded(db, deadline);
+                // One atomic statement: the row is only written (and
+                // returned) when the request fits in the quota.
+                let updated = db
+                    .fetch_optional(
+                        sqlx::query(
+                            "INSERT INTO rate_limits (key, tat) VALUES ($1, $2 + $3) \
+                             ON CONFLICT (key) DO UPDATE SET tat = \
+                               (CASE WHEN rate_limits.tat > $2 THEN rate_limits.tat ELSE $2 END) + $3 \
+                             WHERE (CASE WHEN rate_limits.tat > $2 THEN rate_limits.tat ELSE $2 END) \
+                                   + $3 - $2 <= $4 \
+                             RETURNING tat",
+                        )
+                        .bind(key.clone())
+                        .bind(now)
+                        .bind(interval)
+                        .bind(quota.period_ms()),
+                    )
+                    .await?;
+                if let Some(row) = updated {
+                    return Ok(Decision::new(quota, now, row.try_get("tat")?, true));
+                }
+
+                let current = db
+                    .fetch_optional(
+                        sqlx::query("SELECT tat FROM rate_limits WHERE key = $1").bind(key),
+                    )
+                    .await?;
+                let tat = match current {
+                    Some(row) => row.try_get("tat")?,
+                    None => now,
+                };
+                Ok(Decision::new(quota, now, tat, false))
+            }
+        }
+    }
+
+    /// Where `key` stands against `quota` without counting a request:
+    /// `Some` with the rejection when it is over quota.
+    async fn check(
+        &self,
+        key: &str,
+        quota: Quota,
+        deadline: Option<&Deadline>,
+    ) -> Result<Option<Decision>, DbError> {
+        let now = unix_millis();
+        let tat = match &self.inner.store {
+            Store::Memory(tats) => tats
+                .lock()
+                .expect("rate limit lock poisoned")
+                .get(key)
+                .copied(),
+            Store::Database(db) => {
+                let row = deadline::bounded(db, deadline)
+                    .fetch_optional(
+                        sqlx::query("SELECT tat FROM rate_limits WHERE key = $1")
+                            .bind(key.to_string()),
+                    )
+                    .await?;
+                row.map(|row| row.try_get("tat")).transpose()?
+            }
+        };
+        let tat = tat.map_or(now, |tat| tat.max(now));
+        Ok((tat + quota.interval_ms() - now > quota.period_ms())
+            .then(|| Decision::new(quota, now, tat, false)))
+    }
+
+    /// Who a request counts against: its principal when it has one,
+    /// otherwise its IP address.
+    fn client_key(&self, request: &Request) -> String {
+        if let Some(principal) = request.extensions().get::<Principal>()
+            && principal.id != "-"
+        {
+            return principal.to_string();
+        }
+        self.ip_key(request)
+    }
+
+    fn ip_key(&self, request: &Request) -> String {
+        match extract::client_ip(
+            request.headers(),
+            request.extensions(),
+            &self.inner.trusted_proxies,
+        ) {
+            Some(ip) => format!("ip:{ip}"),
+            None => "ip:unknown".to_string(),
+        }
+    }
+}
+
+/// `METHOD /pattern` of the request's route.
+fn route_of(request: &Request) -> (String, String) {
+    let method = request.method().to_string();
+    let path = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| request.uri().path(), MatchedPath::as_str)
+        .to_string();
+    (method, path)
+}
+
+// --------------------------------------------------
+// Middleware
+// --------------------------------------------------
+
+/// Count the request against its client's quota for the route.
+pub async fn limit(State(limiter): State<RateLimiter>, request: Request, next: Next) -> Response {
+    let (method, path) = route_of(&request);
+    let Some((rule, quota)) = limiter.quota(&method, &path) else {
+        return next.run(request).await;
+    };
+    let route = format!("{method} {path}");
+    let client = limiter.client_key(&request);
+    let deadline = request.extensions().get::<Deadline>().cloned();
+
+    let decision = match limiter
+        .acquire(format!("{rule}|{client}"), quota, deadline.as_ref())
+        .await
+    {
+        Ok(decision) => decision,
+        Err(err) => {
+            warn!(
+                "Rate limit check failed, letting the request through: {}",
+                err
+            );
+            return next.run(request).await;
+        }
+    };
+
+    let mut response = if decision.allowed {
+        next.run(request).await
+    } else {
+        debug!("Rate limited {} on {}", client, route);
+        ApiError::TooManyRequests(format!(
+            "Rate limit exceeded, retry in {} seconds",
+            decision.retry_after
+        ))
+        .into_response()
+    };
+    decision.apply(&mut response);
+    response
+}
+
+/// Outside authentication: turn away IP addresses that are over the
+/// route's quota of failed authentications, and count each request
+/// that fails it (401). Successful requests are not counted here.
+pub async fn limit_auth_failures(
+    State(limiter): State<RateLimiter>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let (method, path) = route_of(&request);
+    let Some((rule, quota)) = limiter.quota(&method, &path) else {
+        return next.run(request).await;
+    };
+    let client = limiter.ip_key(&request);
+    let key = format!("{rule}|auth-failures|{client}");
+    let deadline = request.extensions().get::<Deadline>().cloned();
+
+    match limiter.check(&key, quota, deadline.as_ref()).await {
+        Ok(Some(rejection)) => {
+            debug!(
+                "Rate limited {} on {} {} after failed authentications",
+                client, method, path
+            );
+            let mut response = ApiError::TooManyRequests(format!(
+                "Too many failed authentications, retry in {} seconds",
+                rejection.retry_after
+            ))
+            .into_response();
+            rejection.apply(&mut response);
+            return response;
+        }
+        Ok(None) => {}
+        Err(err) => warn!(
+            "Rate limit check failed, letting the request through: {}",
+            err
+        ),
+    }
+
+    let mut response = next.run(request).await;
+    if response.status() == StatusCode::UNAUTHORIZED {
+        match limiter.acquire(key, quota, deadline.as_ref()).await {
+            Ok(decision) => decision.apply(&mut response),
+            Err(err) => warn!("Failed to count a failed authentication: {}", err),
+        }
+    }
+    response
+}
+
+fn ceil_secs(ms: i64) -> u64 {
+    (ms.max(0) as u64).div_ceil(1000)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        auth::{Credential, Principal},
+        db, migrate,
+    };
+    use axum::{body::Body, extract::ConnectInfo, middleware, routing::get, Router};
+    use std::net::SocketAddr;
+    use tower::ServiceExt;
+
+    async fn migrated() -> Database {
+        let db = db::memory().await;
+        migrate::up(&db).await.unwrap();
+        db
+    }
+
+    fn quota(limit: u32, secs: u64) -> Quota {
+        Quota {
+            limit,
+            period: Duration::from_secs(secs),
+        }
+    }
+
+    async fn limiter(settings: RateLimitSettings) -> RateLimiter {
+        RateLimiter::new(settings, Vec::new(), migrated().await)
+    }
+
+    #[test]
+    fn quotas_are_parsed() {
+        assert_eq!("100/m".parse(), Ok(quota(100, 60)));
+        assert_eq!(" 10 / s ".parse(), Ok(quota(10, 1)));
+        assert_eq!("500/15m".parse(), Ok(quota(500, 900)));
+        assert_eq!("5/2h".parse(), Ok(quota(5, 7200)));
+        assert_eq!("1/d".parse(), Ok(quota(1, 86_400)));
+        assert_eq!("1000/s".parse(), Ok(quota(1000, 1)));
+        assert_eq!("2000/2s".parse(), Ok(quota(2000, 2)));
+
+        for invalid in [
+            "",
+            "100",
+            "100/",
+            "/m",
+            "0/m",
+            "-1/m",
+            "x/m",
+            "10/0m",
+            "10/w",
+            "10/xm",
+            "10/m/s",
+            "1001/s",
+            "2001/2s",
+            "1/99999999999999999d",
+        ] {
+            assert_eq!(invalid.parse::<Quota>(), Err(()), "{invalid:?}");
+        }
+    }
+
+    #[test]
+    fn routes_are_parsed() {
+        let routes = parse_routes("GET /api=10/s, *   /api/admin/:id = 5/m,,").unwrap();
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes["GET /api"], quota(10, 1));
+        assert_eq!(routes["* /api/admin/:id"], quota(5, 60));
+        assert!(parse_routes("").unwrap().is_empty());
+
+        for (raw, error) in [
+            (
+                "GET /api",
+                "expected \"METHOD /path=<quota>\", got \"GET /api\"",
+            ),
+            ("/api=1/s", "route \"/api\" must be \"METHOD /path\""),
+            ("GET api=1/s", "route \"GET api\" must be \"METHOD /path\""),
+            ("GET /api=fast", "invalid quota for GET /api: \"fast\""),
+            ("GET /api=2000/s", "invalid quota for GET /api: \"2000/s\""),
+        ] {
+            assert_eq!(parse_routes(raw), Err(error.to_string()), "{raw:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn route_quotas_take_precedence() {
+        let settings = RateLimitSettings {
+            default: Some(quota(100, 60)),
+            routes: parse_routes("GET /api=10/s, * /api/admin/:id=5/m").unwrap(),
+            store: StoreKind::Memory,
+        };
+        let limiter = limiter(settings.clone()).await;
+        let route = |method, path| limiter.quota(method, path);
+
+        assert_eq!(
+            route("GET", "/api"),
+            Some(("GET /api".to_string(), quota(10, 1)))
+        );
+        assert_eq!(
+            route("POST", "/api"),
+            Some(("*".to_string(), quota(100, 60)))
+        );
+        assert_eq!(
+            route("DELETE", "/api/admin/:id"),
+            Some(("* /api/admin/:id".to_string(), quota(5, 60)))
+        );
+        assert_eq!(
+            route("GET", "/other"),
+            Some(("*".to_string(), quota(100, 60)))
+        );
+
+        let limiter = RateLimiter::new(
+            RateLimitSettings {
+                default: None,
+                ..settings
+            },
+            Vec::new(),
+            migrated().await,
+        );
+        assert_eq!(limiter.quota("POST", "/api"), None);
+        assert!(limiter.quota("GET", "/api").is_some());
+    }
+
+    #[test]
+    fn decisions_count_down_the_quota() {
+        // 10/m: one request every 6 seconds.
+        let quota = quota(10, 60);
+        let now = 1_000_000;
+
+        let first = Decision::new(quota, now, now + 6_000, true);
+        assert_eq!((first.remaining, first.reset, first.retry_after), (9, 6, 0));
+
+        let last = Decision::new(quota, now, now + 60_000, true);
+        assert_eq!((last.remaining, last.reset, last.retry_after), (0, 60, 0));
+
+        let rejected = Decision::new(quota, now, now + 60_000, false);
+        assert_eq!(
+            (rejected.remaining, rejected.reset, rejected.retry_after),
+            (0, 60, 6)
+        );
+
+        // Part of the way to the next request: rounded up.
+        let rejected = Decision::new(quota, now, now + 55_500, false);
+        assert_eq!((rejected.reset, rejected.retry_after), (56, 2));
+    }
+
+    /// `GET /api` behind a stand-in for `require_auth` that accepts
+    /// `Authorization: ok`, with failures limited to 2/m.
+    async fn app(store: StoreKind) -> Router {
+        let limiter = RateLimiter::new(
+            RateLimitSettings {
+                default: "2/m".parse().ok(),
+                routes: HashMap::new(),
+                store,
+            },
+            Vec::new(),
+            migrated().await,
+        );
+        let auth = |request: Request, next: Next| async move {
+            match request.headers().get(header::AUTHORIZATION) {
+                Some(value) if value == "ok" => next.run(request).await,
+                _ => ApiError::Unauthorized("Invalid bearer token".to_string()).into_response(),
+            }
+        };
+        Router::new()
+            .route("/api", get(|| async {}))
+            .route_layer(middleware::from_fn(auth))
+            .route_layer(middleware::from_fn_with_state(limiter, limit_auth_failures))
+    }
+
+    async fn call(app: &Router, ip: [u8; 4], credentials: &str) -> Response {
+        let mut request = Request::get("/api")
+            .header(header::AUTHORIZATION, credentials)
+            .body(Body::empty())
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from((ip, 40000))));
+        app.clone().oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn failed_authentications_are_limited_by_ip() {
+        for store in [StoreKind::Memory, StoreKind::Database] {
+            let app = app(store).await;
+            let attacker = [192, 0, 2, 1];
+
+            let response = call(&app, attacker, "guess-1").await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(response.headers()[RATELIMIT_REMAINING], "1");
+            let response = call(&app, attacker, "guess-2").await;
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+            assert_eq!(response.headers()[RATELIMIT_REMAINING], "0");
+
+            // Over quota: turned away before the credentials are checked.
+            for credentials in ["guess-3", "ok"] {
+                let response = call(&app, attacker, credentials).await;
+                assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS, "{store}");
+                assert_eq!(response.headers()[header::RETRY_AFTER], "30");
+            }
+
+            // Other addresses are unaffected.
+            let response = call(&app, [192, 0, 2, 2], "ok").await;
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+    }
+
+    #[tokio::test]
+    async fn successful_authentications_are_not_counted() {
+        let app = app(StoreKind::Memory).await;
+        for _ in 0..5 {
+            let response = call(&app, [192, 0, 2, 1], "ok").await;
+            assert_eq!(response.status(), StatusCode::OK);
+            assert!(!response.headers().contains_key(RATELIMIT_REMAINING));
+        }
+        let response = call(&app, [192, 0, 2, 1], "guess").await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+
+    /// `GET /api` limited to 2/m.
+    async fn limited(store: StoreKind) -> Router {
+        let limiter = limiter(RateLimitSettings {
+            default: Some(quota(2, 60)),
+            routes: HashMap::new(),
+            store,
+        })
+        .await;
+        Router::new()
+            .route("/api", get(|| async {}))
+            .route_layer(middleware::from_fn_with_state(limiter, limit))
+    }
+
+    async fn call_as(app: &Router, ip: [u8; 4], principal: Option<&str>) -> Response {
+        let mut request = Request::get("/api").body(Body::empty()).unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from((ip, 40000))));
+        if let Some(id) = principal {
+            request.extensions_mut().insert(Principal {
+                id: id.to_string(),
+                credential: Credential::Token,
+                scopes: Vec::new(),
+                roles: Vec::new(),
+            });
+        }
+        app.clone().oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn requests_over_quota_are_rejected() {
+        for store in [StoreKind::Memory, StoreKind::Database] {
+            let app = limited(store).await;
+            let client = [192, 0, 2, 1];
+
+            let response = call_as(&app, client, None).await;
+            assert_eq!(response.status(), StatusCode::OK, "{store}");
+            let headers = response.headers();
+            assert_eq!(headers[RATELIMIT_LIMIT], "2");
+            assert_eq!(headers[RATELIMIT_REMAINING], "1");
+            assert_eq!(headers[RATELIMIT_RESET], "30");
+            assert_eq!(headers[RATELIMIT_POLICY], "2;w=60");
+            assert!(!headers.contains_key(header::RETRY_AFTER));
+
+            let response = call_as(&app, client, None).await;
+            assert_eq!(response.status(), StatusCode::OK, "{store}");
+            assert_eq!(response.headers()[RATELIMIT_REMAINING], "0");
+            assert_eq!(response.headers()[RATELIMIT_RESET], "60");
+
+            let response = call_as(&app, client, None).await;
+            assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS, "{store}");
+            let headers = response.headers();
+            assert_eq!(headers[RATELIMIT_REMAINING], "0");
+            assert_eq!(headers[RATELIMIT_RESET], "60");
+            assert_eq!(headers[header::RETRY_AFTER], "30");
+            let error = response.extensions().get::<ApiError>().unwrap();
+            assert_eq!(
+                error.details(),
+                Some("Rate limit exceeded, retry in 30 seconds")
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn principals_and_addresses_have_their_own_quotas() {
+        let app = limited(StoreKind::Memory).await;
+        let shared_ip = [192, 0, 2, 1];
+        for _ in 0..2 {
+            call_as(&app, shared_ip, None).await;
+        }
+
+        // Authenticated clients behind the same address are apart.
+        for principal in ["alice", "bob"] {
+            for _ in 0..2 {
+                let response = call_as(&app, shared_ip, Some(principal)).await;
+                assert_eq!(response.status(), StatusCode::OK, "{principal}");
+            }
+        }
+        assert_eq!(
+            call_as(&app, shared_ip, None).await.status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+
+        // A principal is one client from wherever it calls.
+        assert_eq!(
+            call_as(&app, [198, 51, 100, 7], Some("alice"))
+                .await
+                .status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(
+            call_as(&app, [198, 51, 100, 7], None).await.status(),
+            StatusCode::OK
+        );
+    }
+}
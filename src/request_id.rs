@@ -0,0 +1,186 @@
+// ==================================================
+// Request IDs
+// ==================================================
+// Every request gets an ID that ties together the client's view
+// and the server's logs:
+//
+// - An incoming `X-Request-Id` is reused when it looks sane
+//   (1-128 chars of `A-Z a-z 0-9 - _ . :`), otherwise a new ULID
+//   is generated
+// - The ID (and the trace ID, see telemetry.rs) is recorded on a
+//   `request` tracing span wrapping the whole request, so every log
+//   line carries it
+// - It is echoed in the `X-Request-Id` response header and in the
+//   `request_id` field of JSON response bodies
+
+use crate::{clock, crypto, error::ApiError, telemetry::TraceContext};
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request},
+    http::{request::Parts, HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use tracing::{info_span, Instrument};
+
+pub static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+const MAX_LEN: usize = 128;
+
+/// The current request's ID, available as an extractor.
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for RequestId {
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<RequestId>()
+            .cloned()
+            .ok_or_else(|| ApiError::Internal("request id middleware is not installed".into()))
+    }
+}
+
+/// Middleware assigning the request ID and the per-request span.
+pub async fn propagate_request_id(mut request: Request, next: Next) -> Response {
+    let id = request
+        .headers()
+        .get(&X_REQUEST_ID)
+        .and_then(|v| v.to_str().ok())
+        .filter(|v| is_valid(v))
+        .map_or_else(new_ulid, str::to_string);
+
+    request.extensions_mut().insert(RequestId(id.clone()));
+
+    let span = info_span!(
+        "request",
+        request_id = %id,
+        method = %request.method(),
+        path = %request.uri().path(),
+        trace_id = tracing::field::Empty,
+        user = tracing::field::Empty,
+    );
+    if let Some(context) = request.extensions().get::<TraceContext>() {
+        span.record("trace_id", crypto::to_hex(&context.trace_id));
+    }
+
+    let mut response = next.run(request).instrument(span).await;
+
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(X_REQUEST_ID.clone(), value);
+    }
+    response
+}
+
+fn is_valid(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_LEN
+        && id
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b':'))
+}
+
+/// A ULID: 48-bit millisecond timestamp + 80 random bits, encoded as
+/// 26 characters of Crockford base32 (lexicographically sortable).
+fn new_ulid() -> String {
+    const ALPHABET: &[u8; 32] = b"0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+
+    let millis = clock::unix_millis() as u64 & 0xFFFF_FFFF_FFFF;
+
+    let mut random = [0u8; 10];
+    crypto::fill_random(&mut random);
+
+    let mut value = u128::from(millis) << 80;
+    for (i, byte) in random.iter().enumerate() {
+        value |= u128::from(*byte) << (72 - 8 * i);
+    }
+
+    (0..26)
+        .rev()
+        .map(|i| ALPHABET[((value >> (5 * i)) & 0x1F) as usize] as char)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware, routing::get, Router};
+    use tower::ServiceExt;
+
+    /// The ID the handler saw and the one sent back.
+    async fn call(id: Option<&str>) -> (String, String) {
+        let app = Router::new()
+            .route("/", get(|RequestId(id): RequestId| async move { id }))
+            .layer(middleware::from_fn(propagate_request_id));
+        let mut request = Request::get("/");
+        if let Some(id) = id {
+            request = request.header(&X_REQUEST_ID, id);
+        }
+        let response = app
+            .oneshot(request.body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let echoed = response.headers()[&X_REQUEST_ID]
+            .to_str()
+            .unwrap()
+            .to_string();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (String::from_utf8(body.to_vec()).unwrap(), echoed)
+    }
+
+    fn assert_ulid(id: &str) {
+        const ALPHABET: &str = "0123456789ABCDEFGHJKMNPQRSTVWXYZ";
+        assert_eq!(id.len(), 26, "{id}");
+        assert!(id.chars().all(|c| ALPHABET.contains(c)), "{id}");
+        // The first 10 characters are the timestamp.
+        let millis = id[..10]
+            .chars()
+            .fold(0i64, |acc, c| acc * 32 + ALPHABET.find(c).unwrap() as i64);
+        assert!((clock::unix_millis() - millis).abs() < 60_000, "{id}");
+    }
+
+    #[tokio::test]
+    async fn valid_ids_are_kept() {
+        let long = "a".repeat(MAX_LEN);
+        for id in [
+            "abc-123",
+            "req_1.2:3",
+            "01HZY3C8ZQ2D6ZQ1V3X7R9T5KM",
+            long.as_str(),
+        ] {
+            assert_eq!(call(Some(id)).await, (id.to_string(), id.to_string()));
+        }
+    }
+
+    #[tokio::test]
+    async fn invalid_ids_are_replaced() {
+        let long = "a".repeat(MAX_LEN + 1);
+        for id in [
+            None,
+            Some(""),
+            Some(long.as_str()),
+            Some("a b"),
+            Some("a/b"),
+            Some("é"),
+        ] {
+            let (seen, echoed) = call(id).await;
+            assert_ulid(&seen);
+            assert_eq!(seen, echoed);
+        }
+    }
+
+    #[test]
+    fn ulids_sort_by_time() {
+        let first = new_ulid();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let second = new_ulid();
+        assert_ulid(&first);
+        assert!(first < second);
+        assert_ne!(new_ulid(), new_ulid());
+    }
+}
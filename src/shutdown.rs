@@ -0,0 +1,284 @@
+// ==================================================
+// Graceful shutdown
+// ==================================================
+// Shutdown happens in two phases:
+//
+// 1. A signal (SIGTERM / Ctrl+C) triggers `Shutdown`. The server
+//    stops accepting connections immediately and lets in-flight
+//    requests finish; it exits as soon as the last one completes.
+// 2. If requests are still running once GRACEFUL_SHUTDOWN_TIMEOUT
+//    has elapsed, the remaining connections are force-closed.
+//
+// Queued trace spans are flushed as soon as the signal arrives, and
+// systemd (if any) is told the service is stopping.
+//
+// `InFlight` counts requests currently being handled so the
+// number dropped by a forced close can be reported.
+
+use crate::telemetry::Telemetry;
+use axum::{
+    extract::{Request, State},
+    middleware::Next,
+    response::Response,
+};
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{signal, sync::watch};
+use tracing::{info, warn};
+
+// --------------------------------------------------
+// Shutdown trigger
+// --------------------------------------------------
+
+/// Cloneable handle shared by everything that reacts to shutdown.
+#[derive(Clone)]
+pub struct Shutdown {
+    tx: Arc<watch::Sender<bool>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (tx, _) = watch::channel(false);
+        Self { tx: Arc::new(tx) }
+    }
+
+    /// Start shutting down. Calling this more than once is harmless.
+    pub fn trigger(&self) {
+        self.tx.send_replace(true);
+    }
+
+    /// Whether shutdown (and therefore draining) has started.
+    pub fn is_triggered(&self) -> bool {
+        *self.tx.borrow()
+    }
+
+    /// Resolves once `trigger` has been called (immediately if it already was).
+    pub async fn triggered(&self) {
+        let mut rx = self.tx.subscribe();
+        // The sender lives as long as `self`, so this cannot fail.
+        let _ = rx.wait_for(|triggered| *triggered).await;
+    }
+
+    /// Resolves `timeout` after shutdown was triggered: the point at
+    /// which remaining connections are force-closed.
+    pub async fn drain_deadline(&self, timeout: Duration) {
+        self.triggered().await;
+        tokio::time::sleep(timeout).await;
+    }
+}
+
+/// Wait for `servers` to finish, force-closing whatever is left once
+/// `timeout` has passed since shutdown was triggered. Returns the
+/// number of requests dropped when the timeout cut the drain short.
+pub async fn drain(
+    shutdown: &Shutdown,
+    servers: impl Future<Output = ()>,
+    in_flight: &InFlight,
+    timeout: Duration,
+) -> Option<usize> {
+    tokio::select! {
+        _ = servers => None,
+        _ = shutdown.drain_deadline(timeout) => {
+            let dropped = in_flight.count();
+            warn!(
+                "Graceful shutdown timed out after {}s, dropping {} in-flight request(s)",
+                timeout.as_secs(),
+                dropped
+            );
+            Some(dropped)
+        }
+    }
+}
+
+/// Wait for SIGTERM or Ctrl+C, then trigger `shutdown` and flush
+/// pending trace spans.
+pub async fn shutdown_signal(shutdown: Shutdown, telemetry: Telemetry) {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("Failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut sigterm =
+            signal(SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+        sigterm.recv().await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    info!("Shutdown signal received, no longer accepting connections");
+    #[cfg(unix)]
+    crate::systemd::notify("STOPPING=1");
+    shutdown.trigger();
+    telemetry.flush().await;
+}
+
+// --------------------------------------------------
+// In-flight request tracking
+// --------------------------------------------------
+
+#[derive(Clone, Default)]
+pub struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+/// Decrements the counter on drop, so aborted requests are released too.
+struct InFlightGuard(Arc<AtomicUsize>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Middleware counting every request for as long as it is being handled.
+pub async fn track_in_flight(
+    State(in_flight): State<InFlight>,
+    request: Request,
+    next: Next,
+) -> Response {
+    in_flight.0.fetch_add(1, Ordering::SeqCst);
+    let _guard = InFlightGuard(in_flight.0);
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, middleware, routing::get, Router};
+    use std::time::Instant;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+        task::JoinHandle,
+    };
+    use tower::ServiceExt;
+
+    /// `GET /` taking `delay`, counted by `in_flight`.
+    fn app(in_flight: &InFlight, delay: Duration) -> Router {
+        Router::new()
+            .route("/", get(move || tokio::time::sleep(delay)))
+            .layer(middleware::from_fn_with_state(
+                in_flight.clone(),
+                track_in_flight,
+            ))
+    }
+
+    /// Serve `app` until `shutdown` and its requests are done.
+    async fn serve(app: Router, shutdown: &Shutdown) -> (JoinHandle<()>, u16) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let shutdown = shutdown.clone();
+        let server = tokio::spawn(async move {
+            axum::serve(listener, app)
+                .with_graceful_shutdown(async move { shutdown.triggered().await })
+                .await
+                .unwrap();
+        });
+        (server, port)
+    }
+
+    /// Send `GET /` and read the response, once the server has it.
+    async fn request(port: u16, in_flight: &InFlight) -> JoinHandle<String> {
+        let before = in_flight.count();
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+            stream
+                .write_all(b"GET / HTTP/1.1\r\nHost: test\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = vec![0; 1024];
+            let n = stream.read(&mut response).await.unwrap();
+            String::from_utf8_lossy(&response[..n]).into_owned()
+        });
+        while in_flight.count() == before {
+            tokio::task::yield_now().await;
+        }
+        client
+    }
+
+    async fn drained(
+        shutdown: &Shutdown,
+        server: JoinHandle<()>,
+        in_flight: &InFlight,
+        timeout: Duration,
+    ) -> Option<usize> {
+        let servers = async {
+            server.await.unwrap();
+        };
+        drain(shutdown, servers, in_flight, timeout).await
+    }
+
+    #[tokio::test]
+    async fn idle_servers_stop_at_once() {
+        let (shutdown, in_flight) = (Shutdown::new(), InFlight::default());
+        let (server, _) = serve(app(&in_flight, Duration::ZERO), &shutdown).await;
+
+        let started = Instant::now();
+        shutdown.trigger();
+        let dropped = drained(&shutdown, server, &in_flight, Duration::from_secs(10)).await;
+        assert_eq!(dropped, None);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn in_flight_requests_are_waited_for() {
+        let (shutdown, in_flight) = (Shutdown::new(), InFlight::default());
+        let app = app(&in_flight, Duration::from_millis(200));
+        let (server, port) = serve(app, &shutdown).await;
+        let client = request(port, &in_flight).await;
+
+        shutdown.trigger();
+        let dropped = drained(&shutdown, server, &in_flight, Duration::from_secs(10)).await;
+        assert_eq!(dropped, None);
+        assert_eq!(in_flight.count(), 0);
+        assert!(client.await.unwrap().starts_with("HTTP/1.1 200"));
+    }
+
+    #[tokio::test]
+    async fn draining_stops_at_the_timeout() {
+        let (shutdown, in_flight) = (Shutdown::new(), InFlight::default());
+        let app = app(&in_flight, Duration::from_secs(3600));
+        let (server, port) = serve(app, &shutdown).await;
+        let _client = request(port, &in_flight).await;
+
+        let started = Instant::now();
+        shutdown.trigger();
+        let dropped = drained(&shutdown, server, &in_flight, Duration::from_millis(100)).await;
+        assert_eq!(dropped, Some(1));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn dropped_requests_are_no_longer_counted() {
+        let in_flight = InFlight::default();
+        let request = Request::get("/").body(Body::empty()).unwrap();
+        let call = tokio::spawn(app(&in_flight, Duration::from_secs(3600)).oneshot(request));
+        while in_flight.count() == 0 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(in_flight.count(), 1);
+
+        call.abort();
+        let _ = call.await;
+        assert_eq!(in_flight.count(), 0);
+    }
+}
@@ -0,0 +1,76 @@
+// ==================================================
+// Shared application state
+// ==================================================
+// Everything handlers and middleware need at runtime lives in
+// `AppState`, which is cheap to clone (all fields are handles).
+// Handlers extract only the part they need via `FromRef`.
+
+use crate::{
+    auth::Auth, config::Config, cors::Cors, db::Database, error::ErrorFormat, health::Health,
+    metrics::Metrics, overload::ConcurrencyLimiter, policy::Policies, ratelimit::RateLimiter,
+    shutdown::InFlight, telemetry::Telemetry,
+};
+use axum::extract::FromRef;
+use std::sync::Arc;
+
+#[derive(Clone)]
+pub struct AppState {
+    pub config: Arc<Config>,
+    pub db: Database,
+    pub health: Health,
+    pub in_flight: InFlight,
+    pub metrics: Metrics,
+    pub telemetry: Telemetry,
+    /// Set when bearer authentication is configured.
+    pub auth: Option<Auth>,
+    /// Route policy overrides; empty without AUTH_POLICY_FILE.
+    pub policies: Policies,
+    /// Set when RATE_LIMIT or RATE_LIMIT_ROUTES is configured.
+    pub rate_limiter: Option<RateLimiter>,
+    /// Set when CONCURRENCY_LIMIT is configured.
+    pub concurrency: Option<ConcurrencyLimiter>,
+    /// Set when CORS_ALLOWED_ORIGINS is configured.
+    pub cors: Option<Cors>,
+}
+
+impl FromRef<AppState> for Arc<Config> {
+    fn from_ref(state: &AppState) -> Self {
+        state.config.clone()
+    }
+}
+
+impl FromRef<AppState> for Database {
+    fn from_ref(state: &AppState) -> Self {
+        state.db.clone()
+    }
+}
+
+impl FromRef<AppState> for ErrorFormat {
+    fn from_ref(state: &AppState) -> Self {
+        state.config.error_format
+    }
+}
+
+impl FromRef<AppState> for Health {
+    fn from_ref(state: &AppState) -> Self {
+        state.health.clone()
+    }
+}
+
+impl FromRef<AppState> for InFlight {
+    fn from_ref(state: &AppState) -> Self {
+        state.in_flight.clone()
+    }
+}
+
+impl FromRef<AppState> for Metrics {
+    fn from_ref(state: &AppState) -> Self {
+        state.metrics.clone()
+    }
+}
+
+impl FromRef<AppState> for Telemetry {
+    fn from_ref(state: &AppState) -> Self {
+        state.telemetry.clone()
+    }
+}
@@ -0,0 +1,293 @@
+// ==================================================
+// systemd integration
+// ==================================================
+// - Socket activation: listeners passed in through LISTEN_FDS /
+//   LISTEN_FDNAMES are used instead of binding. A socket named
+//   `admin` (FileDescriptorName=admin) becomes the admin listener,
+//   the first other one the public listener.
+// - sd_notify: READY=1 once startup completes, STOPPING=1 when a
+//   shutdown signal arrives, and WATCHDOG=1 pings at half of
+//   WATCHDOG_USEC when the unit has WatchdogSec= set.
+//
+// Everything is a no-op when not running under systemd (the
+// variables are absent), so the same binary runs anywhere.
+
+use crate::listener::Listener;
+use std::{
+    env,
+    ffi::{OsStr, OsString},
+    io,
+    os::{
+        fd::{FromRawFd, OwnedFd, RawFd},
+        unix::{ffi::OsStrExt, net::UnixDatagram},
+    },
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use tracing::{info, warn};
+
+/// First inherited file descriptor (`SD_LISTEN_FDS_START`).
+const LISTEN_FDS_START: RawFd = 3;
+
+// --------------------------------------------------
+// Socket activation
+// --------------------------------------------------
+
+/// Inherited (public, admin) listeners, if systemd passed any.
+pub fn listeners() -> io::Result<(Option<Listener>, Option<Listener>)> {
+    Listener::from_named_fds(listen_fds())
+}
+
+/// Take ownership of the descriptors systemd passed to this process.
+///
+/// Only the first call returns anything, so no descriptor can end
+/// up owned twice.
+fn listen_fds() -> Vec<(OwnedFd, String)> {
+    static TAKEN: AtomicBool = AtomicBool::new(false);
+    if TAKEN.swap(true, Ordering::SeqCst) {
+        return Vec::new();
+    }
+
+    passed_fds(|key| env::var(key).ok(), std::process::id())
+        .into_iter()
+        .map(|(fd, name)| {
+            // SAFETY: systemd passes `count` open descriptors starting
+            // at 3; `TAKEN` guarantees each is wrapped only once.
+            let owned = unsafe { OwnedFd::from_raw_fd(fd) };
+            // SAFETY: valid descriptor; just sets FD_CLOEXEC so the
+            // socket does not leak into child processes.
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+            (owned, name)
+        })
+        .collect()
+}
+
+/// The descriptors and names LISTEN_* describe for process `pid`.
+fn passed_fds<F>(lookup: F, pid: u32) -> Vec<(RawFd, String)>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    // LISTEN_* are inherited by children too; only honour them when
+    // they were meant for this very process.
+    let for_us = lookup("LISTEN_PID").and_then(|pid| pid.parse::<u32>().ok()) == Some(pid);
+    if !for_us {
+        return Vec::new();
+    }
+
+    let count = lookup("LISTEN_FDS")
+        .and_then(|n| n.parse::<RawFd>().ok())
+        .unwrap_or(0);
+    let names = lookup("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':');
+
+    (LISTEN_FDS_START..LISTEN_FDS_START.saturating_add(count))
+        .map(|fd| (fd, names.next().unwrap_or("unknown").to_string()))
+        .collect()
+}
+
+// --------------------------------------------------
+// sd_notify
+// --------------------------------------------------
+
+/// Send a state update (e.g. `READY=1`) to the service manager.
+pub fn notify(state: &str) {
+    let Some(socket_path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(err) = send(&socket_path, state) {
+        warn!("sd_notify {:?} failed: {}", state, err);
+    }
+}
+
+fn send(socket_path: &OsStr, state: &str) -> io::Result<()> {
+    let socket = UnixDatagram::unbound()?;
+
+    // A leading '@' denotes a socket in the abstract namespace.
+    match socket_path.as_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+            let addr = SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &addr)?;
+        }
+        #[cfg(not(target_os = "linux"))]
+        Some(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "abstract sockets are Linux-only",
+            ));
+        }
+        None => {
+            socket.send_to(state.as_bytes(), Path::new(socket_path))?;
+        }
+    }
+    Ok(())
+}
+
+/// Ping the watchdog at half the configured interval, if enabled.
+pub fn spawn_watchdog() {
+    let Some(interval) = watchdog_interval(|key| env::var(key).ok(), std::process::id()) else {
+        return;
+    };
+    let Some(socket_path) = env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+
+    info!("systemd watchdog enabled, pinging every {:?}", interval);
+    tokio::spawn(ping(socket_path, interval));
+}
+
+/// Half of WATCHDOG_USEC, unless WATCHDOG_PID names another process.
+fn watchdog_interval<F>(lookup: F, pid: u32) -> Option<Duration>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let timeout = lookup("WATCHDOG_USEC")
+        .and_then(|usec| usec.parse::<u64>().ok())
+        .filter(|usec| *usec > 0)
+        .map(Duration::from_micros)?;
+
+    if let Some(watchdog_pid) = lookup("WATCHDOG_PID").and_then(|pid| pid.parse::<u32>().ok())
+        && watchdog_pid != pid
+    {
+        return None;
+    }
+    Some(timeout / 2)
+}
+
+async fn ping(socket_path: OsString, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        if let Err(err) = send(&socket_path, "WATCHDOG=1") {
+            warn!("sd_notify \"WATCHDOG=1\" failed: {}", err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const PID: u32 = 4242;
+
+    fn lookup(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |key| vars.get(key).cloned()
+    }
+
+    /// A fake NOTIFY_SOCKET, removed again on drop.
+    struct Manager {
+        path: std::path::PathBuf,
+        socket: tokio::net::UnixDatagram,
+    }
+
+    impl Manager {
+        fn bind(name: &str) -> Self {
+            let path = std::env::temp_dir()
+                .join(format!("hello-api-notify-{}-{name}", std::process::id()));
+            let _ = std::fs::remove_file(&path);
+            let socket = tokio::net::UnixDatagram::bind(&path).unwrap();
+            Self { path, socket }
+        }
+
+        async fn recv(&self) -> String {
+            let mut buf = [0u8; 256];
+            let len = tokio::time::timeout(Duration::from_secs(5), self.socket.recv(&mut buf))
+                .await
+                .expect("no notification within 5s")
+                .unwrap();
+            String::from_utf8_lossy(&buf[..len]).into_owned()
+        }
+    }
+
+    impl Drop for Manager {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.path);
+        }
+    }
+
+    #[test]
+    fn listen_fds_are_named_in_order() {
+        let vars = lookup(&[
+            ("LISTEN_PID", "4242"),
+            ("LISTEN_FDS", "3"),
+            ("LISTEN_FDNAMES", "http:admin"),
+        ]);
+        assert_eq!(
+            passed_fds(vars, PID),
+            [
+                (3, "http".to_string()),
+                (4, "admin".to_string()),
+                (5, "unknown".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn listen_fds_for_other_processes_are_ignored() {
+        let other = lookup(&[("LISTEN_PID", "1"), ("LISTEN_FDS", "2")]);
+        assert!(passed_fds(other, PID).is_empty());
+
+        let missing = lookup(&[("LISTEN_FDS", "2")]);
+        assert!(passed_fds(missing, PID).is_empty());
+
+        let invalid = lookup(&[("LISTEN_PID", "4242"), ("LISTEN_FDS", "two")]);
+        assert!(passed_fds(invalid, PID).is_empty());
+    }
+
+    #[test]
+    fn watchdog_pings_at_half_the_timeout() {
+        let vars = lookup(&[("WATCHDOG_USEC", "30000000")]);
+        assert_eq!(watchdog_interval(vars, PID), Some(Duration::from_secs(15)));
+
+        let ours = lookup(&[("WATCHDOG_USEC", "2000000"), ("WATCHDOG_PID", "4242")]);
+        assert_eq!(watchdog_interval(ours, PID), Some(Duration::from_secs(1)));
+
+        let other = lookup(&[("WATCHDOG_USEC", "2000000"), ("WATCHDOG_PID", "1")]);
+        assert_eq!(watchdog_interval(other, PID), None);
+
+        let disabled = lookup(&[("WATCHDOG_USEC", "0")]);
+        assert_eq!(watchdog_interval(disabled, PID), None);
+    }
+
+    #[tokio::test]
+    async fn states_reach_the_notify_socket() {
+        let manager = Manager::bind("states");
+        for state in ["READY=1", "STOPPING=1"] {
+            send(manager.path.as_os_str(), state).unwrap();
+            assert_eq!(manager.recv().await, state);
+        }
+
+        let pinger = tokio::spawn(ping(
+            manager.path.clone().into_os_string(),
+            Duration::from_millis(10),
+        ));
+        assert_eq!(manager.recv().await, "WATCHDOG=1");
+        assert_eq!(manager.recv().await, "WATCHDOG=1");
+        pinger.abort();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn abstract_notify_sockets_are_supported() {
+        use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+
+        let name = format!("hello-api-notify-{}", std::process::id());
+        let addr = SocketAddr::from_abstract_name(name.as_bytes()).unwrap();
+        let socket = UnixDatagram::bind_addr(&addr).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+
+        send(OsStr::new(&format!("@{name}")), "READY=1").unwrap();
+        let mut buf = [0u8; 64];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+    }
+}
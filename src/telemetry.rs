@@ -0,0 +1,665 @@
+// ==================================================
+// Distributed tracing (OpenTelemetry)
+// ==================================================
+// Every request becomes a SERVER span:
+//
+// - A valid W3C `traceparent` (and its `tracestate`) joins the
+//   upstream trace; otherwise a new trace is started
+// - The trace ID is recorded on the `request` log span, so log
+//   lines can be correlated with traces
+// - When an OTLP endpoint is configured, finished spans are
+//   batched and exported, as protobuf over HTTP (`http/protobuf`)
+//   or to the gRPC TraceService (`grpc`)
+//
+// Configuration follows the standard `OTEL_*` variables (see
+// config.rs). `http/json` is not supported.
+//
+// `flush` pushes out whatever is queued; it runs when shutdown is
+// signalled and again once the server has drained.
+
+use crate::{
+    clock::unix_nanos,
+    crypto,
+    extract::ClientIdentity,
+    http_client::{self, Endpoint},
+};
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, HeaderMap, HeaderValue, Method},
+    middleware::Next,
+    response::Response,
+};
+use std::{
+    fmt,
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use tokio::sync::{mpsc, oneshot};
+use tracing::warn;
+
+/// Spans waiting for export; new spans are dropped when full.
+const QUEUE_CAPACITY: usize = 2048;
+/// Spans sent per export request.
+const MAX_BATCH: usize = 512;
+/// Export interval for partially filled batches.
+const SCHEDULE_DELAY: Duration = Duration::from_secs(5);
+
+/// The OTLP/gRPC export method.
+const GRPC_EXPORT_METHOD: &str = "/opentelemetry.proto.collector.trace.v1.TraceService/Export";
+
+const SPAN_KIND_SERVER: u64 = 2;
+const STATUS_CODE_ERROR: u64 = 2;
+
+// --------------------------------------------------
+// Trace context (W3C Trace Context)
+// --------------------------------------------------
+
+/// The current request's position in a distributed trace.
+#[derive(Clone, Debug)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub parent_span_id: Option<[u8; 8]>,
+    pub trace_state: Option<String>,
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    /// Continue the trace described by the request headers, or start one.
+    fn from_headers(headers: &axum::http::HeaderMap) -> Self {
+        let mut span_id = [0u8; 8];
+        crypto::fill_random(&mut span_id);
+
+        let parent = headers
+            .get("traceparent")
+            .and_then(|v| v.to_str().ok())
+            .and_then(parse_traceparent);
+
+        match parent {
+            Some((trace_id, parent_span_id, flags)) => Self {
+                trace_id,
+                span_id,
+                parent_span_id: Some(parent_span_id),
+                trace_state: headers
+                    .get("tracestate")
+                    .and_then(|v| v.to_str().ok())
+                    .map(str::to_string),
+                sampled: flags & 0x01 == 0x01,
+            },
+            None => {
+                let mut trace_id = [0u8; 16];
+                crypto::fill_random(&mut trace_id);
+                Self {
+                    trace_id,
+                    span_id,
+                    parent_span_id: None,
+                    trace_state: None,
+                    sampled: true,
+                }
+            }
+        }
+    }
+}
+
+/// `00-<32 hex trace id>-<16 hex parent id>-<2 hex flags>`.
+///
+/// All-zero IDs are invalid; unknown future versions are accepted
+/// as long as the leading fields parse, as the spec requires.
+fn parse_traceparent(value: &str) -> Option<([u8; 16], [u8; 8], u8)> {
+    let mut parts = value.trim().split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let parent_id = parts.next()?;
+    let flags = parts.next()?;
+
+    if version.len() != 2 || version == "ff" || (version == "00" && parts.next().is_some()) {
+        return None;
+    }
+    u8::from_str_radix(version, 16).ok()?;
+
+    let trace_id: [u8; 16] = decode_hex(trace_id)?.try_into().ok()?;
+    let parent_id: [u8; 8] = decode_hex(parent_id)?.try_into().ok()?;
+    let flags: [u8; 1] = decode_hex(flags)?.try_into().ok()?;
+
+    if trace_id == [0; 16] || parent_id == [0; 8] {
+        return None;
+    }
+    Some((trace_id, parent_id, flags[0]))
+}
+
+/// Decode lowercase hex (the only case W3C Trace Context allows).
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f')) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+// --------------------------------------------------
+// Exporter settings
+// --------------------------------------------------
+
+/// OTEL_EXPORTER_OTLP_PROTOCOL.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OtlpProtocol {
+    /// POST to the endpoint, e.g. `http://collector:4318/v1/traces`.
+    #[default]
+    HttpProtobuf,
+    /// The TraceService at the endpoint, e.g. `http://collector:4317`.
+    Grpc,
+}
+
+impl FromStr for OtlpProtocol {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "http/protobuf" => Ok(Self::HttpProtobuf),
+            "grpc" => Ok(Self::Grpc),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for OtlpProtocol {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::HttpProtobuf => "http/protobuf",
+            Self::Grpc => "grpc",
+        })
+    }
+}
+
+#[derive(Clone)]
+pub struct OtlpSettings {
+    pub endpoint: Endpoint,
+    pub protocol: OtlpProtocol,
+    /// Extra request headers (usually credentials).
+    pub headers: HeaderMap,
+    pub timeout: Duration,
+    /// Resource attributes; always includes `service.name`.
+    pub resource: Vec<(String, String)>,
+}
+
+/// Header values may carry credentials, so only names are printed.
+impl fmt::Debug for OtlpSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let header_names: Vec<&str> = self.headers.keys().map(|k| k.as_str()).collect();
+        f.debug_struct("OtlpSettings")
+            .field("endpoint", &format_args!("{}", self.endpoint))
+            .field("protocol", &self.protocol)
+            .field("headers", &header_names)
+            .field("timeout", &self.timeout)
+            .field("resource", &self.resource)
+            .finish()
+    }
+}
+
+// --------------------------------------------------
+// Telemetry handle
+// --------------------------------------------------
+
+struct SpanData {
+    context: TraceContext,
+    name: String,
+    start_unix_nanos: u64,
+    end_unix_nanos: u64,
+    attributes: Vec<(&'static str, Attribute)>,
+    error: bool,
+}
+
+enum Attribute {
+    Str(String),
+    Int(i64),
+}
+
+enum Message {
+    Span(SpanData),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Cloneable handle to the span exporter; a no-op when export is off.
+#[derive(Clone, Default)]
+pub struct Telemetry {
+    tx: Option<mpsc::Sender<Message>>,
+}
+
+impl Telemetry {
+    /// Start the background exporter, if configured.
+    pub fn start(settings: Option<OtlpSettings>) -> Self {
+        let Some(settings) = settings else {
+            return Self::default();
+        };
+        let (tx, rx) = mpsc::channel(QUEUE_CAPACITY);
+        tokio::spawn(run_exporter(settings, rx));
+        Self { tx: Some(tx) }
+    }
+
+    /// Export everything queued so far, waiting for it to be sent.
+    pub async fn flush(&self) {
+        let Some(tx) = &self.tx else { return };
+        let (ack, done) = oneshot::channel();
+        if tx.send(Message::Flush(ack)).await.is_ok() {
+            let _ = done.await;
+        }
+    }
+
+    fn record(&self, span: SpanData) {
+        if let Some(tx) = &self.tx {
+            // A full queue means the collector cannot keep up; dropping
+            // spans is preferable to slowing requests down.
+            let _ = tx.try_send(Message::Span(span));
+        }
+    }
+}
+
+async fn run_exporter(settings: OtlpSettings, mut rx: mpsc::Receiver<Message>) {
+    let mut batch = Vec::new();
+    let mut ticker = tokio::time::interval(SCHEDULE_DELAY);
+
+    loop {
+        tokio::select! {
+            message = rx.recv() => match message {
+                Some(Message::Span(span)) => {
+                    batch.push(span);
+                    if batch.len() >= MAX_BATCH {
+                        export(&settings, std::mem::take(&mut batch)).await;
+                    }
+                }
+                Some(Message::Flush(ack)) => {
+                    export(&settings, std::mem::take(&mut batch)).await;
+                    let _ = ack.send(());
+                }
+                None => {
+                    export(&settings, std::mem::take(&mut batch)).await;
+                    return;
+                }
+            },
+            _ = ticker.tick() => export(&settings, std::mem::take(&mut batch)).await,
+        }
+    }
+}
+
+async fn export(settings: &OtlpSettings, spans: Vec<SpanData>) {
+    if spans.is_empty() {
+        return;
+    }
+    let body = encode_request(&settings.resource, &spans);
+    match tokio::time::timeout(settings.timeout, send(settings, &body)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(err)) => warn!(
+            "OTLP export of {} span(s) to {} failed: {}",
+            spans.len(),
+            settings.endpoint,
+            err
+        ),
+        Err(_) => warn!(
+            "OTLP export of {} span(s) to {} timed out",
+            spans.len(),
+            settings.endpoint
+        ),
+    }
+}
+
+async fn send(settings: &OtlpSettings, body: &[u8]) -> Result<(), String> {
+    if settings.protocol == OtlpProtocol::Grpc {
+        // The response only reports partially rejected spans, which
+        // are not retried either way.
+        return http_client::grpc_unary(
+            &settings.endpoint,
+            GRPC_EXPORT_METHOD,
+            &settings.headers,
+            settings.timeout,
+            body,
+        )
+        .await
+        .map(drop)
+        .map_err(|err| err.to_string());
+    }
+
+    let mut headers = settings.headers.clone();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/x-protobuf"),
+    );
+
+    let response = http_client::send(&settings.endpoint, Method::POST, &headers, body)
+        .await
+        .map_err(|err| err.to_string())?;
+    if response.is_success() {
+        Ok(())
+    } else {
+        Err(format!("collector responded {}", response.status))
+    }
+}
+
+// --------------------------------------------------
+// Middleware
+// --------------------------------------------------
+
+/// Middleware establishing the trace context and recording the
+/// request as a SERVER span.
+pub async fn trace_requests(
+    State(telemetry): State<Telemetry>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let context = TraceContext::from_headers(request.headers());
+    request.extensions_mut().insert(context.clone());
+
+    let method = request.method().to_string();
+    let path = request.uri().path().to_string();
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|route| route.as_str().to_string());
+    let client_subject = request
+        .extensions()
+        .get::<ClientIdentity>()
+        .map(|identity| identity.subject.clone());
+
+    let start_unix_nanos = unix_nanos();
+    let started = Instant::now();
+
+    let response = next.run(request).await;
+
+    if context.sampled && telemetry.tx.is_some() {
+        let status = response.status();
+        let mut attributes = vec![
+            ("http.request.method", Attribute::Str(method.clone())),
+            ("url.path", Attribute::Str(path)),
+            (
+                "http.response.status_code",
+                Attribute::Int(i64::from(status.as_u16())),
+            ),
+        ];
+        let name = match &route {
+            Some(route) => format!("{method} {route}"),
+            None => method,
+        };
+        if let Some(route) = route {
+            attributes.push(("http.route", Attribute::Str(route)));
+        }
+        if let Some(subject) = client_subject {
+            attributes.push(("tls.client.subject", Attribute::Str(subject)));
+        }
+
+        telemetry.record(SpanData {
+            context,
+            name,
+            start_unix_nanos,
+            end_unix_nanos: start_unix_nanos + started.elapsed().as_nanos() as u64,
+            attributes,
+            error: status.is_server_error(),
+        });
+    }
+    response
+}
+
+// --------------------------------------------------
+// OTLP protobuf encoding
+// --------------------------------------------------
+// Hand-rolled encoder for the handful of messages in
+// opentelemetry/proto/collector/trace/v1 that are needed:
+//
+// ExportTraceServiceRequest { 1: repeated ResourceSpans }
+// ResourceSpans             { 1: Resource, 2: repeated ScopeSpans }
+// Resource                  { 1: repeated KeyValue }
+// ScopeSpans                { 1: InstrumentationScope, 2: repeated Span }
+// Span { 1: trace_id, 2: span_id, 3: trace_state, 4: parent_span_id,
+//        5: name, 6: kind, 7: start (fixed64), 8: end (fixed64),
+//        9: repeated KeyValue, 15: Status }
+
+#[derive(Default)]
+struct Proto(Vec<u8>);
+
+impl Proto {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push((value as u8) | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn key(&mut self, field: u32, wire_type: u8) {
+        self.varint(u64::from(field) << 3 | u64::from(wire_type));
+    }
+
+    fn uint(&mut self, field: u32, value: u64) {
+        self.key(field, 0);
+        self.varint(value);
+    }
+
+    fn fixed64(&mut self, field: u32, value: u64) {
+        self.key(field, 1);
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn bytes(&mut self, field: u32, value: &[u8]) {
+        self.key(field, 2);
+        self.varint(value.len() as u64);
+        self.0.extend_from_slice(value);
+    }
+
+    fn message(&mut self, field: u32, build: impl FnOnce(&mut Proto)) {
+        let mut inner = Proto::default();
+        build(&mut inner);
+        self.bytes(field, &inner.0);
+    }
+
+    fn key_value(&mut self, field: u32, key: &str, value: &Attribute) {
+        self.message(field, |kv| {
+            kv.bytes(1, key.as_bytes());
+            kv.message(2, |any| match value {
+                Attribute::Str(s) => any.bytes(1, s.as_bytes()),
+                Attribute::Int(i) => any.uint(3, *i as u64),
+            });
+        });
+    }
+}
+
+fn encode_request(resource: &[(String, String)], spans: &[SpanData]) -> Vec<u8> {
+    let mut request = Proto::default();
+    request.message(1, |resource_spans| {
+        resource_spans.message(1, |res| {
+            for (key, value) in resource {
+                res.key_value(1, key, &Attribute::Str(value.clone()));
+            }
+        });
+        resource_spans.message(2, |scope_spans| {
+            scope_spans.message(1, |scope| {
+                scope.bytes(1, env!("CARGO_PKG_NAME").as_bytes());
+                scope.bytes(2, env!("CARGO_PKG_VERSION").as_bytes());
+            });
+            for span in spans {
+                scope_spans.message(2, |s| encode_span(s, span));
+            }
+        });
+    });
+    request.0
+}
+
+fn encode_span(out: &mut Proto, span: &SpanData) {
+    let context = &span.context;
+    out.bytes(1, &context.trace_id);
+    out.bytes(2, &context.span_id);
+    if let Some(state) = &context.trace_state {
+        out.bytes(3, state.as_bytes());
+    }
+    if let Some(parent) = &context.parent_span_id {
+        out.bytes(4, parent);
+    }
+    out.bytes(5, span.name.as_bytes());
+    out.uint(6, SPAN_KIND_SERVER);
+    out.fixed64(7, span.start_unix_nanos);
+    out.fixed64(8, span.end_unix_nanos);
+    for (key, value) in &span.attributes {
+        out.key_value(9, key, value);
+    }
+    if span.error {
+        out.message(15, |status| status.uint(3, STATUS_CODE_ERROR));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::{header::CONTENT_TYPE, HeaderMap};
+    use http_body_util::{combinators::BoxBody, BodyExt, Full};
+    use hyper::{
+        body::{Bytes, Incoming},
+        service::service_fn,
+    };
+    use hyper_util::{
+        rt::{TokioExecutor, TokioIo},
+        server::conn::auto,
+    };
+    use std::convert::Infallible;
+    use tokio::net::TcpListener;
+
+    /// A request the collector stub received.
+    struct Received {
+        path: String,
+        headers: HeaderMap,
+        body: Vec<u8>,
+    }
+
+    type Reply = axum::http::Response<BoxBody<Bytes, Infallible>>;
+
+    /// An OTLP collector on loopback, over HTTP/1.1 or HTTP/2. gRPC
+    /// calls are answered with `grpc_status`: in the trailers when it
+    /// is 0, as a Trailers-Only response otherwise.
+    async fn collector(grpc_status: u32) -> (String, mpsc::UnboundedReceiver<Received>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::unbounded_channel();
+
+        let reply = move |grpc: bool| -> Reply {
+            let response = axum::http::Response::builder();
+            if !grpc {
+                return response.body(Full::default().boxed()).unwrap();
+            }
+            let response = response.header(CONTENT_TYPE, "application/grpc");
+            if grpc_status != 0 {
+                return response
+                    .header("grpc-status", grpc_status)
+                    .header("grpc-message", "rejected by the stub")
+                    .body(Full::default().boxed())
+                    .unwrap();
+            }
+            // An empty ExportTraceServiceResponse.
+            let body = Full::new(Bytes::from_static(&[0; 5])).with_trailers(async {
+                let mut trailers = HeaderMap::new();
+                trailers.insert("grpc-status", 0.into());
+                Some(Ok(trailers))
+            });
+            response.body(body.boxed()).unwrap()
+        };
+
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let tx = tx.clone();
+                let service = service_fn(move |request: axum::http::Request<Incoming>| {
+                    let tx = tx.clone();
+                    async move {
+                        let (parts, body) = request.into_parts();
+                        let grpc = parts.headers[CONTENT_TYPE] == "application/grpc";
+                        let _ = tx.send(Received {
+                            path: parts.uri.path().to_string(),
+                            headers: parts.headers,
+                            body: body.collect().await.unwrap().to_bytes().to_vec(),
+                        });
+                        Ok::<_, Infallible>(reply(grpc))
+                    }
+                });
+                tokio::spawn(async move {
+                    let builder = auto::Builder::new(TokioExecutor::new());
+                    let _ = builder
+                        .serve_connection(TokioIo::new(stream), service)
+                        .await;
+                });
+            }
+        });
+        (url, rx)
+    }
+
+    fn settings(url: &str, protocol: OtlpProtocol) -> OtlpSettings {
+        OtlpSettings {
+            endpoint: Endpoint::parse(url).unwrap(),
+            protocol,
+            headers: HeaderMap::from_iter([(
+                header::AUTHORIZATION,
+                HeaderValue::from_static("Bearer xyz"),
+            )]),
+            timeout: Duration::from_secs(2),
+            resource: vec![("service.name".to_string(), "hello-api".to_string())],
+        }
+    }
+
+    fn request(settings: &OtlpSettings) -> Vec<u8> {
+        let span = SpanData {
+            context: TraceContext {
+                trace_id: [1; 16],
+                span_id: [2; 8],
+                parent_span_id: Some([3; 8]),
+                trace_state: None,
+                sampled: true,
+            },
+            name: "GET /api".to_string(),
+            start_unix_nanos: 1_000,
+            end_unix_nanos: 2_000,
+            attributes: vec![("http.response.status_code", Attribute::Int(200))],
+            error: false,
+        };
+        encode_request(&settings.resource, &[span])
+    }
+
+    #[tokio::test]
+    async fn grpc_export_calls_the_trace_service() {
+        let (url, mut received) = collector(0).await;
+        let settings = settings(&url, OtlpProtocol::Grpc);
+        let body = request(&settings);
+
+        send(&settings, &body).await.unwrap();
+
+        let call = received.recv().await.unwrap();
+        assert_eq!(call.path, GRPC_EXPORT_METHOD);
+        assert_eq!(call.headers[CONTENT_TYPE], "application/grpc");
+        assert_eq!(call.headers["te"], "trailers");
+        assert_eq!(call.headers["grpc-timeout"], "2000m");
+        assert_eq!(call.headers["authorization"], "Bearer xyz");
+        // Uncompressed, length-prefixed message.
+        assert_eq!(call.body[0], 0);
+        assert_eq!(call.body[1..5], (body.len() as u32).to_be_bytes());
+        assert_eq!(call.body[5..], body);
+    }
+
+    #[tokio::test]
+    async fn grpc_export_reports_error_statuses() {
+        let (url, _received) = collector(16).await;
+        let settings = settings(&url, OtlpProtocol::Grpc);
+
+        let err = send(&settings, &request(&settings)).await.unwrap_err();
+        assert_eq!(err, "gRPC status 16: rejected by the stub");
+    }
+
+    #[tokio::test]
+    async fn http_export_posts_protobuf() {
+        let (url, mut received) = collector(0).await;
+        let settings = settings(&format!("{url}/v1/traces"), OtlpProtocol::HttpProtobuf);
+        let body = request(&settings);
+
+        send(&settings, &body).await.unwrap();
+
+        let call = received.recv().await.unwrap();
+        assert_eq!(call.path, "/v1/traces");
+        assert_eq!(call.headers[CONTENT_TYPE], "application/x-protobuf");
+        assert_eq!(call.headers["authorization"], "Bearer xyz");
+        assert_eq!(call.body, body);
+    }
+}
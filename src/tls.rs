@@ -0,0 +1,723 @@
+// ==================================================
+// TLS (rustls)
+// ==================================================
+// With TLS_CERT_FILE and TLS_KEY_FILE set, the main listener speaks
+// HTTPS. Certificates are PEM files: the certificate file may hold
+// the full chain (leaf first), the key file the matching private key.
+//
+// - TLS 1.2 and 1.3 only
+// - Optional mutual TLS (TLS_CLIENT_AUTH=optional|required): client
+//   certificates are verified against TLS_CLIENT_CA_FILE, and the
+//   verified subject and SANs reach handlers as `ClientIdentity`
+// - ALPN offers `h2` and `http/1.1`; the connection is then served
+//   as HTTP/2 or HTTP/1.1 (see `listener`)
+// - The files are checked for changes every few seconds (mtime and
+//   size, following symlinks, which covers the atomic symlink swap
+//   used for Kubernetes secrets / cert-manager). A changed pair is
+//   loaded into a new configuration used by new connections;
+//   established connections keep the one they started with, so
+//   nothing is dropped. A pair that fails to load (e.g. cert and key
+//   caught mid-rotation) is logged and retried, while the old one
+//   stays in use.
+//
+// `connect` is the client side, used for outbound HTTPS: servers
+// are verified against the system trust store and the host name.
+
+use crate::{
+    config::{ClientAuth, TlsSettings},
+    extract::ClientIdentity,
+};
+use rustls::{
+    crypto::CryptoProvider, server::WebPkiClientVerifier, ClientConfig, RootCertStore, ServerConfig,
+};
+use rustls_pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, ServerName};
+use std::{
+    fmt::Write as _,
+    fs, io,
+    net::IpAddr,
+    path::Path,
+    sync::{Arc, OnceLock, RwLock},
+    time::{Duration, SystemTime},
+};
+use tokio::net::TcpStream;
+use tracing::{debug, error, info};
+use x509_parser::{
+    certificate::X509Certificate,
+    extensions::GeneralName,
+    objects::{oid2abbrev, oid_registry},
+    prelude::FromDer,
+    x509::X509Name,
+};
+
+/// How often the certificate files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A handshake not finished within this is abandoned.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Offered in order of preference.
+const ALPN_PROTOCOLS: [&[u8]; 2] = [b"h2", b"http/1.1"];
+
+/// A server-side connection after the handshake.
+pub type TlsStream = tokio_rustls::server::TlsStream<TcpStream>;
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+// --------------------------------------------------
+// Configuration
+// --------------------------------------------------
+
+fn server_config(settings: &TlsSettings) -> io::Result<ServerConfig> {
+    let certs = load_certs(&settings.cert_file)?;
+    let key = PrivateKeyDer::from_pem_file(&settings.key_file)
+        .map_err(|err| invalid(&settings.key_file, err))?;
+
+    let builder = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?;
+    let builder = match (settings.client_auth, &settings.client_ca_file) {
+        (ClientAuth::Off, _) | (_, None) => builder.with_no_client_auth(),
+        (client_auth, Some(ca_file)) => {
+            let mut roots = RootCertStore::empty();
+            for cert in load_certs(ca_file)? {
+                roots.add(cert).map_err(|err| invalid(ca_file, err))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider());
+            let verifier = match client_auth {
+                ClientAuth::Optional => verifier.allow_unauthenticated(),
+                _ => verifier,
+            };
+            builder.with_client_cert_verifier(verifier.build().map_err(io::Error::other)?)
+        }
+    };
+
+    let mut config = builder
+        .with_single_cert(certs, key)
+        .map_err(|err| invalid(&settings.key_file, err))?;
+    config.alpn_protocols = ALPN_PROTOCOLS.iter().map(|p| p.to_vec()).collect();
+    Ok(config)
+}
+
+fn client_config() -> io::Result<ClientConfig> {
+    let native = rustls_native_certs::load_native_certs();
+    let mut roots = RootCertStore::empty();
+    let (added, _) = roots.add_parsable_certificates(native.certs);
+    if added == 0 {
+        return Err(io::Error::other(format!(
+            "no usable certificates in the system trust store ({} errors)",
+            native.errors.len()
+        )));
+    }
+    Ok(ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
+/// Every certificate in a PEM file, in order.
+fn load_certs(path: &Path) -> io::Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|err| invalid(path, err))?;
+    if certs.is_empty() {
+        return Err(invalid(path, "no certificates found"));
+    }
+    Ok(certs)
+}
+
+fn invalid(path: &Path, err: impl std::fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {err}", path.display()),
+    )
+}
+
+// --------------------------------------------------
+// Server and client
+// --------------------------------------------------
+
+/// Modification time and size of every file; `None` entries for
+/// files that are momentarily missing.
+type Stamp = Vec<Option<(SystemTime, u64)>>;
+
+/// Performs server handshakes with the current certificate.
+#[derive(Clone)]
+pub struct TlsAcceptor {
+    settings: TlsSettings,
+    current: Arc<RwLock<tokio_rustls::TlsAcceptor>>,
+}
+
+impl TlsAcceptor {
+    pub fn load(settings: TlsSettings) -> io::Result<Self> {
+        let config = server_config(&settings)?;
+        Ok(Self {
+            settings,
+            current: Arc::new(RwLock::new(Arc::new(config).into())),
+        })
+    }
+
+    /// Watch the certificate files and swap in a new configuration
+    /// when they change.
+    pub fn spawn_reload(&self) {
+        let acceptor = self.clone();
+        tokio::spawn(async move {
+            let mut loaded = acceptor.stamp();
+            let mut ticker = tokio::time::interval(RELOAD_INTERVAL);
+            ticker.tick().await;
+            loop {
+                ticker.tick().await;
+                let (acceptor, previous) = (acceptor.clone(), loaded.clone());
+                loaded = tokio::task::spawn_blocking(move || acceptor.reload_if_changed(previous))
+                    .await
+                    .unwrap_or(loaded);
+            }
+        });
+    }
+
+    /// Load the files again if they changed since `loaded`, returning
+    /// the stamp of the files now in use.
+    fn reload_if_changed(&self, loaded: Stamp) -> Stamp {
+        let stamp = self.stamp();
+        if stamp == loaded {
+            return loaded;
+        }
+        match server_config(&self.settings) {
+            Ok(config) => {
+                *self.current.write().expect("TLS config lock poisoned") = Arc::new(config).into();
+                info!(
+                    "Reloaded TLS certificate from {}",
+                    self.settings.cert_file.display()
+                );
+                stamp
+            }
+            Err(err) => {
+                error!(
+                    "TLS certificate reload failed, keeping the old one: {}",
+                    err
+                );
+                loaded
+            }
+        }
+    }
+
+    fn stamp(&self) -> Stamp {
+        let settings = &self.settings;
+        [&settings.cert_file, &settings.key_file]
+            .into_iter()
+            .chain(&settings.client_ca_file)
+            .map(|path| {
+                fs::metadata(path)
+                    .ok()
+                    .and_then(|meta| Some((meta.modified().ok()?, meta.len())))
+            })
+            .collect()
+    }
+
+    /// Run the server side of the handshake on an accepted connection.
+    pub async fn accept(&self, stream: TcpStream) -> io::Result<TlsStream> {
+        let acceptor = self
+            .current
+            .read()
+            .expect("TLS config lock poisoned")
+            .clone();
+        acceptor.accept(stream).await
+    }
+}
+
+/// Run the client side of the handshake with `host` (a DNS name or
+/// IP address), verifying its certificate. With `http2`, ALPN offers
+/// only `h2`, and a server that does not pick it is an error.
+pub async fn connect(
+    stream: TcpStream,
+    host: &str,
+    http2: bool,
+) -> io::Result<tokio_rustls::client::TlsStream<TcpStream>> {
+    type Connector = OnceLock<Result<tokio_rustls::TlsConnector, String>>;
+    static HTTP1: Connector = OnceLock::new();
+    static HTTP2: Connector = OnceLock::new();
+
+    let cell = if http2 { &HTTP2 } else { &HTTP1 };
+    let connector = cell
+        .get_or_init(|| {
+            client_config()
+                .map(|mut config| {
+                    if http2 {
+                        config.alpn_protocols = vec![b"h2".to_vec()];
+                    }
+                    Arc::new(config).into()
+                })
+                .map_err(|err| err.to_string())
+        })
+        .as_ref()
+        .map_err(|err| io::Error::other(err.clone()))?;
+
+    let name = ServerName::try_from(host.to_string())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "invalid host name"))?;
+    let stream = connector.connect(name, stream).await?;
+    if http2 && stream.get_ref().1.alpn_protocol() != Some(b"h2") {
+        return Err(io::Error::other("the server did not negotiate HTTP/2"));
+    }
+    Ok(stream)
+}
+
+// --------------------------------------------------
+// Client certificates
+// --------------------------------------------------
+
+/// Subject and SANs of the client's certificate, if it sent one.
+/// Handshakes only complete with certificates that verified.
+pub fn client_identity(stream: &TlsStream) -> Option<ClientIdentity> {
+    let cert = stream.get_ref().1.peer_certificates()?.first()?;
+    let identity = parse_identity(cert)?;
+    debug!(
+        "TLS client {} [{}]",
+        identity.subject,
+        identity.sans.join(", ")
+    );
+    Some(identity)
+}
+
+fn parse_identity(cert: &CertificateDer<'_>) -> Option<ClientIdentity> {
+    let (_, cert) = X509Certificate::from_der(cert).ok()?;
+    let sans = match cert.subject_alternative_name() {
+        Ok(Some(extension)) => extension
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(dns) => Some(format!("DNS:{dns}")),
+                GeneralName::URI(uri) => Some(format!("URI:{uri}")),
+                GeneralName::RFC822Name(email) => Some(format!("email:{email}")),
+                GeneralName::IPAddress(bytes) => {
+                    let ip = match bytes.len() {
+                        4 => IpAddr::from(<[u8; 4]>::try_from(*bytes).ok()?),
+                        16 => IpAddr::from(<[u8; 16]>::try_from(*bytes).ok()?),
+                        _ => return None,
+                    };
+                    Some(format!("IP:{ip}"))
+                }
+                // Directory names, other names, ...: not exposed.
+                _ => None,
+            })
+            .collect(),
+        _ => Vec::new(),
+    };
+    Some(ClientIdentity {
+        subject: rfc2253(cert.subject()),
+        sans,
+    })
+}
+
+/// A distinguished name in RFC 2253 form: most specific RDN first,
+/// `CN=leaf,O=Org`.
+fn rfc2253(name: &X509Name<'_>) -> String {
+    let mut out = String::new();
+    for (i, rdn) in name
+        .iter()
+        .collect::<Vec<_>>()
+        .into_iter()
+        .rev()
+        .enumerate()
+    {
+        for (j, attr) in rdn.iter().enumerate() {
+            out.push_str(match (i, j) {
+                (0, 0) => "",
+                (_, 0) => ",",
+                _ => "+",
+            });
+            match oid2abbrev(attr.attr_type(), oid_registry()) {
+                Ok(abbrev) => out.push_str(abbrev),
+                Err(_) => out.push_str(&attr.attr_type().to_id_string()),
+            }
+            out.push('=');
+            match attr.as_str() {
+                Ok(value) => escape_rfc2253(&mut out, value),
+                // Not a string type: the value's bytes in hex.
+                Err(_) => {
+                    out.push('#');
+                    for b in attr.as_slice() {
+                        let _ = write!(out, "{b:02x}");
+                    }
+                }
+            }
+        }
+    }
+    out
+}
+
+fn escape_rfc2253(out: &mut String, value: &str) {
+    let last = value.chars().count().saturating_sub(1);
+    for (i, c) in value.chars().enumerate() {
+        let special = matches!(c, ',' | '+' | '"' | '\\' | '<' | '>' | ';')
+            || (i == 0 && matches!(c, '#' | ' '))
+            || (i == last && c == ' ');
+        if special {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{
+        BasicConstraints, Certificate, CertificateParams, DistinguishedName, DnType,
+        ExtendedKeyUsagePurpose, IsCa, KeyPair, SanType,
+    };
+    use std::path::PathBuf;
+    use tokio::net::TcpListener;
+
+    struct Ca {
+        cert: Certificate,
+        key: KeyPair,
+    }
+
+    impl Ca {
+        fn new() -> Self {
+            let key = KeyPair::generate().unwrap();
+            let mut params = CertificateParams::new(Vec::new()).unwrap();
+            params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+            params
+                .distinguished_name
+                .push(DnType::CommonName, "Test CA");
+            let cert = params.self_signed(&key).unwrap();
+            Self { cert, key }
+        }
+
+        /// A leaf certificate and its key, as PEM.
+        fn issue(&self, params: CertificateParams) -> (String, String) {
+            let key = KeyPair::generate().unwrap();
+            let cert = params.signed_by(&key, &self.cert, &self.key).unwrap();
+            (cert.pem(), key.serialize_pem())
+        }
+
+        fn server(&self) -> (String, String) {
+            self.issue(CertificateParams::new(vec!["localhost".to_string()]).unwrap())
+        }
+    }
+
+    /// A scratch directory, removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("hello-api-tls-{}-{name}", std::process::id()));
+            fs::create_dir_all(&dir).unwrap();
+            Self(dir)
+        }
+
+        fn write(&self, name: &str, contents: &str) -> PathBuf {
+            let path = self.0.join(name);
+            fs::write(&path, contents).unwrap();
+            path
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// A client certificate for `CN=billing,O=Example` with one SAN of
+    /// each kind.
+    fn billing_client(ca: &Ca) -> (String, String) {
+        let mut params = CertificateParams::new(Vec::new()).unwrap();
+        params.distinguished_name = DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(DnType::OrganizationName, "Example");
+        params
+            .distinguished_name
+            .push(DnType::CommonName, "billing");
+        params.subject_alt_names = vec![
+            SanType::URI("spiffe://example.org/billing".try_into().unwrap()),
+            SanType::DnsName("billing.internal".try_into().unwrap()),
+            SanType::IpAddress("10.0.0.7".parse().unwrap()),
+            SanType::Rfc822Name("ops@example.org".try_into().unwrap()),
+        ];
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::ClientAuth];
+        ca.issue(params)
+    }
+
+    const BILLING_SANS: [&str; 4] = [
+        "URI:spiffe://example.org/billing",
+        "DNS:billing.internal",
+        "IP:10.0.0.7",
+        "email:ops@example.org",
+    ];
+
+    /// Server settings requiring (or accepting) client certificates
+    /// issued by `client_ca`.
+    fn mtls_settings(dir: &TempDir, server: &Ca, client_ca: &Ca, mode: ClientAuth) -> TlsSettings {
+        TlsSettings {
+            client_auth: mode,
+            client_ca_file: Some(dir.write("client-ca.pem", &client_ca.cert.pem())),
+            ..settings(dir, &server.server())
+        }
+    }
+
+    fn settings(dir: &TempDir, (cert, key): &(String, String)) -> TlsSettings {
+        TlsSettings {
+            cert_file: dir.write("cert.pem", cert),
+            key_file: dir.write("key.pem", key),
+            client_auth: ClientAuth::Off,
+            client_ca_file: None,
+        }
+    }
+
+    struct Handshake {
+        /// The certificate the server presented.
+        server_cert: CertificateDer<'static>,
+        alpn: Option<Vec<u8>>,
+    }
+
+    /// A client trusting `ca`, presenting `client` (cert and key PEM).
+    fn connector(
+        ca: &Ca,
+        client: Option<&(String, String)>,
+        alpn: &[&[u8]],
+    ) -> tokio_rustls::TlsConnector {
+        let mut roots = RootCertStore::empty();
+        roots.add(ca.cert.der().clone()).unwrap();
+        let builder = ClientConfig::builder_with_provider(provider())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let mut config = match client {
+            Some((cert, key)) => builder
+                .with_client_auth_cert(
+                    vec![der(cert)],
+                    PrivateKeyDer::from_pem_slice(key.as_bytes()).unwrap(),
+                )
+                .unwrap(),
+            None => builder.with_no_client_auth(),
+        };
+        config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+        Arc::new(config).into()
+    }
+
+    /// Connect to `acceptor` over loopback, returning the server's
+    /// side of the handshake and the client's, if it completed.
+    async fn connect(
+        acceptor: &TlsAcceptor,
+        connector: tokio_rustls::TlsConnector,
+    ) -> (
+        io::Result<TlsStream>,
+        Option<tokio_rustls::client::TlsStream<TcpStream>>,
+    ) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = {
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let (stream, _) = listener.accept().await.unwrap();
+                acceptor.accept(stream).await
+            })
+        };
+
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let client = connector
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .ok();
+        (server.await.unwrap(), client)
+    }
+
+    async fn handshake(acceptor: &TlsAcceptor, ca: &Ca, alpn: &[&[u8]]) -> Handshake {
+        let (server, client) = connect(acceptor, connector(ca, None, alpn)).await;
+        let (server, client) = (server.unwrap(), client.unwrap());
+
+        let (_, session) = client.get_ref();
+        assert_eq!(session.alpn_protocol(), server.get_ref().1.alpn_protocol());
+        Handshake {
+            server_cert: session.peer_certificates().unwrap()[0].clone().into_owned(),
+            alpn: session.alpn_protocol().map(<[u8]>::to_vec),
+        }
+    }
+
+    fn der(pem: &str) -> CertificateDer<'static> {
+        CertificateDer::from_pem_slice(pem.as_bytes()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn alpn_offers_h2_and_http1() {
+        let ca = Ca::new();
+        let dir = TempDir::new("alpn");
+        let acceptor = TlsAcceptor::load(settings(&dir, &ca.server())).unwrap();
+
+        let negotiated = |alpn: &'static [&'static [u8]]| {
+            let (acceptor, ca) = (&acceptor, &ca);
+            async move { handshake(acceptor, ca, alpn).await.alpn }
+        };
+        assert_eq!(
+            negotiated(&[b"h2", b"http/1.1"]).await.as_deref(),
+            Some(&b"h2"[..])
+        );
+        assert_eq!(
+            negotiated(&[b"http/1.1"]).await.as_deref(),
+            Some(&b"http/1.1"[..])
+        );
+        assert_eq!(negotiated(&[]).await, None);
+    }
+
+    #[tokio::test]
+    async fn reload_serves_changed_certificates_to_new_connections() {
+        let ca = Ca::new();
+        let dir = TempDir::new("reload");
+        let first = ca.server();
+        let settings = settings(&dir, &first);
+        let acceptor = TlsAcceptor::load(settings.clone()).unwrap();
+        let loaded = acceptor.stamp();
+
+        // Unchanged files are not reloaded.
+        assert_eq!(acceptor.reload_if_changed(loaded.clone()), loaded);
+        assert_eq!(
+            handshake(&acceptor, &ca, &[]).await.server_cert,
+            der(&first.0)
+        );
+
+        let second = ca.server();
+        fs::write(&settings.cert_file, &second.0).unwrap();
+        fs::write(&settings.key_file, &second.1).unwrap();
+        let loaded = acceptor.reload_if_changed(loaded);
+        assert_eq!(loaded, acceptor.stamp());
+        assert_eq!(
+            handshake(&acceptor, &ca, &[]).await.server_cert,
+            der(&second.0)
+        );
+
+        // A pair caught mid-rotation keeps the previous one in use,
+        // and is retried until it loads.
+        fs::write(&settings.cert_file, &first.0).unwrap();
+        let failed = acceptor.reload_if_changed(loaded.clone());
+        assert_eq!(failed, loaded);
+        assert_eq!(
+            handshake(&acceptor, &ca, &[]).await.server_cert,
+            der(&second.0)
+        );
+
+        fs::write(&settings.key_file, &first.1).unwrap();
+        assert_eq!(acceptor.reload_if_changed(failed), acceptor.stamp());
+        assert_eq!(
+            handshake(&acceptor, &ca, &[]).await.server_cert,
+            der(&first.0)
+        );
+    }
+
+    #[tokio::test]
+    async fn required_client_certificates_are_verified() {
+        let (ca, other_ca) = (Ca::new(), Ca::new());
+        let dir = TempDir::new("mtls-required");
+        let acceptor =
+            TlsAcceptor::load(mtls_settings(&dir, &ca, &ca, ClientAuth::Required)).unwrap();
+
+        let client = billing_client(&ca);
+        let (server, _) = connect(&acceptor, connector(&ca, Some(&client), &[])).await;
+        let identity = client_identity(&server.unwrap()).unwrap();
+        assert_eq!(identity.subject, "CN=billing,O=Example");
+        assert_eq!(identity.sans, BILLING_SANS);
+
+        let (server, _) = connect(&acceptor, connector(&ca, None, &[])).await;
+        assert!(server.is_err(), "accepted a client without a certificate");
+
+        let stranger = billing_client(&other_ca);
+        let (server, _) = connect(&acceptor, connector(&ca, Some(&stranger), &[])).await;
+        assert!(server.is_err(), "accepted a certificate from another CA");
+    }
+
+    #[tokio::test]
+    async fn optional_client_certificates_are_verified_when_presented() {
+        let (ca, other_ca) = (Ca::new(), Ca::new());
+        let dir = TempDir::new("mtls-optional");
+        let acceptor =
+            TlsAcceptor::load(mtls_settings(&dir, &ca, &ca, ClientAuth::Optional)).unwrap();
+
+        let (server, _) = connect(&acceptor, connector(&ca, None, &[])).await;
+        assert!(client_identity(&server.unwrap()).is_none());
+
+        let client = billing_client(&ca);
+        let (server, _) = connect(&acceptor, connector(&ca, Some(&client), &[])).await;
+        assert!(client_identity(&server.unwrap()).is_some());
+
+        let stranger = billing_client(&other_ca);
+        let (server, _) = connect(&acceptor, connector(&ca, Some(&stranger), &[])).await;
+        assert!(server.is_err(), "accepted a certificate from another CA");
+    }
+
+    #[tokio::test]
+    async fn client_identity_reaches_handlers() {
+        use crate::{listener::Listener, shutdown::Shutdown};
+        use axum::{routing::get, Router};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let ca = Ca::new();
+        let dir = TempDir::new("mtls-handler");
+        let acceptor =
+            TlsAcceptor::load(mtls_settings(&dir, &ca, &ca, ClientAuth::Optional)).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let app = Router::new().route(
+            "/whoami",
+            get(|identity: Option<ClientIdentity>| async move {
+                match identity {
+                    Some(identity) => {
+                        format!("{} [{}]", identity.subject, identity.sans.join(", "))
+                    }
+                    None => "anonymous".to_string(),
+                }
+            }),
+        );
+        let shutdown = Shutdown::new();
+        let server = tokio::spawn(Listener::Tls(listener, acceptor).serve(app, shutdown.clone()));
+
+        let whoami = async |client: Option<&(String, String)>| {
+            let stream = TcpStream::connect(addr).await.unwrap();
+            let mut stream = connector(&ca, client, &[b"http/1.1"])
+                .connect(ServerName::try_from("localhost").unwrap(), stream)
+                .await
+                .unwrap();
+            stream
+                .write_all(b"GET /whoami HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).await.unwrap();
+            let (head, body) = response.split_once("\r\n\r\n").unwrap();
+            assert!(head.starts_with("HTTP/1.1 200"), "{head}");
+            body.to_string()
+        };
+
+        assert_eq!(
+            whoami(Some(&billing_client(&ca))).await,
+            format!("CN=billing,O=Example [{}]", BILLING_SANS.join(", "))
+        );
+        assert_eq!(whoami(None).await, "anonymous");
+
+        shutdown.trigger();
+        server.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn load_rejects_a_mismatched_key() {
+        let ca = Ca::new();
+        let dir = TempDir::new("mismatch");
+        let (cert, _) = ca.server();
+        let (_, other_key) = ca.server();
+        assert!(TlsAcceptor::load(settings(&dir, &(cert, other_key))).is_err());
+        assert!(TlsAcceptor::load(settings(&dir, &(String::new(), String::new()))).is_err());
+    }
+
+    #[test]
+    fn rfc2253_escapes_special_characters() {
+        let mut out = String::new();
+        escape_rfc2253(&mut out, "#Acme, Inc. <ops>+dev ");
+        assert_eq!(out, r"\#Acme\, Inc. \<ops\>\+dev\ ");
+    }
+}
@@ -0,0 +1,384 @@
+// ==================================================
+// Zero-downtime binary upgrade (SIGUSR2)
+// ==================================================
+// nginx-style hot reload:
+//
+// 1. SIGUSR2 makes the running process start the binary at its
+//    own path again (normally a freshly installed one), passing
+//    its listening sockets as file descriptors 3.. together with a
+//    readiness pipe
+// 2. The new process adopts those listeners instead of binding,
+//    boots as usual and reports readiness through the pipe once
+//    startup is complete
+// 3. Only then does the old process trigger its normal graceful
+//    shutdown, so the sockets never stop accepting
+//
+// If the new process fails to start or does not become ready
+// within UPGRADE_TIMEOUT, it is killed and the old one keeps
+// serving as if nothing happened.
+//
+// The handoff uses its own variables rather than systemd's
+// LISTEN_FDS, whose LISTEN_PID cannot be known before spawning:
+//
+//   UPGRADE_PID=1234 UPGRADE_FDS=2 UPGRADE_FDNAMES=http:admin UPGRADE_READY_FD=5
+//
+// UPGRADE_PID is the old process; like LISTEN_PID it keeps anything
+// the new process starts in turn from claiming the descriptors.
+//
+// Under systemd the new process is announced with MAINPID=, which
+// requires NotifyAccess=all in the unit.
+
+use crate::{
+    listener::{self, Listener},
+    shutdown::Shutdown,
+    systemd,
+};
+use std::{
+    env,
+    fs::File,
+    io::{self, Read, Write},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::process::CommandExt,
+    },
+    process::{Child, Command},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+use tokio::signal::unix::{signal, SignalKind};
+use tracing::{error, info, warn};
+
+/// First descriptor handed to the new process, as with systemd.
+const FDS_START: RawFd = 3;
+
+// --------------------------------------------------
+// Old process: hand over and step down
+// --------------------------------------------------
+
+/// Listening sockets to hand over, with the names the new process
+/// uses to tell them apart (`admin` marks the admin listener).
+pub struct Handoff {
+    pub fds: Vec<(RawFd, &'static str)>,
+    pub timeout: Duration,
+}
+
+/// Wait for SIGUSR2 and upgrade; on success, trigger `shutdown`.
+///
+/// The descriptors in `handoff` must stay open until shutdown is
+/// triggered, which holds as long as the servers are running.
+pub async fn upgrade_signal(shutdown: Shutdown, handoff: Handoff) {
+    let mut sigusr2 =
+        signal(SignalKind::user_defined2()).expect("Failed to install SIGUSR2 handler");
+
+    while sigusr2.recv().await.is_some() {
+        if shutdown.is_triggered() {
+            warn!("Ignoring SIGUSR2: already shutting down");
+            continue;
+        }
+
+        info!("SIGUSR2 received, starting a new process");
+        match spawn_successor(&handoff).await {
+            Ok(pid) => {
+                info!("New process {} is ready, handing over", pid);
+                // The successor now owns any Unix socket files.
+                listener::release_socket_files();
+                systemd::notify(&format!("MAINPID={pid}"));
+                shutdown.trigger();
+                return;
+            }
+            Err(err) => error!("Upgrade failed, continuing to serve: {}", err),
+        }
+    }
+}
+
+async fn spawn_successor(handoff: &Handoff) -> io::Result<u32> {
+    // Duplicate every socket above the target range first, so the
+    // dup2 calls in the child can never clobber a source descriptor.
+    let min_fd = FDS_START + handoff.fds.len() as RawFd + 1;
+    let mut sources = handoff
+        .fds
+        .iter()
+        .map(|(fd, _)| dup_above(*fd, min_fd))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let (ready_read, ready_write) = pipe()?;
+    sources.push(dup_above(ready_write.as_raw_fd(), min_fd)?);
+    drop(ready_write);
+
+    let mut command = Command::new(env::current_exe()?);
+    command
+        .args(env::args_os().skip(1))
+        .envs(handoff_env(handoff, std::process::id()))
+        // systemd's variables describe this process, not the child.
+        .env_remove("LISTEN_PID")
+        .env_remove("LISTEN_FDS")
+        .env_remove("LISTEN_FDNAMES");
+
+    let raw_sources: Vec<RawFd> = sources.iter().map(AsRawFd::as_raw_fd).collect();
+    // SAFETY: only async-signal-safe calls (dup2) run between fork
+    // and exec; dup2 onto a different descriptor clears FD_CLOEXEC.
+    unsafe {
+        command.pre_exec(move || {
+            for (i, fd) in raw_sources.iter().enumerate() {
+                if libc::dup2(*fd, FDS_START + i as RawFd) < 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+
+    let mut child = command.spawn()?;
+    // Our copy of the pipe's write end must be closed, or EOF would
+    // never be seen if the child dies before reporting readiness.
+    drop(sources);
+    let pid = child.id();
+
+    match tokio::time::timeout(handoff.timeout, wait_ready(ready_read)).await {
+        Ok(Ok(())) => Ok(pid),
+        Ok(Err(err)) => Err(abandon(&mut child, err)),
+        Err(_) => Err(abandon(
+            &mut child,
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                format!("process {pid} not ready after {:?}", handoff.timeout),
+            ),
+        )),
+    }
+}
+
+/// The variables describing the handoff to the child of `pid`. The
+/// readiness pipe follows the listening sockets.
+fn handoff_env(handoff: &Handoff, pid: u32) -> [(&'static str, String); 4] {
+    let names: Vec<&str> = handoff.fds.iter().map(|(_, name)| *name).collect();
+    let ready_fd = FDS_START + handoff.fds.len() as RawFd;
+    [
+        ("UPGRADE_PID", pid.to_string()),
+        ("UPGRADE_FDS", handoff.fds.len().to_string()),
+        ("UPGRADE_FDNAMES", names.join(":")),
+        ("UPGRADE_READY_FD", ready_fd.to_string()),
+    ]
+}
+
+/// Block (off the runtime) until the child writes to the pipe.
+async fn wait_ready(pipe: OwnedFd) -> io::Result<()> {
+    tokio::task::spawn_blocking(move || {
+        let mut byte = [0u8; 1];
+        match File::from(pipe).read(&mut byte)? {
+            0 => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "new process exited before becoming ready",
+            )),
+            _ => Ok(()),
+        }
+    })
+    .await
+    .map_err(io::Error::other)?
+}
+
+/// Kill a failed successor, returning the reason it was abandoned.
+fn abandon(child: &mut Child, reason: io::Error) -> io::Error {
+    let _ = child.kill();
+    let _ = child.wait();
+    reason
+}
+
+fn dup_above(fd: RawFd, min: RawFd) -> io::Result<OwnedFd> {
+    // SAFETY: F_DUPFD_CLOEXEC on a descriptor we hold open.
+    let new = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, min) };
+    if new < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: `new` is a fresh descriptor owned by nobody else.
+    Ok(unsafe { OwnedFd::from_raw_fd(new) })
+}
+
+fn pipe() -> io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+    // SAFETY: `fds` has room for the two descriptors pipe returns.
+    if unsafe { libc::pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    for fd in fds {
+        // SAFETY: fresh descriptor; keep it out of unrelated children.
+        unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+    }
+    // SAFETY: both descriptors are fresh and owned by nobody else.
+    Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) })
+}
+
+// --------------------------------------------------
+// New process: adopt listeners and report readiness
+// --------------------------------------------------
+
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+/// Listeners handed over by the previous process, if this process
+/// was started by an upgrade. Unix socket files become ours to
+/// remove on shutdown.
+pub fn listeners() -> io::Result<(Option<Listener>, Option<Listener>)> {
+    let Some(fds) = handed_over(|key| env::var(key).ok(), parent_pid()) else {
+        return Ok((None, None));
+    };
+    if TAKEN.swap(true, Ordering::SeqCst) {
+        return Ok((None, None));
+    }
+
+    let fds = fds
+        .into_iter()
+        .map(|(fd, name)| {
+            // SAFETY: the previous process placed the listening sockets
+            // at 3..; `TAKEN` guarantees a single owner.
+            let owned = unsafe { OwnedFd::from_raw_fd(fd) };
+            // SAFETY: valid descriptor; keep it out of our own children.
+            unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
+            (owned, name)
+        })
+        .collect();
+
+    let (public, admin) = Listener::from_named_fds(fds)?;
+    Ok((
+        public.map(Listener::adopt_socket_file),
+        admin.map(Listener::adopt_socket_file),
+    ))
+}
+
+/// Tell the previous process that startup is complete.
+pub fn notify_ready() {
+    let Some(fd) = ready_fd(|key| env::var(key).ok(), parent_pid()) else {
+        return;
+    };
+
+    // SAFETY: the previous process passed the pipe's write end at
+    // `fd`; it is wrapped once and closed after writing.
+    let mut pipe = unsafe { File::from_raw_fd(fd) };
+    if let Err(err) = pipe.write_all(b"1") {
+        warn!(
+            "Failed to report readiness to the previous process: {}",
+            err
+        );
+    }
+}
+
+fn parent_pid() -> u32 {
+    // SAFETY: getppid has no preconditions and cannot fail.
+    unsafe { libc::getppid() as u32 }
+}
+
+/// Whether the UPGRADE_* variables were set by `parent`, our parent.
+fn from_parent<F>(lookup: &F, parent: u32) -> bool
+where
+    F: Fn(&str) -> Option<String>,
+{
+    lookup("UPGRADE_PID").and_then(|pid| pid.parse::<u32>().ok()) == Some(parent)
+}
+
+/// The descriptors and names `handoff_env` describes, if they were
+/// handed over by `parent`.
+fn handed_over<F>(lookup: F, parent: u32) -> Option<Vec<(RawFd, String)>>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    if !from_parent(&lookup, parent) {
+        return None;
+    }
+    let count = lookup("UPGRADE_FDS").and_then(|n| n.parse::<RawFd>().ok())?;
+    let names = lookup("UPGRADE_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':').filter(|name| !name.is_empty());
+
+    Some(
+        (FDS_START..FDS_START.saturating_add(count))
+            .map(|fd| (fd, names.next().unwrap_or("unknown").to_string()))
+            .collect(),
+    )
+}
+
+/// The readiness pipe, if it was handed over by `parent`.
+fn ready_fd<F>(lookup: F, parent: u32) -> Option<RawFd>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    if !from_parent(&lookup, parent) {
+        return None;
+    }
+    lookup("UPGRADE_READY_FD").and_then(|fd| fd.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    const OLD_PID: u32 = 4242;
+
+    fn handoff(fds: Vec<(RawFd, &'static str)>) -> Handoff {
+        Handoff {
+            fds,
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    /// What the child sees: `vars` as its environment.
+    fn lookup(vars: &[(&'static str, String)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<&str, String> = vars.iter().cloned().collect();
+        move |key| vars.get(key).cloned()
+    }
+
+    #[test]
+    fn handoffs_round_trip() {
+        // Wherever the sockets live in the old process, the new one
+        // finds them at 3.. with the readiness pipe right after.
+        let env = handoff_env(&handoff(vec![(17, "http"), (9, "admin")]), OLD_PID);
+        assert_eq!(
+            env,
+            [
+                ("UPGRADE_PID", "4242".to_string()),
+                ("UPGRADE_FDS", "2".to_string()),
+                ("UPGRADE_FDNAMES", "http:admin".to_string()),
+                ("UPGRADE_READY_FD", "5".to_string()),
+            ]
+        );
+        assert_eq!(
+            handed_over(lookup(&env), OLD_PID),
+            Some(vec![(3, "http".to_string()), (4, "admin".to_string())])
+        );
+        assert_eq!(ready_fd(lookup(&env), OLD_PID), Some(5));
+
+        let env = handoff_env(&handoff(vec![(8, "http")]), OLD_PID);
+        assert_eq!(
+            handed_over(lookup(&env), OLD_PID),
+            Some(vec![(3, "http".to_string())])
+        );
+        assert_eq!(ready_fd(lookup(&env), OLD_PID), Some(4));
+    }
+
+    #[test]
+    fn missing_names_are_unknown() {
+        let env = [
+            ("UPGRADE_PID", "4242".to_string()),
+            ("UPGRADE_FDS", "2".to_string()),
+        ];
+        assert_eq!(
+            handed_over(lookup(&env), OLD_PID),
+            Some(vec![(3, "unknown".to_string()), (4, "unknown".to_string())])
+        );
+    }
+
+    #[test]
+    fn handoffs_to_another_process_are_ignored() {
+        // A process started by the new one inherits the variables too.
+        let env = handoff_env(&handoff(vec![(17, "http")]), OLD_PID);
+        assert_eq!(handed_over(lookup(&env), 1), None);
+        assert_eq!(ready_fd(lookup(&env), 1), None);
+
+        let without_pid = &env[1..];
+        assert_eq!(handed_over(lookup(without_pid), OLD_PID), None);
+        assert_eq!(ready_fd(lookup(without_pid), OLD_PID), None);
+    }
+
+    #[test]
+    fn no_handoff_without_variables() {
+        assert_eq!(handed_over(lookup(&[]), OLD_PID), None);
+        assert_eq!(ready_fd(lookup(&[]), OLD_PID), None);
+    }
+}